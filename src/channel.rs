@@ -15,12 +15,13 @@ use futures3::{
         Receiver
     },
     Sink,
-    Stream,
-    Never
+    Stream
 };
 use odds::vec::VecExt;
 
 use crate::chunk::Chunk;
+use crate::error::WebmetroError;
+use crate::webm::parse_header_doc_type;
 
 /// A collection of listeners to a stream of WebM chunks.
 /// Sending a chunk may fail due to a client being disconnected,
@@ -30,19 +31,39 @@ use crate::chunk::Chunk;
 pub struct Channel {
     pub name: String,
     header_chunk: Option<Chunk>,
+    /// the DocType of the streams this channel carries, once one has been sent
+    doc_type: Option<String>,
+    listener_buffer: usize,
     listeners: Vec<Sender<Chunk>>
 }
 
 pub type Handle = Arc<Mutex<Channel>>;
 
+/// How many chunks a listener can fall behind by before it's dropped
+pub const DEFAULT_LISTENER_BUFFER: usize = 5;
+
 impl Channel {
     pub fn new(name: String) -> Handle {
+        Channel::with_listener_buffer(name, DEFAULT_LISTENER_BUFFER)
+    }
+
+    /// Like `new`, but with a custom listener buffer size. Streams of partial
+    /// clusters send every block as its own chunk, so need a much larger
+    /// buffer to give listeners the same amount of slack.
+    pub fn with_listener_buffer(name: String, listener_buffer: usize) -> Handle {
         Arc::new(Mutex::new(Channel {
             name,
             header_chunk: None,
+            doc_type: None,
+            listener_buffer,
             listeners: Vec::new()
         }))
     }
+
+    /// The DocType (e.g. "webm" or "matroska") of the streams sent to this channel so far
+    pub fn doc_type(&self) -> Option<&str> {
+        self.doc_type.as_deref()
+    }
 }
 
 pub struct Transmitter {
@@ -58,16 +79,29 @@ impl Transmitter {
 }
 
 impl Sink<Chunk> for Transmitter {
-    type Error = Never; // never errors, slow clients are simply dropped
+    /// slow clients are simply dropped, so this only errors on headers
+    /// with a different DocType from the streams the channel already carries
+    type Error = WebmetroError;
 
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), Never>> {
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
         Poll::Ready(Ok(()))
     }
 
-    fn start_send(self: Pin<&mut Self>, chunk: Chunk) -> Result<(), Never> {
+    fn start_send(self: Pin<&mut Self>, chunk: Chunk) -> Result<(), WebmetroError> {
         let mut channel = self.channel.lock().expect("Locking channel");
 
-        if let Chunk::Headers { .. } = chunk {
+        if let Chunk::Headers { ref bytes } = chunk {
+            if let Some(doc_type) = parse_header_doc_type(bytes) {
+                match channel.doc_type {
+                    Some(ref current) if current != doc_type => {
+                        return Err(WebmetroError::ApplicationError {
+                            message: format!("DocType \"{}\" doesn't match the \"{}\" streams on channel {}", doc_type, current, channel.name)
+                        });
+                    },
+                    Some(_) => {},
+                    None => channel.doc_type = Some(doc_type.to_string())
+                }
+            }
             channel.header_chunk = Some(chunk.clone());
         }
 
@@ -76,7 +110,7 @@ impl Sink<Chunk> for Transmitter {
         Ok(())
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Never>> {
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
         let mut channel = self.channel.lock().expect("Locking channel");
         let mut result = Poll::Ready(Ok(()));
 
@@ -90,7 +124,7 @@ impl Sink<Chunk> for Transmitter {
         result
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Never>> {
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
         // don't actually disconnect listeners, since other sources may want to transmit to this channel;
         // just ensure we've sent everything we can out
         self.poll_flush(cx)
@@ -105,17 +139,17 @@ pub struct Listener {
 
 impl Listener {
     pub fn new(channel_arc: Handle) -> Self {
-        let (mut sender, receiver) = mpsc_channel(5);
-
-        {
+        let receiver = {
             let mut channel = channel_arc.lock().expect("Locking channel");
+            let (mut sender, receiver) = mpsc_channel(channel.listener_buffer);
 
             if let Some(ref chunk) = channel.header_chunk {
                 sender.start_send(chunk.clone()).expect("Queuing existing header chunk");
             }
 
             channel.listeners.push(sender);
-        }
+            receiver
+        };
 
         Listener {
             _channel: channel_arc,
@@ -132,3 +166,38 @@ impl Stream for Listener {
         Pin::new(receiver).poll_next(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use crate::webm::{encode_webm_element, EbmlHeader, WebmElement};
+    use super::*;
+
+    fn headers(doc_type: &str) -> Chunk {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader {
+            doc_type: Some(doc_type),
+            ..EbmlHeader::default()
+        }), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        Chunk::Headers {bytes: Bytes::from(cursor.into_inner())}
+    }
+
+    #[test]
+    fn refuse_other_doc_types() {
+        let channel = Channel::new("test".into());
+        // both sources connect before either has sent its headers
+        let mut first = Transmitter::new(channel.clone());
+        let mut second = Transmitter::new(channel.clone());
+
+        Pin::new(&mut first).start_send(headers("matroska")).unwrap();
+        assert_eq!(channel.lock().unwrap().doc_type(), Some("matroska"));
+
+        assert!(Pin::new(&mut second).start_send(headers("webm")).is_err());
+        assert_eq!(channel.lock().unwrap().doc_type(), Some("matroska"));
+        assert!(Pin::new(&mut second).start_send(headers("matroska")).is_ok());
+    }
+}
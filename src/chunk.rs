@@ -1,6 +1,7 @@
 use bytes::{Buf, Bytes};
 use futures3::prelude::*;
 use std::{
+    convert::TryFrom,
     io::Cursor,
     mem,
     pin::Pin,
@@ -8,6 +9,7 @@ use std::{
 };
 use crate::stream_parser::EbmlStreamingParser;
 use crate::error::WebmetroError;
+use crate::ebml::encode_fixed_integer;
 use crate::webm::*;
 
 #[derive(Clone, Debug)]
@@ -18,7 +20,11 @@ pub struct ClusterHead {
     /// space for a Cluster tag and a Timecode tag
     /// TODO: consider using a BytesMut here for simplicity
     bytes: [u8;16],
-    bytes_used: u8
+    bytes_used: u8,
+    /// whether the Timecode is always written in 8 bytes, keeping the head's size constant
+    fixed_size: bool,
+    /// whether the block deciding `keyframe` has been seen yet
+    keyframe_known: bool
 }
 
 impl ClusterHead {
@@ -28,11 +34,20 @@ impl ClusterHead {
             start: 0,
             end: 0,
             bytes: [0;16],
-            bytes_used: 0
+            bytes_used: 0,
+            fixed_size: false,
+            keyframe_known: false
         };
         cluster_head.update_timecode(timecode);
         cluster_head
     }
+    /// Always write the Timecode in 8 bytes, so the head stays the same size
+    /// as `update_timecode` rewrites it in place
+    pub fn with_fixed_size(mut self) -> ClusterHead {
+        self.fixed_size = true;
+        self.update_timecode(self.start);
+        self
+    }
     pub fn update_timecode(&mut self, timecode: u64) {
         let delta = self.end - self.start;
         self.start = timecode;
@@ -40,13 +55,17 @@ impl ClusterHead {
         let mut cursor = Cursor::new(self.bytes.as_mut());
         // buffer is sized so these should never fail
         encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
-        encode_webm_element(WebmElement::Timecode(timecode), &mut cursor).unwrap();
+        if self.fixed_size {
+            encode_fixed_integer(TIMECODE_ID, timecode, &mut cursor).unwrap();
+        } else {
+            encode_webm_element(WebmElement::Timecode(timecode), &mut cursor).unwrap();
+        }
         self.bytes_used = cursor.position() as u8;
     }
     pub fn observe_simpleblock_timecode(&mut self, timecode: i16) {
-        let absolute_timecode = self.start + (timecode as u64);
-        if absolute_timecode > self.start {
-            self.end = absolute_timecode;
+        let absolute_timecode = self.start as i64 + timecode as i64;
+        if absolute_timecode > self.start as i64 {
+            self.end = absolute_timecode as u64;
         }
     }
 }
@@ -66,6 +85,12 @@ pub enum Chunk {
     ClusterHead(ClusterHead),
     ClusterBody {
         bytes: Bytes
+    },
+    /// part of a cluster body, emitted as soon as it's read when
+    /// streaming partial clusters; `timecode` is that of its SimpleBlock
+    ClusterBodyPart {
+        bytes: Bytes,
+        timecode: u64
     }
 }
 
@@ -75,7 +100,8 @@ impl Chunk {
         match self {
             Chunk::Headers {bytes, ..} => bytes,
             Chunk::ClusterHead(cluster_head) => Bytes::from(cluster_head.as_ref()),
-            Chunk::ClusterBody {bytes, ..} => bytes
+            Chunk::ClusterBody {bytes, ..} => bytes,
+            Chunk::ClusterBodyPart {bytes, ..} => bytes
         }
     }
 }
@@ -85,17 +111,57 @@ impl AsRef<[u8]> for Chunk {
         match self {
             &Chunk::Headers {ref bytes, ..} => bytes.as_ref(),
             &Chunk::ClusterHead(ref cluster_head) => cluster_head.as_ref(),
-            &Chunk::ClusterBody {ref bytes, ..} => bytes.as_ref()
+            &Chunk::ClusterBody {ref bytes, ..} => bytes.as_ref(),
+            &Chunk::ClusterBodyPart {ref bytes, ..} => bytes.as_ref()
         }
     }
 }
 
+/// Rules for starting new clusters partway through the source stream's clusters,
+/// so that encoders emitting long clusters don't make viewers wait for each one to finish.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClusterSplitting {
+    /// start a new cluster at every video keyframe
+    pub on_keyframe: bool,
+    /// start a new cluster once a cluster spans this many milliseconds
+    pub max_duration: Option<u64>,
+    /// start a new cluster once a cluster body reaches this many bytes
+    pub max_size: Option<usize>
+}
+
+impl ClusterSplitting {
+    fn should_split(&self, cluster_head: &ClusterHead, body_size: usize, block_timecode: u64, video_keyframe: bool) -> bool {
+        if body_size == 0 {
+            // never emit an empty cluster
+            return false;
+        }
+
+        let keyframe_split = self.on_keyframe && video_keyframe;
+        let duration_split = self.max_duration
+            .is_some_and(|max| block_timecode >= cluster_head.start + max);
+        let size_split = self.max_size
+            .is_some_and(|max| body_size >= max);
+
+        keyframe_split || duration_split || size_split
+    }
+}
+
 #[derive(Debug)]
 enum ChunkerState {
     BuildingHeader(Cursor<Vec<u8>>),
     // ClusterHead & body buffer
     BuildingCluster(ClusterHead, Cursor<Vec<u8>>),
     EmittingClusterBody(Vec<u8>),
+    // cluster body, followed by the cluster that was split off from it
+    EmittingClusterBodyBeforeSplit {
+        body: Vec<u8>,
+        next_cluster: Option<(ClusterHead, Cursor<Vec<u8>>)>
+    },
+    // ClusterHead to resume building after emitting a partial body
+    EmittingClusterBodyPart {
+        cluster_head: ClusterHead,
+        part: Chunk
+    },
     EmittingClusterBodyBeforeNewHeader {
         body: Vec<u8>,
         new_header: Cursor<Vec<u8>>
@@ -104,9 +170,26 @@ enum ChunkerState {
     End
 }
 
+/// Which DocTypes a chunker accepts, and which one the input started with
+#[derive(Debug)]
+struct DocTypeCheck {
+    accepted: Vec<String>,
+    doc_type: Option<String>
+}
+
 pub struct WebmChunker<S> {
     source: EbmlStreamingParser<S>,
     buffer_size_limit: Option<usize>,
+    doc_type_check: Option<DocTypeCheck>,
+    splitting: Option<ClusterSplitting>,
+    video_tracks: Vec<u64>,
+    /// the Timecode of the source cluster currently being read, which may differ
+    /// from the emitted cluster's timecode if it has been split
+    source_timecode: u64,
+    partial_clusters: bool,
+    cluster_head_sent: bool,
+    /// bytes of the current cluster's body already emitted as parts
+    emitted_body_size: usize,
     state: ChunkerState
 }
 
@@ -118,6 +201,68 @@ impl<S> WebmChunker<S> {
         self.buffer_size_limit = Some(limit);
         self
     }
+
+    /// start new clusters within the source's clusters according to the given rules;
+    /// SimpleBlock timecodes are rewritten relative to the new clusters.
+    pub fn with_cluster_splitting(mut self, splitting: ClusterSplitting) -> Self {
+        self.splitting = Some(splitting);
+        self
+    }
+
+    /// emit each ClusterHead as soon as its first block is read, followed by
+    /// the cluster's blocks one by one as `ClusterBodyPart`s, instead of
+    /// holding the whole cluster back until the next one starts.
+    pub fn with_partial_clusters(mut self) -> Self {
+        self.partial_clusters = true;
+        self
+    }
+
+    /// error the stream unless it starts with an EBML header declaring one
+    /// of these DocTypes (e.g. "webm"), and every later EBML header in it
+    /// declares the same DocType as the first
+    pub fn with_accepted_doc_types(mut self, doc_types: Vec<String>) -> Self {
+        self.doc_type_check = Some(DocTypeCheck {
+            accepted: doc_types,
+            doc_type: None
+        });
+        self
+    }
+}
+
+/// turns events the DocType check (if any) rejects into errors
+fn check_doc_type<'a>(
+    check: &mut Option<DocTypeCheck>,
+    event: Poll<Option<Result<WebmElement<'a>, WebmetroError>>>
+) -> Poll<Option<Result<WebmElement<'a>, WebmetroError>>> {
+    let check = match check {
+        Some(check) => check,
+        None => return event
+    };
+
+    match event {
+        Ready(Some(Ok(WebmElement::EbmlHead(header)))) => {
+            let doc_type = header.doc_type();
+            match check.doc_type {
+                Some(ref first) if first != doc_type => {
+                    let message = format!("DocType changed from \"{}\" to \"{}\" partway through the stream", first, doc_type);
+                    return Ready(Some(Err(message.as_str().into())));
+                },
+                Some(_) => {},
+                None if check.accepted.iter().any(|accepted| accepted == doc_type) => {
+                    check.doc_type = Some(doc_type.to_string());
+                },
+                None => {
+                    let message = format!("Unsupported DocType \"{}\" (accepting {})", doc_type, check.accepted.join(", "));
+                    return Ready(Some(Err(message.as_str().into())));
+                }
+            }
+            event
+        },
+        Ready(Some(Ok(_))) if check.doc_type.is_none() => {
+            Ready(Some(Err("Input doesn't start with an EBML header".into())))
+        },
+        event => event
+    }
 }
 
 fn encode(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
@@ -130,6 +275,35 @@ fn encode(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, limit: Option<usiz
     encode_webm_element(element, buffer).map_err(|err| err.into())
 }
 
+/// The track, timecode & keyframe flag of a SimpleBlock or BlockGroup
+fn block_details(block: &WebmElement) -> (u64, i16, bool) {
+    match block {
+        WebmElement::SimpleBlock(block) => (block.track, block.timecode, block.is_keyframe()),
+        WebmElement::BlockGroup(group) => (group.track, group.timecode, group.keyframe),
+        _ => panic!("Not a block: {:?}", block)
+    }
+}
+
+fn set_block_timecode(block: &mut WebmElement, timecode: i16) {
+    match block {
+        WebmElement::SimpleBlock(block) => block.timecode = timecode,
+        WebmElement::BlockGroup(group) => group.timecode = timecode,
+        _ => panic!("Not a block: {:?}", block)
+    }
+}
+
+/// Adds a SimpleBlock or BlockGroup to the cluster
+fn add_block(cluster_head: &mut ClusterHead, buffer: &mut Cursor<Vec<u8>>, block: WebmElement, video_tracks: &[u64], limit: Option<usize>) -> Result<(), WebmetroError> {
+    let (track, timecode, keyframe) = block_details(&block);
+    // a cluster is a starting point if its first video block (or first block, without video) is a keyframe
+    if !cluster_head.keyframe_known && (video_tracks.is_empty() || video_tracks.contains(&track)) {
+        cluster_head.keyframe = keyframe;
+        cluster_head.keyframe_known = true;
+    }
+    cluster_head.observe_simpleblock_timecode(timecode);
+    encode(block, buffer, limit)
+}
+
 impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for WebmChunker<S>
 {
     type Item = Result<Chunk, WebmetroError>;
@@ -139,7 +313,7 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for Webm
         loop {
             match chunker.state {
                 ChunkerState::BuildingHeader(ref mut buffer) => {
-                    match chunker.source.poll_event(cx) {
+                    match check_doc_type(&mut chunker.doc_type_check, chunker.source.poll_event(cx)) {
                         Ready(Some(Err(passthru))) => return Ready(Some(Err(passthru))),
                         Pending => return Pending,
                         Ready(None) => return Ready(None),
@@ -158,6 +332,9 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for Webm
                             WebmElement::Void => {},
                             WebmElement::Unknown(_) => {},
                             element => {
+                                if let WebmElement::Tracks(tracks) = element {
+                                    chunker.video_tracks = video_track_numbers(tracks);
+                                }
                                 if let Err(err) = encode(element, buffer, chunker.buffer_size_limit) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
@@ -167,49 +344,124 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for Webm
                     }
                 },
                 ChunkerState::BuildingCluster(ref mut cluster_head, ref mut buffer) => {
-                    match chunker.source.poll_event(cx) {
+                    match check_doc_type(&mut chunker.doc_type_check, chunker.source.poll_event(cx)) {
                         Ready(Some(Err(passthru))) => return Ready(Some(Err(passthru))),
                         Pending => return Pending,
                         Ready(Some(Ok(element))) => match element {
-                            WebmElement::EbmlHead | WebmElement::Segment => {
+                            WebmElement::EbmlHead(_) | WebmElement::Segment => {
                                 let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                chunker.emitted_body_size = 0;
 
                                 let mut new_header_cursor = Cursor::new(Vec::new());
-                                match encode(element, &mut new_header_cursor, chunker.buffer_size_limit) {
-                                    Ok(_) => {
-                                        chunker.state = ChunkerState::EmittingClusterBodyBeforeNewHeader{
-                                            body: liberated_buffer.into_inner(),
-                                            new_header: new_header_cursor
-                                        };
-                                        return Ready(Some(Ok(Chunk::ClusterHead(liberated_cluster_head))));
-                                    },
-                                    Err(err) => {
-                                        chunker.state = ChunkerState::End;
-                                        return Ready(Some(Err(err)));
-                                    }
+                                if let Err(err) = encode(element, &mut new_header_cursor, chunker.buffer_size_limit) {
+                                    chunker.state = ChunkerState::End;
+                                    return Ready(Some(Err(err)));
+                                }
+
+                                if chunker.cluster_head_sent {
+                                    // cluster was already streamed out in parts
+                                    chunker.cluster_head_sent = false;
+                                    chunker.state = ChunkerState::BuildingHeader(new_header_cursor);
+                                    continue;
                                 }
+
+                                chunker.state = ChunkerState::EmittingClusterBodyBeforeNewHeader{
+                                    body: liberated_buffer.into_inner(),
+                                    new_header: new_header_cursor
+                                };
+                                return Ready(Some(Ok(Chunk::ClusterHead(liberated_cluster_head))));
                             },
                             WebmElement::Cluster => {
                                 let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                chunker.emitted_body_size = 0;
+
+                                if chunker.cluster_head_sent {
+                                    // cluster was already streamed out in parts
+                                    chunker.cluster_head_sent = false;
+                                    continue;
+                                }
 
                                 chunker.state = ChunkerState::EmittingClusterBody(liberated_buffer.into_inner());
                                 return Ready(Some(Ok(Chunk::ClusterHead(liberated_cluster_head))));
                             },
                             WebmElement::Timecode(timecode) => {
+                                chunker.source_timecode = timecode;
                                 cluster_head.update_timecode(timecode);
                             },
-                            WebmElement::SimpleBlock(ref block) => {
-                                if (block.flags & 0b10000000) != 0 {
-                                    // TODO: this is incorrect, condition needs to also affirm we're the first video block of the cluster
-                                    cluster_head.keyframe = true;
+                            WebmElement::SimpleBlock(_) | WebmElement::BlockGroup(_) => {
+                                let mut block = element;
+                                let (track, block_timecode, keyframe) = block_details(&block);
+                                if let Some(splitting) = chunker.splitting {
+                                    let absolute_timecode = chunker.source_timecode as i64 + block_timecode as i64;
+                                    let video_keyframe = keyframe && chunker.video_tracks.contains(&track);
+                                    let body_size = chunker.emitted_body_size + buffer.get_ref().len();
+
+                                    if splitting.should_split(cluster_head, body_size, absolute_timecode.max(0) as u64, video_keyframe) {
+                                        let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(absolute_timecode.max(0) as u64));
+                                        let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                        chunker.emitted_body_size = 0;
+
+                                        if chunker.cluster_head_sent {
+                                            // the previous cluster was already streamed out in parts,
+                                            // so the block can just go into the new one as normal
+                                            chunker.cluster_head_sent = false;
+                                        } else {
+                                            set_block_timecode(&mut block, (absolute_timecode - cluster_head.start as i64) as i16);
+                                            if let Err(err) = add_block(cluster_head, buffer, block, &chunker.video_tracks, chunker.buffer_size_limit) {
+                                                chunker.state = ChunkerState::End;
+                                                return Ready(Some(Err(err)));
+                                            }
+
+                                            let next_cluster = (
+                                                mem::replace(cluster_head, ClusterHead::new(0)),
+                                                mem::replace(buffer, Cursor::new(Vec::new()))
+                                            );
+                                            chunker.state = ChunkerState::EmittingClusterBodyBeforeSplit {
+                                                body: liberated_buffer.into_inner(),
+                                                next_cluster: Some(next_cluster)
+                                            };
+                                            return Ready(Some(Ok(Chunk::ClusterHead(liberated_cluster_head))));
+                                        }
+                                    }
+
+                                    match i16::try_from(absolute_timecode - cluster_head.start as i64) {
+                                        Ok(timecode) => set_block_timecode(&mut block, timecode),
+                                        Err(_) => {
+                                            chunker.state = ChunkerState::End;
+                                            return Ready(Some(Err("Block timecode out of range after splitting cluster".into())));
+                                        }
+                                    }
                                 }
-                                cluster_head.observe_simpleblock_timecode(block.timecode);
-                                if let Err(err) = encode(WebmElement::SimpleBlock(*block), buffer, chunker.buffer_size_limit) {
+
+                                if let Err(err) = add_block(cluster_head, buffer, block, &chunker.video_tracks, chunker.buffer_size_limit) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
                                 }
+
+                                // until the block deciding if the cluster starts with a keyframe, hold the blocks back
+                                if chunker.partial_clusters && (chunker.cluster_head_sent || cluster_head.keyframe_known) {
+                                    let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                    chunker.emitted_body_size += liberated_buffer.get_ref().len();
+                                    let part = Chunk::ClusterBodyPart {
+                                        bytes: Bytes::from(liberated_buffer.into_inner()),
+                                        timecode: (cluster_head.start as i64 + block_details(&block).1 as i64).max(0) as u64
+                                    };
+
+                                    if chunker.cluster_head_sent {
+                                        return Ready(Some(Ok(part)));
+                                    }
+
+                                    // now it's known if the cluster starts with a keyframe, the head can go out
+                                    chunker.cluster_head_sent = true;
+                                    let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
+                                    chunker.state = ChunkerState::EmittingClusterBodyPart {
+                                        cluster_head: liberated_cluster_head.clone(),
+                                        part
+                                    };
+                                    return Ready(Some(Ok(Chunk::ClusterHead(liberated_cluster_head))));
+                                }
                             },
                             WebmElement::Info => {},
                             WebmElement::Void => {},
@@ -219,9 +471,25 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for Webm
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
                                 }
+
+                                if chunker.cluster_head_sent && !buffer.get_ref().is_empty() {
+                                    // the cluster is going out in parts, so this can't wait for the next block
+                                    let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                    chunker.emitted_body_size += liberated_buffer.get_ref().len();
+                                    return Ready(Some(Ok(Chunk::ClusterBodyPart {
+                                        bytes: Bytes::from(liberated_buffer.into_inner()),
+                                        timecode: cluster_head.end
+                                    })));
+                                }
                             },
                         },
                         Ready(None) => {
+                            if chunker.cluster_head_sent {
+                                // cluster was already streamed out in parts
+                                chunker.state = ChunkerState::End;
+                                return Ready(None);
+                            }
+
                             // flush final Cluster on end of stream
                             let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
                             let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
@@ -240,6 +508,20 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> Stream for Webm
                     );
                     return Ready(Some(Ok(Chunk::ClusterBody {bytes: Bytes::from(liberated_buffer)})));
                 },
+                ChunkerState::EmittingClusterBodyPart { ref mut cluster_head, ref mut part } => {
+                    let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
+                    let liberated_part = mem::replace(part, Chunk::ClusterBody {bytes: Bytes::new()});
+
+                    chunker.state = ChunkerState::BuildingCluster(liberated_cluster_head, Cursor::new(Vec::new()));
+                    return Ready(Some(Ok(liberated_part)));
+                },
+                ChunkerState::EmittingClusterBodyBeforeSplit { ref mut body, ref mut next_cluster } => {
+                    let liberated_body = mem::take(body);
+                    let (next_cluster_head, next_buffer) = next_cluster.take().expect("Split cluster already emitted");
+
+                    chunker.state = ChunkerState::BuildingCluster(next_cluster_head, next_buffer);
+                    return Ready(Some(Ok(Chunk::ClusterBody {bytes: Bytes::from(liberated_body)})));
+                },
                 ChunkerState::EmittingClusterBodyBeforeNewHeader { ref mut body, ref mut new_header } => {
                     let liberated_body = mem::replace(body, Vec::new());
                     let liberated_header_cursor = mem::replace(new_header, Cursor::new(Vec::new()));
@@ -271,6 +553,13 @@ impl<S: Stream> WebmStream for EbmlStreamingParser<S> {
         WebmChunker {
             source: self,
             buffer_size_limit: None,
+            doc_type_check: None,
+            splitting: None,
+            video_tracks: Vec::new(),
+            source_timecode: 0,
+            partial_clusters: false,
+            cluster_head_sent: false,
+            emitted_body_size: 0,
             state: ChunkerState::BuildingHeader(Cursor::new(Vec::new()))
         }
     }
@@ -278,11 +567,327 @@ impl<S: Stream> WebmStream for EbmlStreamingParser<S> {
 
 #[cfg(test)]
 mod tests {
+    use bytes::IntoBuf;
+    use futures3::{FutureExt, TryStreamExt};
+    use matches::assert_matches;
 
     use crate::chunk::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::TEST_FILE;
 
     #[test]
     fn enough_space_for_header() {
         ClusterHead::new(u64::max_value());
+        ClusterHead::new(u64::MAX).with_fixed_size();
+    }
+
+    #[test]
+    fn cluster_head_sizes() {
+        let mut cluster_head = ClusterHead::new(0);
+        assert_eq!(cluster_head.as_ref(), &[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x81, 0x00]);
+        cluster_head.update_timecode(1000);
+        assert_eq!(cluster_head.as_ref(), &[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x82, 0x03, 0xE8]);
+
+        let mut cluster_head = ClusterHead::new(0).with_fixed_size();
+        assert_eq!(cluster_head.as_ref(), &[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x88, 0, 0, 0, 0, 0, 0, 0, 0]);
+        cluster_head.update_timecode(1000);
+        assert_eq!(cluster_head.as_ref(), &[0x1F, 0x43, 0xB6, 0x75, 0xFF, 0xE7, 0x88, 0, 0, 0, 0, 0, 0, 0x03, 0xE8]);
+    }
+
+    /// a single 2-second cluster of video track 1, with a block every 100ms and a keyframe every 500ms
+    fn long_cluster_file() -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Tracks(&TEST_FILE[358..421]), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(5000), &mut cursor).unwrap();
+        for index in 0..20 {
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                track: 1,
+                timecode: index * 100,
+                flags: if index % 5 == 0 { 0b10000000 } else { 0 },
+                data: &[0; 100]
+            }), &mut cursor).unwrap();
+        }
+        cursor.into_inner()
+    }
+
+    fn chunk(file: &[u8], splitting: Option<ClusterSplitting>) -> Vec<Chunk> {
+        chunk_with(file, splitting, false)
+    }
+
+    fn chunk_with(file: &[u8], splitting: Option<ClusterSplitting>, partial_clusters: bool) -> Vec<Chunk> {
+        let mut chunker = futures3::stream::iter(file.chunks(64))
+            .map(|bytes| Ok(bytes.into_buf()))
+            .parse_ebml()
+            .chunk_webm();
+        if let Some(splitting) = splitting {
+            chunker = chunker.with_cluster_splitting(splitting);
+        }
+        if partial_clusters {
+            chunker = chunker.with_partial_clusters();
+        }
+
+        chunker.try_collect().now_or_never().expect("Test tried to block on I/O").unwrap()
+    }
+
+    /// (cluster start, keyframe, block timecodes) for each cluster
+    fn clusters(chunks: &[Chunk]) -> Vec<(u64, bool, Vec<i16>)> {
+        let mut clusters = Vec::new();
+        for chunk in chunks {
+            match chunk {
+                Chunk::ClusterHead(head) => clusters.push((head.start, head.keyframe, Vec::new())),
+                Chunk::ClusterBody {bytes} | Chunk::ClusterBodyPart {bytes, ..} => {
+                    let timecodes = &mut clusters.last_mut().unwrap().2;
+                    for element in parse_webm(bytes) {
+                        match element {
+                            WebmElement::SimpleBlock(block) => timecodes.push(block.timecode),
+                            WebmElement::BlockGroup(group) => timecodes.push(group.timecode),
+                            _ => {}
+                        }
+                    }
+                },
+                Chunk::Headers {..} => {}
+            }
+        }
+        clusters
+    }
+
+    #[test]
+    fn no_splitting_by_default() {
+        let clusters = clusters(&chunk(&long_cluster_file(), None));
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].0, 5000);
+        assert_eq!(clusters[0].2.len(), 20);
+    }
+
+    #[test]
+    fn split_on_keyframes() {
+        let clusters = clusters(&chunk(&long_cluster_file(), Some(ClusterSplitting {
+            on_keyframe: true,
+            ..ClusterSplitting::default()
+        })));
+
+        assert_eq!(clusters, vec![
+            (5000, true, vec![0, 100, 200, 300, 400]),
+            (5500, true, vec![0, 100, 200, 300, 400]),
+            (6000, true, vec![0, 100, 200, 300, 400]),
+            (6500, true, vec![0, 100, 200, 300, 400]),
+        ]);
+    }
+
+    #[test]
+    fn split_on_duration() {
+        let clusters = clusters(&chunk(&long_cluster_file(), Some(ClusterSplitting {
+            max_duration: Some(300),
+            ..ClusterSplitting::default()
+        })));
+
+        assert_eq!(clusters.len(), 7);
+        assert_eq!(clusters[0], (5000, true, vec![0, 100, 200]));
+        // only clusters whose first block is a keyframe are marked as starting points
+        assert_eq!(clusters[1], (5300, false, vec![0, 100, 200]));
+        assert_eq!(clusters[2], (5600, false, vec![0, 100, 200]));
+        assert_eq!(clusters[6], (6800, false, vec![0, 100]));
+    }
+
+    #[test]
+    fn split_on_size() {
+        let clusters = clusters(&chunk(&long_cluster_file(), Some(ClusterSplitting {
+            max_size: Some(400),
+            ..ClusterSplitting::default()
+        })));
+
+        // each encoded block is 107 bytes, so 4 fit before the limit is reached
+        assert_eq!(clusters.len(), 5);
+        assert!(clusters.iter().all(|cluster| cluster.2 == vec![0, 100, 200, 300]));
+        assert_eq!(clusters[4].0, 6600);
+    }
+
+    /// clusters of audio (track 2) & video (track 1) blocks, as (flags, track) for each block
+    fn mixed_cluster_file(clusters: &[&[(u8, u64)]]) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Tracks(&TEST_FILE[358..421]), &mut cursor).unwrap();
+        for (index, blocks) in clusters.iter().enumerate() {
+            encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+            encode_webm_element(WebmElement::Timecode(index as u64 * 1000), &mut cursor).unwrap();
+            for (block_index, &(flags, track)) in blocks.iter().enumerate() {
+                encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                    track,
+                    timecode: block_index as i16 * 10,
+                    flags,
+                    data: &[0; 10]
+                }), &mut cursor).unwrap();
+            }
+        }
+        cursor.into_inner()
+    }
+
+    #[test]
+    fn block_groups_kept() {
+        // a BlockGroup on track 1 at the given timecode, referencing an earlier frame if not a keyframe
+        let block_group = |timecode: i16, keyframe: bool| {
+            let [high, low] = timecode.to_be_bytes();
+            let mut group = vec![0xA0, 0x86, 0xA1, 0x84, 0x81, high, low, 0x00];
+            if !keyframe {
+                group[1] += 3;
+                group.extend_from_slice(&[0xFB, 0x81, 0xD8]);
+            }
+            group
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Tracks(&TEST_FILE[358..421]), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::Timecode(1000), &mut cursor).unwrap();
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {track: 1, timecode: 0, flags: 0x80, data: &[0; 10]}), &mut cursor).unwrap();
+        cursor.get_mut().extend(block_group(40, false));
+        cursor.get_mut().extend(block_group(600, true));
+        cursor.get_mut().extend(block_group(640, false));
+        let file = cursor.into_inner();
+
+        assert_eq!(clusters(&chunk(&file, None)), vec![
+            (1000, true, vec![0, 40, 600, 640])
+        ]);
+        // keyframe groups can start new clusters, and the rest follow their cluster's timecode
+        assert_eq!(clusters(&chunk(&file, Some(ClusterSplitting {
+            on_keyframe: true,
+            ..ClusterSplitting::default()
+        }))), vec![
+            (1000, true, vec![0, 40]),
+            (1600, true, vec![0, 40])
+        ]);
+    }
+
+    #[test]
+    fn keyframe_from_first_video_block() {
+        let file = mixed_cluster_file(&[
+            // audio keyframe first, then a video keyframe
+            &[(0b10000000, 2), (0b10000000, 1), (0, 1)],
+            // audio keyframe, then a video delta frame, then a keyframe mid-cluster
+            &[(0b10000000, 2), (0, 1), (0b10000000, 1)]
+        ]);
+
+        let keyframes: Vec<bool> = clusters(&chunk(&file, None)).iter().map(|cluster| cluster.1).collect();
+        assert_eq!(keyframes, vec![true, false]);
+
+        // partial clusters hold the audio block back until the first video block decides
+        let chunks = chunk_with(&file, None, true);
+        assert_eq!(clusters(&chunks), clusters(&chunk(&file, None)));
+        assert_matches!(chunks[1], Chunk::ClusterHead(ClusterHead {start: 0, keyframe: true, ..}));
+        assert_matches!(chunks[2], Chunk::ClusterBodyPart {timecode: 10, ..});
+        assert_matches!(chunks[4], Chunk::ClusterHead(ClusterHead {start: 1000, keyframe: false, ..}));
+    }
+
+    #[test]
+    fn partial_clusters() {
+        let chunks = chunk_with(&long_cluster_file(), None, true);
+
+        // header, cluster head, then one part per block
+        assert_eq!(chunks.len(), 22);
+        assert_matches!(chunks[0], Chunk::Headers {..});
+        assert_matches!(chunks[1], Chunk::ClusterHead(ClusterHead {start: 5000, keyframe: true, ..}));
+        assert_matches!(chunks[2], Chunk::ClusterBodyPart {timecode: 5000, ..});
+        assert_matches!(chunks[21], Chunk::ClusterBodyPart {timecode: 6900, ..});
+
+        // reassembles into the same stream as whole clusters
+        let whole: Vec<u8> = chunk(&long_cluster_file(), None).iter().flat_map(|chunk| chunk.as_ref().to_vec()).collect();
+        let parts: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.as_ref().to_vec()).collect();
+        assert_eq!(whole, parts);
+    }
+
+    #[test]
+    fn partial_clusters_across_headers() {
+        let mut file = long_cluster_file();
+        file.extend(long_cluster_file());
+        let splitting = ClusterSplitting {
+            max_size: Some(400),
+            ..ClusterSplitting::default()
+        };
+
+        // the size limit starts over with the new header's first cluster, instead of splitting it off empty
+        let clusters = clusters(&chunk_with(&file, Some(splitting), true));
+        assert_eq!(clusters.len(), 10);
+        assert!(clusters.iter().all(|cluster| cluster.2 == vec![0, 100, 200, 300]));
+    }
+
+    #[test]
+    fn partial_clusters_keep_trailing_elements() {
+        let mut file = Cursor::new(long_cluster_file());
+        file.set_position(file.get_ref().len() as u64);
+        // an element after the cluster's last block has no later block to go out with
+        encode_webm_element(WebmElement::Tracks(&TEST_FILE[358..421]), &mut file).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut file).unwrap();
+        encode_webm_element(WebmElement::Timecode(7000), &mut file).unwrap();
+        let file = file.into_inner();
+
+        let whole: Vec<u8> = chunk(&file, None).iter().flat_map(|chunk| chunk.as_ref().to_vec()).collect();
+        let parts: Vec<u8> = chunk_with(&file, None, true).iter().flat_map(|chunk| chunk.as_ref().to_vec()).collect();
+        assert_eq!(whole, parts);
+    }
+
+    #[test]
+    fn partial_clusters_with_splitting() {
+        let chunks = chunk_with(&long_cluster_file(), Some(ClusterSplitting {
+            on_keyframe: true,
+            ..ClusterSplitting::default()
+        }), true);
+
+        assert_eq!(chunks.len(), 1 + 4 * 6);
+        assert_eq!(clusters(&chunks), clusters(&chunk(&long_cluster_file(), Some(ClusterSplitting {
+            on_keyframe: true,
+            ..ClusterSplitting::default()
+        }))));
+        assert_matches!(chunks[7], Chunk::ClusterHead(ClusterHead {start: 5500, keyframe: true, ..}));
+        assert_matches!(chunks[8], Chunk::ClusterBodyPart {timecode: 5500, ..});
+    }
+
+    #[test]
+    fn accepted_doc_types() {
+        let accepting_webm = |file: &[u8]| futures3::stream::iter(file.chunks(64))
+            .map(|bytes| Ok(bytes.into_buf()))
+            .parse_ebml()
+            .chunk_webm()
+            .with_accepted_doc_types(vec!["webm".into()])
+            .try_collect::<Vec<_>>()
+            .now_or_never()
+            .expect("Test tried to block on I/O");
+
+        assert_eq!(accepting_webm(&long_cluster_file()).unwrap().len(), 3);
+
+        let mut webm_header = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut webm_header).unwrap();
+        let header_len = webm_header.get_ref().len();
+
+        let mut matroska = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader {
+            doc_type: Some("matroska"),
+            ..EbmlHeader::default()
+        }), &mut matroska).unwrap();
+        matroska.get_mut().extend_from_slice(&long_cluster_file()[header_len..]);
+        assert_eq!(chunk(matroska.get_ref(), None).len(), 3);
+        assert!(accepting_webm(matroska.get_ref()).is_err());
+
+        // no header at all
+        assert!(accepting_webm(&long_cluster_file()[header_len..]).is_err());
+
+        // a Matroska stream spliced onto a WebM one
+        let mut mixed = long_cluster_file();
+        mixed.extend_from_slice(matroska.get_ref());
+        let accepting_both = futures3::stream::iter(mixed.chunks(64))
+            .map(|bytes| Ok(bytes.into_buf()))
+            .parse_ebml()
+            .chunk_webm()
+            .with_accepted_doc_types(vec!["webm".into(), "matroska".into()])
+            .try_collect::<Vec<_>>()
+            .now_or_never()
+            .expect("Test tried to block on I/O");
+        assert!(accepting_both.is_err());
+        assert_eq!(accepting_webm(&[long_cluster_file(), long_cluster_file()].concat()).unwrap().len(), 6);
     }
 }
@@ -0,0 +1,252 @@
+use hyper13::{
+    client::{HttpConnector, ResponseFuture},
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    Body,
+    Client,
+    Method,
+    Request
+};
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, TlsConnector};
+
+use crate::error::WebmetroError;
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Settings for the HTTP(S) client used to upload streams to a relay
+pub struct ClientOptions {
+    method: Method,
+    headers: HeaderMap,
+    root_certificates: Vec<Certificate>,
+    insecure: bool
+}
+
+impl ClientOptions {
+    pub fn new() -> ClientOptions {
+        ClientOptions {
+            method: Method::PUT,
+            headers: HeaderMap::new(),
+            root_certificates: Vec::new(),
+            insecure: false
+        }
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// add a header given as "Name: value"
+    pub fn with_header(mut self, header: &str) -> Result<Self, WebmetroError> {
+        let invalid = || WebmetroError::from(format!("Invalid header: {}", header).as_str());
+
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().map(str::trim).ok_or_else(invalid)?;
+        let value = parts.next().map(str::trim).ok_or_else(invalid)?;
+        self.headers.append(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+            HeaderValue::from_str(value).map_err(|_| invalid())?
+        );
+        Ok(self)
+    }
+
+    pub fn with_bearer_token(mut self, token: &str) -> Result<Self, WebmetroError> {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|_| WebmetroError::from("Invalid bearer token"))?;
+        self.headers.insert(AUTHORIZATION, value);
+        Ok(self)
+    }
+
+    /// trust the certificate authorities in a PEM file, in addition to the system's
+    pub fn with_ca_bundle(mut self, pem: &[u8]) -> Result<Self, WebmetroError> {
+        let pem = String::from_utf8_lossy(pem);
+        let mut found = false;
+        // native-tls only reads the first certificate of a PEM file, so split them up
+        for certificate in pem.split_terminator(PEM_CERTIFICATE_END) {
+            if certificate.trim().is_empty() {
+                continue;
+            }
+            let certificate = format!("{}{}", certificate, PEM_CERTIFICATE_END);
+            self.root_certificates.push(Certificate::from_pem(certificate.as_bytes())?);
+            found = true;
+        }
+
+        if !found {
+            return Err("No certificates found in CA bundle".into());
+        }
+        Ok(self)
+    }
+
+    /// skip verifying the server's certificate; only for testing!
+    pub fn insecure(mut self) -> Self {
+        self.insecure = true;
+        self
+    }
+
+    pub fn build(self) -> Result<UploadClient, WebmetroError> {
+        let mut tls = TlsConnector::builder();
+        for certificate in self.root_certificates {
+            tls.add_root_certificate(certificate);
+        }
+        if self.insecure {
+            tls.danger_accept_invalid_certs(true);
+        }
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let connector = HttpsConnector::from((http, tls.build()?.into()));
+
+        Ok(UploadClient {
+            client: Client::builder().build(connector),
+            method: self.method,
+            headers: self.headers
+        })
+    }
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions::new()
+    }
+}
+
+pub struct UploadClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    method: Method,
+    headers: HeaderMap
+}
+
+impl UploadClient {
+    /// Starts a request sending `body` to `url`, with the configured method & headers
+    pub fn upload(&self, url: &str, body: Body) -> Result<ResponseFuture, WebmetroError> {
+        let mut request = Request::builder()
+            .method(self.method.clone())
+            .uri(url)
+            .body(body)?;
+        request.headers_mut().extend(self.headers.clone());
+        Ok(self.client.request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{spawn, JoinHandle};
+
+    use futures3::TryStreamExt;
+    use hyper13::{Body, Method};
+    use native_tls::{Identity, TlsAcceptor};
+    use tokio2::runtime::Runtime;
+
+    use crate::client::*;
+    use crate::error::WebmetroError;
+    use crate::tests::{TEST_CA, TEST_SERVER_IDENTITY};
+
+    /// Accepts one HTTPS request on a local port, answering "ok";
+    /// the thread yields the raw request, or None if the handshake failed.
+    fn tls_stand_in() -> (u16, JoinHandle<Option<String>>) {
+        let identity = Identity::from_pkcs12(TEST_SERVER_IDENTITY, "webmetro").unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).ok()?;
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request_complete(&request) {
+                let read = stream.read(&mut buffer).ok()?;
+                if read == 0 {
+                    return None;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").unwrap();
+            Some(String::from_utf8(request).unwrap())
+        });
+
+        (port, server)
+    }
+
+    /// whether a request with a Content-Length body has been entirely read
+    fn request_complete(request: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(request);
+        let header_end = match text.find("\r\n\r\n") {
+            Some(index) => index + 4,
+            None => return false
+        };
+        let content_length = text[..header_end].lines()
+            .filter_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|length| length.trim().to_string()))
+            .find_map(|length| length.parse::<usize>().ok())
+            .unwrap_or(0);
+        request.len() >= header_end + content_length
+    }
+
+    fn upload(options: ClientOptions, url: &str) -> Result<Vec<u8>, WebmetroError> {
+        let client = options.build()?;
+        Runtime::new().unwrap().block_on(async {
+            let response = client.upload(url, Body::from("hello"))?.await?;
+            let body = response.into_body().try_concat().await?;
+            Ok(body.to_vec())
+        })
+    }
+
+    #[test]
+    fn trusts_ca_bundle() {
+        let (port, server) = tls_stand_in();
+        let options = ClientOptions::new().with_ca_bundle(TEST_CA).unwrap();
+
+        let response = upload(options, &format!("https://localhost:{}/live/test", port)).unwrap();
+        assert_eq!(response, b"ok");
+
+        let request = server.join().unwrap().expect("Handshake failed");
+        assert!(request.starts_with("PUT /live/test HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn rejects_unknown_certificate() {
+        let (port, server) = tls_stand_in();
+
+        assert!(upload(ClientOptions::new(), &format!("https://localhost:{}/live/test", port)).is_err());
+        assert_eq!(server.join().unwrap(), None);
+    }
+
+    #[test]
+    fn insecure_skips_verification() {
+        let (port, server) = tls_stand_in();
+        let options = ClientOptions::new().insecure();
+
+        assert_eq!(upload(options, &format!("https://localhost:{}/live/test", port)).unwrap(), b"ok");
+        assert!(server.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn sends_method_and_headers() {
+        let (port, server) = tls_stand_in();
+        let options = ClientOptions::new()
+            .with_ca_bundle(TEST_CA).unwrap()
+            .with_method(Method::POST)
+            .with_header("X-Stream-Key: abc123").unwrap()
+            .with_bearer_token("secret").unwrap();
+
+        upload(options, &format!("https://localhost:{}/live/test", port)).unwrap();
+
+        let request = server.join().unwrap().expect("Handshake failed");
+        assert!(request.starts_with("POST /live/test HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nx-stream-key: abc123\r\n"));
+        assert!(request.contains("\r\nauthorization: Bearer secret\r\n"));
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(ClientOptions::new().with_header("no colon").is_err());
+        assert!(ClientOptions::new().with_header("bad name: value").is_err());
+        assert!(ClientOptions::new().with_bearer_token("line\nbreak").is_err());
+        assert!(ClientOptions::new().with_ca_bundle(b"not a certificate").is_err());
+    }
+}
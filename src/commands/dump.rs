@@ -1,35 +1,326 @@
-use clap::{App, AppSettings, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
 use tokio2::runtime::Runtime;
 
-use super::stdin_stream;
+use super::{input_arg, input_stream};
 use webmetro::{
+    ebml::EbmlValue,
     error::WebmetroError,
-    stream_parser::StreamEbml,
+    schema::{lookup, ElementType, Nesting, SchemaElement},
+    stream_parser::{ElementPosition, StreamEbml},
     webm::{
+        parse_tracks,
         SimpleBlock,
+        WebmElement,
         WebmElement::*
-    }
+    },
+    FromEbml
 };
 
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("dump")
         .setting(AppSettings::Hidden)
-        .about("Dumps WebM parsing events from parsing stdin")
+        .about("Dumps WebM parsing events from parsing the input")
+        .arg(input_arg())
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Print events as text, or as one JSON object per line (with offsets, sizes & decoded values)"))
+        .arg(Arg::with_name("tree")
+            .long("tree")
+            .help("Print every element in the file as an indented tree, with its decoded value"))
 }
 
-pub fn run(_args: &ArgMatches) -> Result<(), WebmetroError> {
+pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    if args.is_present("tree") {
+        if args.occurrences_of("format") > 0 {
+            return Err("--tree can't be combined with --format".into());
+        }
+        return run_tree(args);
+    }
 
-    let mut events = stdin_stream().parse_ebml();
+    let mut events = input_stream(args)?.parse_ebml();
+    let json = args.value_of("format") == Some("json");
+    let mut nesting = Nesting::default();
 
     Runtime::new().unwrap().block_on(async {
-        while let Some(element) = events.next().await? {
+        while let Some((position, element)) = events.next_with_position().await? {
+            if json {
+                let depth = nesting.depth_of(&position, WebmElement::should_unwrap(position.element_id));
+                println!("{}", element_json(&position, depth, element));
+                continue;
+            }
+
             match element {
                 // suppress printing byte arrays
                 Tracks(slice) => println!("Tracks[{}]", slice.len()),
                 SimpleBlock(SimpleBlock {timecode, ..}) => println!("SimpleBlock@{}", timecode),
+                BlockGroup(group) => println!("BlockGroup@{}", group.timecode),
                 other => println!("{:?}", other)
             }
         }
         Ok(())
     })
 }
+
+fn element_json(position: &ElementPosition, depth: usize, element: WebmElement) -> Value {
+    let name = match element {
+        EbmlHead(_) => "EbmlHead",
+        Void => "Void",
+        Segment => "Segment",
+        SeekHead => "SeekHead",
+        Info => "Info",
+        Cues => "Cues",
+        Tracks(_) => "Tracks",
+        Cluster => "Cluster",
+        Timecode(_) => "Timecode",
+        SimpleBlock(_) => "SimpleBlock",
+        BlockGroup(_) => "BlockGroup",
+        Unknown(_) => "Unknown"
+    };
+
+    let mut value = json!({
+        "offset": position.offset,
+        "id": format!("0x{:X}", position.encoded_id()),
+        "name": name,
+        "header_size": position.header_size,
+        "payload_size": position.payload_size,
+        "depth": depth
+    });
+    let object = value.as_object_mut().unwrap();
+
+    match element {
+        EbmlHead(header) => {
+            object.insert("doc_type".into(), json!(header.doc_type()));
+            object.insert("doc_type_version".into(), json!(header.doc_type_version));
+            object.insert("doc_type_read_version".into(), json!(header.doc_type_read_version));
+        },
+        Timecode(timecode) => {
+            object.insert("timecode".into(), json!(timecode));
+        },
+        SimpleBlock(block) => {
+            object.insert("track".into(), json!(block.track));
+            object.insert("timecode".into(), json!(block.timecode));
+            object.insert("flags".into(), json!(block.flags));
+            object.insert("keyframe".into(), json!(block.is_keyframe()));
+            object.insert("size".into(), json!(block.data.len()));
+        },
+        BlockGroup(group) => {
+            object.insert("track".into(), json!(group.track));
+            object.insert("timecode".into(), json!(group.timecode));
+            object.insert("keyframe".into(), json!(group.keyframe));
+        },
+        Tracks(slice) => {
+            object.insert("tracks".into(), parse_tracks(slice).into_iter().map(|track| json!({
+                "number": track.number,
+                "type": track.track_type,
+                "codec_id": track.codec_id,
+                "default_duration": track.default_duration
+            })).collect());
+        },
+        _ => {}
+    }
+
+    value
+}
+
+fn run_tree(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let mut elements = input_stream(args)?.parse_ebml();
+    let mut nesting = Nesting::default();
+
+    Runtime::new().unwrap().block_on(async {
+        while let Some((position, element)) = elements.next_with_position::<SchemaElement>().await? {
+            let depth = nesting.depth_of(&position, matches!(element, SchemaElement::Master(_)));
+            let indent = "  ".repeat(depth);
+            let schema = lookup(position.element_id);
+            let name = match schema {
+                Some(schema) => schema.name.to_string(),
+                None => format!("Unknown(0x{:X})", position.encoded_id())
+            };
+
+            match element {
+                SchemaElement::Master(_) => match position.payload_size {
+                    Some(size) => println!("{}{} [{} bytes]", indent, name, size),
+                    None => println!("{}{} [unknown size]", indent, name)
+                },
+                SchemaElement::Value(_, bytes) => {
+                    let element_type = schema.map_or(ElementType::Binary, |schema| schema.element_type);
+                    let value = match EbmlValue::decode(element_type, bytes) {
+                        Ok(value) => describe_value(&name, value),
+                        Err(err) => format!("invalid {:?} ({})", element_type, err)
+                    };
+                    println!("{}{}: {}", indent, name, value);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+fn describe_value(name: &str, value: EbmlValue) -> String {
+    match value {
+        EbmlValue::Uint(value) => value.to_string(),
+        EbmlValue::Int(value) => value.to_string(),
+        EbmlValue::Float(value) => value.to_string(),
+        EbmlValue::String(value) | EbmlValue::Utf8(value) => format!("{:?}", value),
+        EbmlValue::Date(nanoseconds) => describe_date(nanoseconds),
+        EbmlValue::Binary(bytes) if name == "SimpleBlock" || name == "Block" => {
+            // both kinds of block share a layout, though only SimpleBlock has a keyframe flag
+            match WebmElement::decode(0x23, &bytes) {
+                Ok(SimpleBlock(block)) => format!(
+                    "track {}, timecode {}{}, {} bytes",
+                    block.track,
+                    block.timecode,
+                    if name == "SimpleBlock" && block.is_keyframe() { ", keyframe" } else { "" },
+                    block.data.len()
+                ),
+                _ => describe_binary(&bytes)
+            }
+        },
+        EbmlValue::Binary(bytes) => describe_binary(&bytes)
+    }
+}
+
+/// the length of a binary value, and its first few bytes in hex
+fn describe_binary(bytes: &[u8]) -> String {
+    let preview: Vec<String> = bytes.iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+    let ellipsis = if bytes.len() > 8 { " ..." } else { "" };
+    format!("<{} bytes: {}{}>", bytes.len(), preview.join(" "), ellipsis)
+}
+
+/// formats an EBML date (nanoseconds since 2001-01-01) as an ISO 8601 UTC time
+fn describe_date(nanoseconds: i64) -> String {
+    const NANOSECONDS_PER_SECOND: i64 = 1_000_000_000;
+    const SECONDS_PER_DAY: i64 = 86_400;
+    // days from 0000-03-01 to 2001-01-01, in the proleptic Gregorian calendar
+    const EPOCH_DAYS: i64 = 730_791;
+
+    let seconds = nanoseconds.div_euclid(NANOSECONDS_PER_SECOND);
+    let fraction = nanoseconds.rem_euclid(NANOSECONDS_PER_SECOND);
+    let time_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+    let days = seconds.div_euclid(SECONDS_PER_DAY) + EPOCH_DAYS;
+
+    // convert a day count to a civil date, counting years from March so leap days come last
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year, month, day,
+        time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60,
+        fraction
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use webmetro::{
+        ebml::{EbmlNode, EbmlValue},
+        stream_parser::ElementPosition,
+        webm::{EbmlHeader, SimpleBlock, WebmElement}
+    };
+
+    use super::{describe_date, element_json};
+
+    fn position(offset: u64, element_id: u64, id_size: usize, header_size: usize, payload_size: Option<u64>) -> ElementPosition {
+        ElementPosition {offset, element_id, id_size, header_size, payload_size}
+    }
+
+    #[test]
+    fn ebml_head_json() {
+        let position = position(0, 0x0A45DFA3, 4, 5, Some(31));
+        assert_eq!(element_json(&position, 0, WebmElement::EbmlHead(EbmlHeader::webm())), json!({
+            "offset": 0,
+            "id": "0x1A45DFA3",
+            "name": "EbmlHead",
+            "header_size": 5,
+            "payload_size": 31,
+            "depth": 0,
+            "doc_type": "webm",
+            "doc_type_version": null,
+            "doc_type_read_version": null
+        }));
+    }
+
+    #[test]
+    fn simple_block_json() {
+        let block = SimpleBlock {track: 2, timecode: -40, flags: 0x80, data: &[0; 5]};
+        let position = position(500, 0x23, 1, 2, Some(9));
+        assert_eq!(element_json(&position, 2, WebmElement::SimpleBlock(block)), json!({
+            "offset": 500,
+            "id": "0xA3",
+            "name": "SimpleBlock",
+            "header_size": 2,
+            "payload_size": 9,
+            "depth": 2,
+            "track": 2,
+            "timecode": -40,
+            "flags": 128,
+            "keyframe": true,
+            "size": 5
+        }));
+    }
+
+    #[test]
+    fn tracks_json() {
+        let mut payload = Vec::new();
+        EbmlNode::master(0x2E, vec![
+            EbmlNode::value(0x57, EbmlValue::Uint(1)),
+            EbmlNode::value(0x03, EbmlValue::Uint(1)),
+            EbmlNode::value(0x06, EbmlValue::String("V_VP9".into())),
+            EbmlNode::value(0x03E383, EbmlValue::Uint(33_333_333))
+        ]).encode(&mut payload).unwrap();
+
+        let position = position(43, 0x0654AE6B, 4, 5, None);
+        assert_eq!(element_json(&position, 1, WebmElement::Tracks(&payload)), json!({
+            "offset": 43,
+            "id": "0x1654AE6B",
+            "name": "Tracks",
+            "header_size": 5,
+            "payload_size": null,
+            "depth": 1,
+            "tracks": [{
+                "number": 1,
+                "type": 1,
+                "codec_id": "V_VP9",
+                "default_duration": 33_333_333
+            }]
+        }));
+    }
+
+    const SECOND: i64 = 1_000_000_000;
+    const DAY: i64 = 86_400 * SECOND;
+
+    #[test]
+    fn dates_from_epoch() {
+        assert_eq!(describe_date(0), "2001-01-01T00:00:00.000000000Z");
+        assert_eq!(describe_date(SECOND + 500_000_001), "2001-01-01T00:00:01.500000001Z");
+        assert_eq!(describe_date(365 * DAY + 3_723 * SECOND), "2002-01-01T01:02:03.000000000Z");
+    }
+
+    #[test]
+    fn dates_before_epoch() {
+        assert_eq!(describe_date(-1), "2000-12-31T23:59:59.999999999Z");
+        assert_eq!(describe_date(-DAY), "2000-12-31T00:00:00.000000000Z");
+        assert_eq!(describe_date(-978_307_200 * SECOND), "1970-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn leap_days() {
+        // 2000 & 2004 are leap years, 2100 isn't
+        assert_eq!(describe_date(-307 * DAY), "2000-02-29T00:00:00.000000000Z");
+        assert_eq!(describe_date(1154 * DAY + 43_200 * SECOND), "2004-02-29T12:00:00.000000000Z");
+        assert_eq!(describe_date(1155 * DAY), "2004-03-01T00:00:00.000000000Z");
+        assert_eq!(describe_date(36_217 * DAY), "2100-02-28T00:00:00.000000000Z");
+        assert_eq!(describe_date(36_218 * DAY), "2100-03-01T00:00:00.000000000Z");
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     io,
-    io::prelude::*
+    io::prelude::*,
+    sync::{Arc, Mutex}
 };
 
 use clap::{App, Arg, ArgMatches, SubCommand};
@@ -8,42 +9,82 @@ use futures3::prelude::*;
 use futures3::future::ready;
 use tokio2::runtime::Runtime;
 
-use super::stdin_stream;
+use super::{
+    cluster_splitting,
+    cluster_splitting_args,
+    input_arg,
+    input_stream,
+    parse_time,
+    repair_summary,
+    throttle,
+    throttle_args,
+    timecode_fixer,
+    timecode_fixer_args
+};
 use webmetro::{
     chunk::{
         Chunk,
         WebmStream
     },
     error::WebmetroError,
-    fixers::{
-        ChunkTimecodeFixer,
-        Throttle,
-    },
+    fixers::ChunkStream,
     stream_parser::StreamEbml
 };
 
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("filter")
-        .about("Copies WebM from stdin to stdout, applying the same cleanup & stripping the relay server does.")
+        .about("Copies WebM from the input (stdin by default) to stdout, applying the same cleanup & stripping the relay server does.")
+        .arg(input_arg())
         .arg(Arg::with_name("throttle")
             .long("throttle")
             .help("Slow down output to \"real time\" speed as determined by the timestamps (useful for streaming static files)"))
+        .arg(Arg::with_name("start")
+            .long("start")
+            .takes_value(true)
+            .help("Begin output at the last keyframe at or before this time (in seconds, or as [HH:]MM:SS[.fff]); timestamps are rebased to start at zero"))
+        .arg(Arg::with_name("end")
+            .long("end")
+            .takes_value(true)
+            .help("Drop all content at or after this time (in seconds, or as [HH:]MM:SS[.fff])"))
+        .args(&cluster_splitting_args())
+        .args(&throttle_args())
+        .args(&timecode_fixer_args())
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
-    let mut timecode_fixer = ChunkTimecodeFixer::new();
+    let splitting = cluster_splitting(args)?;
+    let timecode_fixer = Arc::new(Mutex::new(timecode_fixer(args)?));
+    let fixer = timecode_fixer.clone();
     let mut chunk_stream: Box<dyn TryStream<Item = Result<Chunk, WebmetroError>, Ok = Chunk, Error = WebmetroError> + Send + Unpin> = Box::new(
-        stdin_stream()
+        input_stream(args)?
         .parse_ebml()
         .chunk_webm()
-        .map_ok(move |chunk| timecode_fixer.process(chunk))
+        .with_cluster_splitting(splitting)
+        .map_ok(move |chunk| stream::iter(fixer.lock().unwrap().process(chunk).into_iter().map(Ok)))
+        .try_flatten()
     );
 
+    let start = args.value_of("start").map(parse_time).transpose()?;
+    let end = args.value_of("end").map(parse_time).transpose()?;
+    if let (Some(start), Some(end)) = (start, end) {
+        if end <= start {
+            return Err("End time must be after the start time".into());
+        }
+    }
+    if start.is_some() || end.is_some() {
+        chunk_stream = Box::new(chunk_stream.clip_range(start.unwrap_or(0), end));
+    }
+
     if args.is_present("throttle") {
-        chunk_stream = Box::new(Throttle::new(chunk_stream));
+        chunk_stream = Box::new(throttle(args, chunk_stream)?);
     }
 
-    Runtime::new().unwrap().block_on(chunk_stream.try_for_each(|chunk| {
+    let result = Runtime::new().unwrap().block_on(chunk_stream.try_for_each(|chunk| {
         ready(io::stdout().write_all(chunk.as_ref()).map_err(WebmetroError::from))
-    }))
+    }));
+
+    if let Some(summary) = repair_summary(&timecode_fixer.lock().unwrap()) {
+        eprintln!("{}", summary);
+    }
+    result
 }
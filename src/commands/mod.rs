@@ -1,13 +1,23 @@
 use std::io::Cursor;
+use std::path::PathBuf;
 
 use bytes::Bytes;
-use futures3::TryStreamExt;
-use webmetro::error::WebmetroError;
+use clap::{Arg, ArgMatches};
+use futures3::{future, TryFutureExt, TryStream, TryStreamExt};
+use hyper13::{Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use webmetro::{
+    chunk::ClusterSplitting,
+    error::WebmetroError,
+    fixers::{ChunkTimecodeFixer, Throttle}
+};
 
 pub mod dump;
 pub mod filter;
+pub mod probe;
 pub mod relay;
 pub mod send;
+pub mod validate;
 
 /// An adapter that makes chunks of bytes from stdin available as a Stream;
 /// is NOT actually async, and just uses blocking read. Don't use more than
@@ -22,3 +32,247 @@ pub fn stdin_stream() -> impl futures3::TryStream<
         .map_ok(|bytes| Cursor::new(bytes.freeze()))
         .map_err(WebmetroError::from)
 }
+
+/// A stream of byte chunks from wherever the input comes from
+pub type InputStream = Box<
+    dyn TryStream<Item = Result<Cursor<Bytes>, WebmetroError>, Ok = Cursor<Bytes>, Error = WebmetroError>
+        + Send
+        + Unpin,
+>;
+
+/// The option for choosing where to read WebM from
+pub fn input_arg() -> Arg<'static, 'static> {
+    Arg::with_name("input")
+        .long("input")
+        .short("i")
+        .takes_value(true)
+        .help("Where to read WebM from: a file, an http:// or https:// URL (such as another relay's stream), or - for stdin (the default)")
+}
+
+pub fn input_stream(args: &ArgMatches) -> Result<InputStream, WebmetroError> {
+    Ok(match args.value_of("input").unwrap_or("-") {
+        "-" => Box::new(stdin_stream()),
+        url if url.starts_with("http://") || url.starts_with("https://") => Box::new(url_stream(url)?),
+        path => Box::new(file_stream(PathBuf::from(path)))
+    })
+}
+
+/// Makes the body of a GET request available as a Stream of byte chunks;
+/// the request isn't made until the stream is first polled.
+pub fn url_stream(url: &str) -> Result<impl futures3::TryStream<
+    Item = Result<Cursor<Bytes>, WebmetroError>,
+    Ok = Cursor<Bytes>,
+    Error = WebmetroError,
+> + Sized
+       + Unpin, WebmetroError> {
+    let uri: Uri = url.parse().map_err(|_| WebmetroError::from(format!("Invalid URL: {}", url).as_str()))?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new()?);
+
+    Ok(Box::pin(client.get(uri)
+        .map_err(WebmetroError::from)
+        .and_then(|response| future::ready(if response.status().is_success() {
+            Ok(response.into_body()
+                .map_ok(|chunk| Cursor::new(chunk.into_bytes()))
+                .map_err(WebmetroError::from))
+        } else {
+            Err(WebmetroError::ApplicationError {
+                message: format!("Couldn't read input: {}", response.status())
+            })
+        }))
+        .try_flatten_stream()))
+}
+
+/// Makes the contents of a file available as a Stream of byte chunks;
+/// the file isn't opened until the stream is first polled.
+pub fn file_stream(path: PathBuf) -> impl futures3::TryStream<
+    Item = Result<Cursor<Bytes>, WebmetroError>,
+    Ok = Cursor<Bytes>,
+    Error = WebmetroError,
+> + Sized
+       + Unpin {
+    Box::pin(tokio2::fs::File::open(path)
+        .map_ok(|file| {
+            tokio2::codec::FramedRead::new(file, tokio2::codec::BytesCodec::new())
+                .map_ok(|bytes| Cursor::new(bytes.freeze()))
+                .map_err(WebmetroError::from)
+        })
+        .map_err(WebmetroError::from)
+        .try_flatten_stream())
+}
+
+/// Parses a time given either as seconds ("90", "12.5") or as a
+/// "[HH:]MM:SS[.fff]" timecode, returning it in milliseconds.
+/// In a timecode, the seconds (and minutes, if hours are given) must be below 60.
+pub fn parse_time(text: &str) -> Result<u64, WebmetroError> {
+    let invalid = || WebmetroError::from(format!("Invalid time: {}", text).as_str());
+
+    let mut parts = text.rsplit(':');
+    let seconds: f64 = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(invalid());
+    }
+
+    let mut total_seconds = seconds;
+    let mut lower_field = seconds;
+    for (index, part) in parts.enumerate() {
+        if lower_field >= 60.0 {
+            return Err(invalid());
+        }
+        let value: u64 = part.parse().map_err(|_| invalid())?;
+        match index {
+            0 => total_seconds += (value * 60) as f64,
+            1 => total_seconds += (value * 60 * 60) as f64,
+            _ => return Err(invalid())
+        }
+        lower_field = value as f64;
+    }
+
+    Ok((total_seconds * 1000.0).round() as u64)
+}
+
+/// Options for starting new clusters partway through the input's clusters
+pub fn cluster_splitting_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("split_keyframes")
+            .long("split-keyframes")
+            .help("Start a new cluster at every video keyframe (reduces latency with encoders that emit long clusters)"),
+        Arg::with_name("max_cluster_duration")
+            .long("max-cluster-duration")
+            .takes_value(true)
+            .help("Start a new cluster once a cluster spans this long (in seconds, or as [HH:]MM:SS[.fff])"),
+        Arg::with_name("max_cluster_size")
+            .long("max-cluster-size")
+            .takes_value(true)
+            .help("Start a new cluster once a cluster's body reaches this many bytes"),
+    ]
+}
+
+pub fn cluster_splitting(args: &ArgMatches) -> Result<ClusterSplitting, WebmetroError> {
+    let max_duration = args.value_of("max_cluster_duration").map(parse_time).transpose()?;
+    let max_size = args.value_of("max_cluster_size").map(|size| size.parse::<usize>()
+        .map_err(|_| WebmetroError::from("Invalid maximum cluster size"))
+    ).transpose()?;
+
+    if max_duration == Some(0) || max_size == Some(0) {
+        return Err("Maximum cluster duration & size must be greater than zero".into());
+    }
+
+    Ok(ClusterSplitting {
+        on_keyframe: args.is_present("split_keyframes"),
+        max_duration,
+        max_size
+    })
+}
+
+/// Options for joining spliced streams' timecodes together
+pub fn timecode_fixer_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("frame_duration")
+            .long("frame-duration")
+            .takes_value(true)
+            .help("Milliseconds to assume the last frame lasts when a new stream is spliced in (by default, taken from the track headers or learned from the stream)"),
+        Arg::with_name("max_gap")
+            .long("max-gap")
+            .takes_value(true)
+            .help("Close any forward jump in timestamps longer than this (in seconds, or as [HH:]MM:SS[.fff]), so players don't stall waiting for the gap"),
+        Arg::with_name("repair_tracks")
+            .long("repair-tracks")
+            .help("Rewrite block timestamps so each track's only increase, and so a spliced-in stream starts at the splice point with its tracks kept in sync (not for codecs with B-frames)"),
+    ]
+}
+
+pub fn timecode_fixer(args: &ArgMatches) -> Result<ChunkTimecodeFixer, WebmetroError> {
+    let mut fixer = ChunkTimecodeFixer::new();
+    if let Some(duration) = args.value_of("frame_duration") {
+        fixer = fixer.with_frame_duration(duration.parse()
+            .map_err(|_| WebmetroError::from("Invalid frame duration"))?);
+    }
+    if let Some(max_gap) = args.value_of("max_gap") {
+        fixer = fixer.with_max_gap(parse_time(max_gap)?);
+    }
+    if args.is_present("repair_tracks") {
+        fixer = fixer.with_track_repair();
+    }
+    Ok(fixer)
+}
+
+/// Describes the repairs a timecode fixer has made, if it made any
+pub fn repair_summary(fixer: &ChunkTimecodeFixer) -> Option<String> {
+    match (fixer.discontinuities_repaired(), fixer.repaired_blocks()) {
+        (0, 0) => None,
+        (discontinuities, 0) => Some(format!("timecode discontinuities repaired: {}", discontinuities)),
+        (discontinuities, blocks) => Some(format!("timecode discontinuities repaired: {}, block timecodes repaired: {}", discontinuities, blocks))
+    }
+}
+
+/// Options adjusting how `--throttle` paces a stream
+pub fn throttle_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("speed")
+            .long("speed")
+            .takes_value(true)
+            .requires("throttle")
+            .help("Play back this many times faster than real time when throttling (e.g. 0.5, 2)"),
+        Arg::with_name("preroll")
+            .long("preroll")
+            .takes_value(true)
+            .requires("throttle")
+            .help("Send this much of the stream immediately when throttling, staying that far ahead so viewers can buffer (in seconds, or as [HH:]MM:SS[.fff])"),
+        Arg::with_name("pace_blocks")
+            .long("pace-blocks")
+            .requires("throttle")
+            .help("Throttle each block individually, instead of sending whole clusters at once"),
+    ]
+}
+
+pub fn throttle<S>(args: &ArgMatches, stream: S) -> Result<Throttle<S>, WebmetroError> {
+    let mut throttle = Throttle::new(stream);
+    if let Some(speed) = args.value_of("speed") {
+        match speed.parse::<f64>() {
+            Ok(speed) if speed > 0.0 && speed.is_finite() => throttle = throttle.with_speed(speed),
+            _ => return Err("Invalid throttle speed".into())
+        }
+    }
+    if let Some(preroll) = args.value_of("preroll") {
+        throttle = throttle.with_preroll(parse_time(preroll)?);
+    }
+    if args.is_present("pace_blocks") {
+        throttle = throttle.pace_blocks();
+    }
+    Ok(throttle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_time;
+
+    #[test]
+    fn parse_seconds() {
+        assert_eq!(parse_time("0").unwrap(), 0);
+        assert_eq!(parse_time("90").unwrap(), 90_000);
+        assert_eq!(parse_time("12.5").unwrap(), 12_500);
+        assert_eq!(parse_time("0.0015").unwrap(), 2);
+    }
+
+    #[test]
+    fn parse_timecodes() {
+        assert_eq!(parse_time("01:30").unwrap(), 90_000);
+        assert_eq!(parse_time("1:02:03").unwrap(), 3_723_000);
+        assert_eq!(parse_time("01:02:03.250").unwrap(), 3_723_250);
+        assert_eq!(parse_time("00:59.999").unwrap(), 59_999);
+        // minutes can run past an hour when no hours are given
+        assert_eq!(parse_time("90:00").unwrap(), 5_400_000);
+        assert_eq!(parse_time("100:00:00").unwrap(), 360_000_000);
+    }
+
+    #[test]
+    fn reject_bad_times() {
+        for text in &[
+            "", "-1", "-0.5", "1:-5", "-1:05", "abc", "inf", "NaN",
+            ":30", "1:", "1::30", "1:02:03:04",
+            "1:60", "1:75.5", "1:60:00", "1.5:00"
+        ] {
+            assert!(parse_time(text).is_err(), "accepted {:?}", text);
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::BTreeMap;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+use tokio2::runtime::Runtime;
+
+use super::{input_arg, input_stream, relay::BUFFER_LIMIT};
+use webmetro::{
+    ebml::EbmlError,
+    error::WebmetroError,
+    schema::Nesting,
+    stream_parser::{ElementPosition, StreamEbml},
+    webm::{parse_tracks, Info, TrackEntry, WebmElement},
+    FromEbml
+};
+
+/// forward jumps in a track's timestamps larger than this (in milliseconds) are reported
+const GAP_WARNING: i64 = 1000;
+const INFO_ID: u64 = 0x0549A966;
+/// nanoseconds per timecode unit, when the Info element doesn't say
+const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("probe")
+        .about("Reads a WebM input to the end, and reports on its tracks, clusters & timestamps")
+        .arg(input_arg())
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Print the report as text, or as a JSON object"))
+}
+
+/// The elements probe looks at: `WebmElement`s, plus the contents of the Info element
+enum ProbeElement<'b> {
+    Info(Info),
+    Webm(WebmElement<'b>)
+}
+
+impl<'b> FromEbml<'b> for ProbeElement<'b> {
+    fn should_unwrap(element_id: u64) -> bool {
+        WebmElement::should_unwrap(element_id)
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<ProbeElement<'b>, EbmlError> {
+        match element_id {
+            INFO_ID => Info::decode(element_id, bytes).map(ProbeElement::Info),
+            _ => WebmElement::decode(element_id, bytes).map(ProbeElement::Webm)
+        }
+    }
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let mut elements = input_stream(args)?.parse_ebml();
+    let mut probe = Probe::default();
+
+    Runtime::new().unwrap().block_on(async {
+        while let Some((position, element)) = elements.next_with_position().await? {
+            probe.process(&position, element);
+        }
+        Result::<_, WebmetroError>::Ok(())
+    })?;
+
+    if args.value_of("format") == Some("json") {
+        println!("{}", probe.to_json());
+    } else {
+        probe.print();
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct TrackStats {
+    entry: TrackEntry,
+    blocks: u64,
+    bytes: u64,
+    first_timestamp: Option<i64>,
+    last_timestamp: i64,
+    keyframe_timestamps: Vec<i64>
+}
+
+impl TrackStats {
+    /// milliseconds per frame, if the track declares it
+    fn frame_duration(&self) -> i64 {
+        self.entry.default_duration.map_or(0, |nanoseconds| (nanoseconds / 1_000_000) as i64)
+    }
+
+    /// the span of the track's blocks, in milliseconds
+    fn duration(&self) -> Option<i64> {
+        self.first_timestamp.map(|first| self.last_timestamp + self.frame_duration() - first)
+    }
+
+    /// average bits per second
+    fn bitrate(&self) -> Option<u64> {
+        match self.duration() {
+            Some(duration) if duration > 0 => Some(self.bytes * 8 * 1000 / duration as u64),
+            _ => None
+        }
+    }
+
+    /// milliseconds between keyframes, leaving out jumps backwards (which are reported as anomalies)
+    fn keyframe_intervals(&self) -> Vec<i64> {
+        self.keyframe_timestamps.windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .filter(|&interval| interval >= 0)
+            .collect()
+    }
+}
+
+struct ClusterStats {
+    offset: u64,
+    /// in timecode units
+    timecode: Option<i64>,
+    /// where the cluster's content ends, so far
+    end: u64,
+    /// when the cluster's last block ends, in milliseconds
+    content_end: Option<i64>
+}
+
+struct Anomaly {
+    offset: u64,
+    message: String
+}
+
+/// Gathers statistics about a stream, one element at a time.
+/// Timestamps are converted to milliseconds using the Info element's TimecodeScale,
+/// which comes before any clusters.
+#[derive(Default)]
+struct Probe {
+    nesting: Nesting,
+    timecode_scale: Option<u64>,
+    tracks: BTreeMap<u64, TrackStats>,
+    clusters: Vec<ClusterStats>,
+    anomalies: Vec<Anomaly>
+}
+
+impl Probe {
+    fn process(&mut self, position: &ElementPosition, element: ProbeElement) {
+        let depth = self.nesting.depth_of(position, WebmElement::should_unwrap(position.element_id));
+        let element_end = position.offset + position.header_size as u64 + position.payload_size.unwrap_or(0);
+
+        let element = match element {
+            ProbeElement::Info(info) => {
+                self.timecode_scale = Some(info.timecode_scale);
+                WebmElement::Info
+            },
+            ProbeElement::Webm(element) => element
+        };
+        match element {
+            WebmElement::Tracks(tracks) => for entry in parse_tracks(tracks) {
+                let number = entry.number;
+                self.tracks.entry(number).or_default().entry = entry;
+            },
+            WebmElement::Cluster => {
+                self.clusters.push(ClusterStats {
+                    offset: position.offset,
+                    timecode: None,
+                    end: element_end,
+                    content_end: None
+                });
+            },
+            WebmElement::Timecode(timecode) => {
+                let timecode = timecode as i64;
+                let previous = self.clusters.iter().rev().skip(1).find_map(|cluster| cluster.timecode);
+                if let Some(previous) = previous.filter(|&previous| timecode < previous) {
+                    let message = format!(
+                        "cluster timecode went backwards, from {}ms to {}ms",
+                        self.milliseconds(previous), self.milliseconds(timecode)
+                    );
+                    self.anomaly(position, message);
+                }
+                if let Some(cluster) = self.clusters.last_mut() {
+                    cluster.timecode = Some(timecode);
+                }
+            },
+            WebmElement::SimpleBlock(block) => self.process_block(position, block.track, block.timecode, block.is_keyframe(), block.data.len()),
+            WebmElement::BlockGroup(group) => self.process_block(position, group.track, group.timecode, group.keyframe, group.data().len()),
+            _ => {}
+        }
+
+        // the nesting says which elements are inside the current cluster, even if its size is unknown
+        if depth == 2 {
+            if let Some(cluster) = self.clusters.last_mut() {
+                cluster.end = cluster.end.max(element_end);
+            }
+        }
+    }
+
+    fn process_block(&mut self, position: &ElementPosition, track_number: u64, timecode: i16, keyframe: bool, size: usize) {
+        let cluster_timecode = self.clusters.last().and_then(|cluster| cluster.timecode);
+        let timestamp = match cluster_timecode {
+            Some(cluster_timecode) => self.milliseconds(cluster_timecode + timecode as i64),
+            None => {
+                self.anomaly(position, format!("block on track {} before any cluster timecode", track_number));
+                return;
+            }
+        };
+
+        let track = self.tracks.entry(track_number).or_default();
+        let mut anomalies = Vec::new();
+        if track.entry.number != track_number {
+            anomalies.push(format!("block on track {}, which isn't described in the Tracks element", track_number));
+            track.entry.number = track_number;
+        }
+        if timestamp < 0 {
+            anomalies.push(format!("track {} has a negative timestamp ({}ms)", track_number, timestamp));
+        }
+        if track.first_timestamp.is_some() {
+            if timestamp < track.last_timestamp {
+                anomalies.push(format!(
+                    "track {} timestamp went backwards, from {}ms to {}ms",
+                    track_number, track.last_timestamp, timestamp
+                ));
+            } else if timestamp - track.last_timestamp > GAP_WARNING {
+                anomalies.push(format!(
+                    "track {} has a {}ms gap, from {}ms to {}ms",
+                    track_number, timestamp - track.last_timestamp, track.last_timestamp, timestamp
+                ));
+            }
+        }
+
+        track.blocks += 1;
+        track.bytes += size as u64;
+        track.first_timestamp = Some(track.first_timestamp.map_or(timestamp, |first| first.min(timestamp)));
+        track.last_timestamp = timestamp;
+        if keyframe {
+            track.keyframe_timestamps.push(timestamp);
+        }
+
+        let block_end = timestamp + track.frame_duration();
+        if let Some(cluster) = self.clusters.last_mut() {
+            cluster.content_end = Some(cluster.content_end.map_or(block_end, |end| end.max(block_end)));
+        }
+
+        for message in anomalies {
+            self.anomaly(position, message);
+        }
+    }
+
+    /// converts a timestamp in timecode units to milliseconds
+    fn milliseconds(&self, timecode: i64) -> i64 {
+        let scale = self.timecode_scale.unwrap_or(DEFAULT_TIMECODE_SCALE);
+        (timecode as i128 * scale as i128 / 1_000_000) as i64
+    }
+
+    fn anomaly(&mut self, position: &ElementPosition, message: String) {
+        self.anomalies.push(Anomaly {offset: position.offset, message});
+    }
+
+    /// the span of all the tracks together, in milliseconds
+    fn duration(&self) -> Option<i64> {
+        let start = self.tracks.values().filter_map(|track| track.first_timestamp).min()?;
+        let end = self.tracks.values()
+            .filter(|track| track.first_timestamp.is_some())
+            .map(|track| track.last_timestamp + track.frame_duration())
+            .max()?;
+        Some(end - start)
+    }
+
+    /// each cluster's duration in milliseconds: up to the next cluster, or the end of its last block
+    /// (leaving out jumps backwards, which are reported as anomalies)
+    fn cluster_durations(&self) -> Vec<i64> {
+        let timed: Vec<&ClusterStats> = self.clusters.iter().filter(|cluster| cluster.timecode.is_some()).collect();
+        timed.iter().enumerate().filter_map(|(index, cluster)| {
+            let start = self.milliseconds(cluster.timecode?);
+            let end = match timed.get(index + 1) {
+                Some(next) => self.milliseconds(next.timecode?),
+                None => cluster.content_end?
+            };
+            Some(end - start)
+        }).filter(|&duration| duration >= 0).collect()
+    }
+
+    fn largest_cluster(&self) -> Option<&ClusterStats> {
+        self.clusters.iter().max_by_key(|cluster| cluster.end - cluster.offset)
+    }
+
+    fn print(&self) {
+        match self.duration() {
+            Some(duration) => println!("Duration: {}", format_milliseconds(duration)),
+            None => println!("Duration: unknown (no blocks)")
+        }
+        if let Some(scale) = self.timecode_scale.filter(|&scale| scale != DEFAULT_TIMECODE_SCALE) {
+            println!("Timecode scale: {}ns", scale);
+        }
+
+        for track in self.tracks.values() {
+            let entry = &track.entry;
+            let mut description = vec![format!("{} {}", track_kind(entry), entry.codec_id)];
+            if let (Some(width), Some(height)) = (entry.pixel_width(), entry.pixel_height()) {
+                description.push(format!("{}x{}", width, height));
+            }
+            if let Some(frequency) = entry.sampling_frequency() {
+                description.push(format!("{}Hz", frequency));
+            }
+            if let Some(channels) = entry.channels() {
+                description.push(format!("{} channels", channels));
+            }
+            description.push(format!("{} blocks", track.blocks));
+            description.push(format!("{} bytes", track.bytes));
+            if let Some(bitrate) = track.bitrate() {
+                description.push(format!("{} kbit/s", bitrate / 1000));
+            }
+            println!("Track {}: {}", entry.number, description.join(", "));
+
+            if entry.is_video() {
+                match Summary::of(track.keyframe_intervals()) {
+                    Some(intervals) => println!("  keyframe interval: {}", intervals),
+                    None => println!("  keyframes: {}", track.keyframe_timestamps.len())
+                }
+            }
+        }
+
+        println!("Clusters: {}", self.clusters.len());
+        if let Some(durations) = Summary::of(self.cluster_durations()) {
+            println!("  duration: {}", durations);
+        }
+        if let Some(cluster) = self.largest_cluster() {
+            let size = cluster.end - cluster.offset;
+            println!("  largest: {} bytes, at offset {}", size, cluster.offset);
+            if size > BUFFER_LIMIT as u64 {
+                println!("  warning: larger than the relay's {} byte buffer limit", BUFFER_LIMIT);
+            }
+        }
+
+        if self.anomalies.is_empty() {
+            println!("Timestamp anomalies: none");
+        } else {
+            println!("Timestamp anomalies: {}", self.anomalies.len());
+            for anomaly in &self.anomalies {
+                println!("  at offset {}: {}", anomaly.offset, anomaly.message);
+            }
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let tracks: Vec<Value> = self.tracks.values().map(|track| {
+            let entry = &track.entry;
+            json!({
+                "number": entry.number,
+                "type": track_kind(entry),
+                "codec_id": entry.codec_id,
+                "pixel_width": entry.pixel_width(),
+                "pixel_height": entry.pixel_height(),
+                "sampling_frequency": entry.sampling_frequency(),
+                "channels": entry.channels(),
+                "default_duration": entry.default_duration,
+                "blocks": track.blocks,
+                "bytes": track.bytes,
+                "bitrate": track.bitrate(),
+                "keyframes": track.keyframe_timestamps.len(),
+                "keyframe_interval": Summary::of(track.keyframe_intervals()).as_ref().map(Summary::to_json)
+            })
+        }).collect();
+
+        let largest = self.largest_cluster();
+        json!({
+            "duration": self.duration(),
+            "timecode_scale": self.timecode_scale.unwrap_or(DEFAULT_TIMECODE_SCALE),
+            "tracks": tracks,
+            "clusters": {
+                "count": self.clusters.len(),
+                "duration": Summary::of(self.cluster_durations()).as_ref().map(Summary::to_json),
+                "largest": largest.map(|cluster| json!({
+                    "offset": cluster.offset,
+                    "size": cluster.end - cluster.offset
+                })),
+                "exceeds_relay_buffer": largest.is_some_and(|cluster| cluster.end - cluster.offset > BUFFER_LIMIT as u64)
+            },
+            "anomalies": self.anomalies.iter().map(|anomaly| json!({
+                "offset": anomaly.offset,
+                "message": anomaly.message
+            })).collect::<Vec<Value>>()
+        })
+    }
+}
+
+fn track_kind(entry: &TrackEntry) -> &'static str {
+    if entry.is_video() {
+        "video"
+    } else if entry.is_audio() {
+        "audio"
+    } else {
+        "other"
+    }
+}
+
+/// The spread of a set of durations, in milliseconds
+struct Summary {
+    min: i64,
+    median: i64,
+    mean: i64,
+    max: i64
+}
+
+impl Summary {
+    fn of(mut values: Vec<i64>) -> Option<Summary> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        Some(Summary {
+            min: values[0],
+            median: values[values.len() / 2],
+            mean: values.iter().sum::<i64>() / values.len() as i64,
+            max: values[values.len() - 1]
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "min": self.min,
+            "median": self.median,
+            "mean": self.mean,
+            "max": self.max
+        })
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "min {}ms, median {}ms, mean {}ms, max {}ms", self.min, self.median, self.mean, self.max)
+    }
+}
+
+fn format_milliseconds(milliseconds: i64) -> String {
+    format!("{}.{:03}s", milliseconds / 1000, (milliseconds % 1000).abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+    use futures3::{future::FutureExt, stream};
+    use webmetro::{
+        ebml::{EbmlEncode, EbmlNode, EbmlValue},
+        webm::{encode_webm_element, EbmlHeader, SimpleBlock}
+    };
+
+    use super::*;
+
+    /// the headers of a stream with a 25fps video track 1 & an audio track 2,
+    /// and an Info element if a TimecodeScale is given
+    fn headers(timecode_scale: Option<u64>) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut output).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut output).unwrap();
+        if let Some(timecode_scale) = timecode_scale {
+            Info {timecode_scale, ..Info::default()}.encode(&mut output).unwrap();
+        }
+        EbmlNode::master(0x0654AE6B, vec![
+            EbmlNode::master(0x2E, vec![
+                EbmlNode::value(0x57, EbmlValue::Uint(1)),
+                EbmlNode::value(0x03, EbmlValue::Uint(1)),
+                EbmlNode::value(0x06, EbmlValue::String("V_VP9".into())),
+                EbmlNode::value(0x03E383, EbmlValue::Uint(40_000_000))
+            ]),
+            EbmlNode::master(0x2E, vec![
+                EbmlNode::value(0x57, EbmlValue::Uint(2)),
+                EbmlNode::value(0x03, EbmlValue::Uint(2)),
+                EbmlNode::value(0x06, EbmlValue::String("A_OPUS".into()))
+            ])
+        ]).encode(&mut output).unwrap();
+        output.into_inner()
+    }
+
+    fn block(track: u64, timecode: i16, keyframe: bool, size: usize) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        let data = vec![0; size];
+        let flags = if keyframe { 0x80 } else { 0 };
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {track, timecode, flags, data: &data}), &mut output).unwrap();
+        output.into_inner()
+    }
+
+    /// a BlockGroup with no ReferenceBlock, so a keyframe
+    fn block_group(track: u64, timecode: i16, size: usize) -> Vec<u8> {
+        let mut block = vec![0x80 | track as u8];
+        block.extend_from_slice(&timecode.to_be_bytes());
+        block.push(0);
+        block.extend(vec![0; size]);
+
+        let mut output = Vec::new();
+        EbmlNode::master(0x20, vec![
+            EbmlNode::value(0x21, EbmlValue::Binary(block.into()))
+        ]).encode(&mut output).unwrap();
+        output
+    }
+
+    /// an unknown-size Cluster, with a Timecode if given
+    fn cluster(timecode: Option<u64>, blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::Cluster, &mut output).unwrap();
+        if let Some(timecode) = timecode {
+            encode_webm_element(WebmElement::Timecode(timecode), &mut output).unwrap();
+        }
+        let mut output = output.into_inner();
+        for block in blocks {
+            output.extend(block);
+        }
+        output
+    }
+
+    fn probe(bytes: Vec<u8>) -> Probe {
+        let mut elements = stream::iter(vec![Ok::<_, WebmetroError>(Cursor::new(Bytes::from(bytes)))]).parse_ebml();
+        let mut probe = Probe::default();
+        async {
+            while let Some((position, element)) = elements.next_with_position().await? {
+                probe.process(&position, element);
+            }
+            Result::<_, WebmetroError>::Ok(())
+        }.now_or_never().expect("Test tried to block on I/O").unwrap();
+        probe
+    }
+
+    fn anomalies(probe: &Probe) -> Vec<&str> {
+        probe.anomalies.iter().map(|anomaly| anomaly.message.as_str()).collect()
+    }
+
+    fn summary(values: Vec<i64>) -> Option<(i64, i64, i64, i64)> {
+        Summary::of(values).map(|summary| (summary.min, summary.median, summary.mean, summary.max))
+    }
+
+    #[test]
+    fn stream_statistics() {
+        let clusters = [
+            cluster(Some(0), &[block(1, 0, true, 100), block(2, 0, true, 50), block(1, 40, false, 100)]),
+            cluster(Some(1000), &[block(1, 0, true, 100), block(2, 0, true, 50), block(1, 40, false, 100)]),
+            // after a gap, and with the video keyframe in a BlockGroup
+            cluster(Some(3000), &[block_group(1, 0, 100), block(2, 0, true, 50)])
+        ];
+        let mut bytes = headers(None);
+        let largest_offset = (bytes.len() + clusters[0].len()) as u64;
+        bytes.extend(clusters.concat());
+        let probe = probe(bytes);
+
+        // video runs to the end of its last 40ms frame
+        assert_eq!(probe.duration(), Some(3040));
+
+        let video = &probe.tracks[&1];
+        assert_eq!((video.blocks, video.bytes), (5, 500));
+        assert_eq!(video.bitrate(), Some(500 * 8 * 1000 / 3040));
+        assert_eq!(video.keyframe_intervals(), vec![1000, 2000]);
+        assert_eq!(summary(video.keyframe_intervals()), Some((1000, 2000, 1500, 2000)));
+
+        let audio = &probe.tracks[&2];
+        assert_eq!((audio.blocks, audio.bytes), (3, 150));
+        assert_eq!(audio.bitrate(), Some(400));
+
+        // the last cluster lasts until its last frame ends
+        assert_eq!(probe.cluster_durations(), vec![1000, 2000, 40]);
+        assert_eq!(summary(probe.cluster_durations()), Some((40, 1000, 1013, 2000)));
+
+        let largest = probe.largest_cluster().unwrap();
+        assert_eq!(largest.offset, largest_offset);
+        assert_eq!(largest.end - largest.offset, clusters[1].len() as u64);
+
+        assert_eq!(anomalies(&probe), vec![
+            "track 1 has a 1960ms gap, from 1040ms to 3000ms",
+            "track 2 has a 2000ms gap, from 1000ms to 3000ms"
+        ]);
+    }
+
+    #[test]
+    fn timestamp_anomalies() {
+        let mut bytes = headers(None);
+        bytes.extend(cluster(None, &[block(1, 0, true, 10)]));
+        bytes.extend(cluster(Some(1000), &[block(1, 0, true, 10), block(3, 0, true, 10)]));
+        bytes.extend(cluster(Some(500), &[block(1, 0, true, 10), block(1, -600, false, 10)]));
+        let probe = probe(bytes);
+
+        assert_eq!(anomalies(&probe), vec![
+            "block on track 1 before any cluster timecode",
+            "block on track 3, which isn't described in the Tracks element",
+            "cluster timecode went backwards, from 1000ms to 500ms",
+            "track 1 timestamp went backwards, from 1000ms to 500ms",
+            "track 1 has a negative timestamp (-100ms)",
+            "track 1 timestamp went backwards, from 500ms to -100ms"
+        ]);
+        // jumps backwards are left out of the intervals
+        assert_eq!(probe.tracks[&1].keyframe_intervals(), Vec::<i64>::new());
+        // the last cluster still lasts until its latest frame ends
+        assert_eq!(probe.cluster_durations(), vec![40]);
+    }
+
+    #[test]
+    fn timecode_scale() {
+        // half-millisecond timecodes
+        let mut bytes = headers(Some(500_000));
+        bytes.extend(cluster(Some(0), &[block(1, 0, true, 100), block(1, 80, false, 100)]));
+        bytes.extend(cluster(Some(2000), &[block(1, 0, true, 100)]));
+        let probe = probe(bytes);
+
+        assert_eq!(probe.duration(), Some(1040));
+        assert_eq!(probe.tracks[&1].keyframe_intervals(), vec![1000]);
+        assert_eq!(probe.cluster_durations(), vec![1000, 40]);
+        assert_eq!(probe.tracks[&1].bitrate(), Some(300 * 8 * 1000 / 1040));
+        assert_eq!(probe.to_json()["timecode_scale"], 500_000);
+        assert_eq!(anomalies(&probe), Vec::<&str>::new());
+    }
+}
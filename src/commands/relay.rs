@@ -1,4 +1,8 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
 use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
 use std::sync::{
     Arc,
     Mutex,
@@ -9,9 +13,10 @@ use bytes::{Bytes, Buf};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::{
     Future,
+    Poll,
     Stream,
-    Sink,
-    stream::empty
+    future::{self, Either},
+    stream::{self, empty}
 };
 use futures3::{
     compat::{
@@ -25,54 +30,96 @@ use futures3::{
 use hyper::{
     Body,
     Response,
+    StatusCode,
     header::{
         CACHE_CONTROL,
         CONTENT_TYPE
     }
 };
+use percent_encoding::percent_decode_str;
 use warp::{
     self,
     Filter,
-    path
+    Rejection,
+    path,
+    path::Tail
 };
 use weak_table::{
     WeakValueHashMap
 };
+use super::{
+    cluster_splitting,
+    cluster_splitting_args,
+    parse_time,
+    repair_summary,
+    timecode_fixer,
+    timecode_fixer_args
+};
 use webmetro::{
     channel::{
         Channel,
+        DEFAULT_LISTENER_BUFFER,
         Handle,
         Listener,
         Transmitter
     },
-    chunk::WebmStream,
+    chunk::{
+        Chunk,
+        ClusterSplitting,
+        WebmStream
+    },
     error::WebmetroError,
     fixers::{
         ChunkStream,
         ChunkTimecodeFixer,
     },
-    stream_parser::StreamEbml
+    stream_parser::StreamEbml,
+    webm_file::{play_pieces, WebmFile}
 };
 
-const BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+pub const BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+/// listener buffer size, in chunks, when every block is sent as its own chunk
+const PARTIAL_CLUSTER_LISTENER_BUFFER: usize = 512;
+
+/// A listener's timecode fixer, which logs the repairs it made once the listener disconnects
+struct ListenerFixer {
+    name: String,
+    fixer: ChunkTimecodeFixer
+}
+
+impl Drop for ListenerFixer {
+    fn drop(&mut self) {
+        if let Some(summary) = repair_summary(&self.fixer) {
+            info!("Listener Disconnected From Channel {}, {}", self.name, summary);
+        }
+    }
+}
 
-fn get_stream(channel: Handle) -> impl Stream<Item = Bytes, Error = WebmetroError> {
-    let mut timecode_fixer = ChunkTimecodeFixer::new();
-    Compat::new(Listener::new(channel).map(|c| Ok(c))
-    .map_ok(move |chunk| timecode_fixer.process(chunk))
+fn get_stream(channel: Handle, name: String, timecode_fixer: ChunkTimecodeFixer) -> impl Stream<Item = Bytes, Error = WebmetroError> {
+    let mut listener_fixer = ListenerFixer {name, fixer: timecode_fixer};
+    Compat::new(Listener::new(channel).map(|c| Ok::<_, Never>(c))
+    .map_ok(move |chunk| futures3::stream::iter(listener_fixer.fixer.process(chunk).into_iter().map(Ok)))
+    .try_flatten()
     .find_starting_point()
     .map_ok(|webm_chunk| webm_chunk.into_bytes())
     .map_err(|err: Never| match err {}))
 }
 
-fn post_stream(channel: Handle, stream: impl Stream<Item = impl Buf, Error = warp::Error>) -> impl Stream<Item = Bytes, Error = WebmetroError> {
+fn post_stream(channel: Handle, splitting: ClusterSplitting, partial_clusters: bool, doc_types: Vec<String>, stream: impl Stream<Item = impl Buf, Error = warp::Error>) -> impl Stream<Item = Bytes, Error = WebmetroError> {
     let source = Compat01As03::new(stream
         .map_err(WebmetroError::from))
         .parse_ebml().with_soft_limit(BUFFER_LIMIT)
-        .chunk_webm().with_soft_limit(BUFFER_LIMIT);
+        .chunk_webm().with_soft_limit(BUFFER_LIMIT)
+        .with_accepted_doc_types(doc_types)
+        .with_cluster_splitting(splitting);
+    let source = if partial_clusters {
+        source.with_partial_clusters()
+    } else {
+        source
+    };
     let sink = CompatSink::new(Transmitter::new(channel));
 
-    Compat::new(source).forward(sink.sink_map_err(|err| -> WebmetroError {match err {}}))
+    Compat::new(source).forward(sink)
     .into_stream()
     .map(|_| empty())
     .map_err(|err| {
@@ -82,26 +129,136 @@ fn post_stream(channel: Handle, stream: impl Stream<Item = impl Buf, Error = war
     .flatten()
 }
 
-fn media_response(body: Body) -> Response<Body> {
+/// the MIME type to serve a channel's stream as, by its DocType
+fn content_type(doc_type: Option<&str>) -> &'static str {
+    match doc_type {
+        Some("matroska") => "video/x-matroska",
+        _ => "video/webm"
+    }
+}
+
+fn media_response(content_type: &'static str, body: Body) -> Response<Body> {
     Response::builder()
-        .header(CONTENT_TYPE, "video/webm")
+        .header(CONTENT_TYPE, content_type)
         .header("X-Accel-Buffering", "no")
         .header(CACHE_CONTROL, "no-cache, no-store")
         .body(body)
         .unwrap()
 }
 
+/// Resolves a path under /vod/ to a file in the VOD directory,
+/// refusing anything that could escape it
+fn vod_path(dir: &Path, tail: &str) -> Option<PathBuf> {
+    let tail = percent_decode_str(tail).decode_utf8().ok()?;
+    let mut path = dir.to_path_buf();
+    for segment in tail.split('/').filter(|segment| !segment.is_empty()) {
+        if segment == ".." || segment == "." || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+/// Runs blocking file I/O on the runtime's blocking pool, so it can't hold up the event loop;
+/// NotReady until the pool has a thread to spare
+fn poll_blocking<T>(work: impl FnOnce() -> T) -> Poll<T, WebmetroError> {
+    tokio_threadpool::blocking(work).map_err(|_| "File I/O must run on the thread pool".into())
+}
+
+/// A VOD file, opened & ready to stream from its starting point
+struct OpenedVod<I> {
+    content_type: &'static str,
+    /// the timecode to clip the stream to
+    start: u64,
+    /// the file's headers & clusters, read as the iterator advances
+    pieces: I
+}
+
+/// Opens a VOD file & finds where to start streaming it from
+fn open_vod(path: &Path, start: u64) -> Result<OpenedVod<impl Iterator<Item = Result<Vec<u8>, WebmetroError>>>, WebmetroError> {
+    let file = WebmFile::open(File::open(path)?)?;
+    let content_type = content_type(Some(file.ebml_header().doc_type()));
+    let (start, pieces) = file.pieces_from(start)?;
+    Ok(OpenedVod { content_type, start, pieces })
+}
+
+/// Streams a file from the VOD directory like a live channel, starting from the given time.
+/// Opening the file & reading each cluster happen on the blocking pool, one step at a time.
+fn vod_stream(dir: &Path, tail: &str, start: &str) -> impl Future<Item = Response<Body>, Error = Rejection> {
+    let path = match vod_path(dir, tail) {
+        Some(path) => path,
+        None => return Either::A(future::err(warp::reject::not_found()))
+    };
+    let start = match parse_time(start) {
+        Ok(start) => start,
+        Err(err) => return Either::A(future::ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(err.to_string()))
+            .unwrap()))
+    };
+
+    Either::B(future::poll_fn({
+        let path = path.clone();
+        move || poll_blocking(|| open_vod(&path, start))
+    }).and_then(|opened| opened).then(move |opened| match opened {
+        Ok(OpenedVod { content_type, start: start_timecode, mut pieces }) => {
+            info!("Streaming {} From {}ms", path.display(), start);
+            let pieces = stream::poll_fn(move || poll_blocking(|| pieces.next())).and_then(|piece| piece);
+            let chunks = play_pieces(start_timecode, Compat01As03::new(pieces));
+            Ok(media_response(content_type, Body::wrap_stream(Compat::new(chunks.map_ok(Chunk::into_bytes)))))
+        },
+        Err(WebmetroError::IoError { ref source }) if source.kind() == ErrorKind::NotFound => Err(warp::reject::not_found()),
+        Err(err) => {
+            warn!("Can't stream {}: {}", path.display(), err);
+            Err(warp::reject::not_found())
+        }
+    }))
+}
+
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("relay")
         .about("Hosts an HTTP-based relay server")
         .arg(Arg::with_name("listen")
             .help("The address:port to listen to")
             .required(true))
+        .arg(Arg::with_name("partial_clusters")
+            .long("partial-clusters")
+            .help("Send each block to viewers as soon as it arrives, instead of waiting for its cluster to finish (reduces latency to about one frame)"))
+        .arg(Arg::with_name("doc_type")
+            .long("doc-type")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .default_value("webm")
+            .help("Accept sources whose EBML header declares this DocType, e.g. \"webm\" or \"matroska\" (may be given more than once); other sources are disconnected, as are sources that don't match the DocType a channel is already carrying"))
+        .arg(Arg::with_name("vod_dir")
+            .long("vod-dir")
+            .takes_value(true)
+            .help("Also serve the files in this directory at /vod/<file>, with HTTP Range support; adding ?t=<time> (in seconds, or as [HH:]MM:SS[.fff]) instead streams the file like a live channel, from the last keyframe at or before that time"))
+        .args(&cluster_splitting_args())
+        .args(&timecode_fixer_args())
 }
 
 pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
     let channel_map = Arc::new(Mutex::new(WeakValueHashMap::<String, Weak<Mutex<Channel>>>::new()));
     let addr_str = args.value_of("listen").ok_or("Listen address wasn't provided")?;
+    let splitting = cluster_splitting(args)?;
+    let partial_clusters = args.is_present("partial_clusters");
+    let doc_types: Vec<String> = args.values_of("doc_type").into_iter().flatten().map(String::from).collect();
+    // until a channel's first source connects, a lone accepted DocType is the only one it can carry
+    let only_doc_type = match doc_types.as_slice() {
+        [doc_type] => Some(doc_type.clone()),
+        _ => None
+    };
+    let head_doc_type = only_doc_type.clone();
+    let timecode_fixer = timecode_fixer(args)?;
+    let vod_dir = args.value_of("vod_dir").map(PathBuf::from);
+    let listener_buffer = if partial_clusters {
+        PARTIAL_CLUSTER_LISTENER_BUFFER
+    } else {
+        DEFAULT_LISTENER_BUFFER
+    };
 
     let addrs = addr_str.to_socket_addrs()?;
     info!("Binding to {:?}", addrs);
@@ -112,31 +269,50 @@ pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
     let channel = path!("live" / String).map(move |name: String| {
         let channel = channel_map.lock().unwrap()
             .entry(name.clone())
-            .or_insert_with(|| Channel::new(name.clone()));
+            .or_insert_with(|| Channel::with_listener_buffer(name.clone(), listener_buffer));
         (channel, name)
     });
 
     let head = channel.clone().and(warp::head())
-        .map(|(_, name)| {
+        .map(move |(channel, name): (Handle, String)| {
             info!("HEAD Request For Channel {}", name);
-            media_response(Body::empty())
+            let content_type = content_type(channel.lock().unwrap().doc_type().or(head_doc_type.as_deref()));
+            media_response(content_type, Body::empty())
         });
 
     let get = channel.clone().and(warp::get2())
-        .map(|(channel, name)| {
+        .map(move |(channel, name): (Handle, String)| {
             info!("Listener Connected On Channel {}", name);
-            media_response(Body::wrap_stream(get_stream(channel)))
+            let content_type = content_type(channel.lock().unwrap().doc_type().or(only_doc_type.as_deref()));
+            media_response(content_type, Body::wrap_stream(get_stream(channel, name, timecode_fixer.clone())))
         });
 
     let post_put = channel.clone().and(warp::post2().or(warp::put2()).unify())
-        .and(warp::body::stream()).map(|(channel, name), stream| {
+        .and(warp::body::stream()).map(move |(channel, name): (Handle, String), stream| {
             info!("Source Connected On Channel {}", name);
-            Response::new(Body::wrap_stream(post_stream(channel, stream)))
+            // once a channel has carried one DocType, the Transmitter refuses sources of any other
+            Response::new(Body::wrap_stream(post_stream(channel, splitting, partial_clusters, doc_types.clone(), stream)))
+        });
+
+    let vod_root = vod_dir.clone().unwrap_or_default();
+    // without a VOD directory, nothing under /vod/ matches
+    let vod = path!("vod").and(warp::any().map(move || vod_dir.clone())
+        .and_then(|dir: Option<PathBuf>| dir.ok_or_else(warp::reject::not_found)));
+
+    let vod_seek = vod.clone().and(warp::get2()).and(warp::path::tail()).and(warp::query::<HashMap<String, String>>())
+        .and_then(|dir: PathBuf, tail: Tail, query: HashMap<String, String>| match query.get("t") {
+            Some(start) => Either::A(vod_stream(&dir, tail.as_str(), start)),
+            None => Either::B(future::err(warp::reject::not_found()))
         });
 
+    let vod_file = vod.and(warp::fs::dir(vod_root))
+        .map(|_, file| file);
+
     let routes = head
         .or(get)
-        .or(post_put);
+        .or(post_put)
+        .or(vod_seek)
+        .or(vod_file);
 
     let mut rt = tokio::runtime::Runtime::new()?;
 
@@ -146,3 +322,48 @@ pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
 
     rt.shutdown_on_idle().wait().map_err(|_| "Shutdown error.".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::vod_path;
+
+    fn resolve(tail: &str) -> Option<PathBuf> {
+        vod_path(Path::new("/srv/vod"), tail)
+    }
+
+    #[test]
+    fn vod_paths_stay_in_dir() {
+        assert_eq!(resolve("show.webm"), Some(PathBuf::from("/srv/vod/show.webm")));
+        assert_eq!(resolve("2019/show%20one.webm"), Some(PathBuf::from("/srv/vod/2019/show one.webm")));
+        // leading, doubled & encoded slashes can't make the path absolute
+        assert_eq!(resolve("%2Fetc//passwd"), Some(PathBuf::from("/srv/vod/etc/passwd")));
+    }
+
+    #[test]
+    fn vod_paths_refuse_parents() {
+        assert_eq!(resolve(".."), None);
+        assert_eq!(resolve("../secret.webm"), None);
+        assert_eq!(resolve("2019/../../secret.webm"), None);
+        assert_eq!(resolve("%2e%2e/secret.webm"), None);
+        assert_eq!(resolve("%2E%2E"), None);
+        assert_eq!(resolve("2019%2F..%2F..%2Fsecret.webm"), None);
+        assert_eq!(resolve("./show.webm"), None);
+    }
+
+    #[test]
+    fn vod_paths_allow_dotted_names() {
+        assert_eq!(resolve("..intro.webm"), Some(PathBuf::from("/srv/vod/..intro.webm")));
+        assert_eq!(resolve(".hidden/show...webm"), Some(PathBuf::from("/srv/vod/.hidden/show...webm")));
+    }
+
+    #[test]
+    fn vod_paths_refuse_backslashes() {
+        assert_eq!(resolve("..\\secret.webm"), None);
+        assert_eq!(resolve("2019\\show.webm"), None);
+        assert_eq!(resolve("..%5Csecret.webm"), None);
+        // not valid UTF-8 once decoded
+        assert_eq!(resolve("%FF.webm"), None);
+    }
+}
@@ -1,71 +1,777 @@
 use clap::{App, Arg, ArgMatches, SubCommand};
-use futures3::prelude::*;
-use hyper13::{client::HttpConnector, Body, Client, Request};
-use std::io::{stdout, Write};
-use tokio2::runtime::Runtime;
+use futures3::{
+    channel::mpsc::{channel as mpsc_channel, Receiver, Sender},
+    future::{self, Either},
+    pin_mut,
+    prelude::*,
+};
+use hyper13::{Body, Method};
+use std::fs::{read, read_to_string, File};
+use std::io::{stdout, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio2::{runtime::Runtime, timer::delay};
 
-use super::stdin_stream;
+use super::{
+    cluster_splitting,
+    cluster_splitting_args,
+    file_stream,
+    input_arg,
+    input_stream,
+    repair_summary,
+    throttle,
+    throttle_args,
+    timecode_fixer,
+    timecode_fixer_args
+};
 use webmetro::{
-    chunk::{Chunk, WebmStream},
+    chunk::{Chunk, ClusterSplitting, WebmStream},
+    client::{ClientOptions, UploadClient},
     error::WebmetroError,
-    fixers::{ChunkTimecodeFixer, Throttle},
+    fixers::ResumeBuffer,
     stream_parser::StreamEbml,
+    webm::{parse_header_tracks, tracks_compatible, TrackEntry},
+    webm_file::WebmFile,
 };
 
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("send")
-        .about("PUTs WebM from stdin to a relay server.")
+        .about("PUTs WebM from the input (stdin by default) or a playlist of files to a relay server.")
+        .arg(input_arg()
+            .conflicts_with("playlist"))
         .arg(Arg::with_name("url")
-            .help("The location to upload to")
-            .required(true))
+            .help("The location to upload to; if several are given, the stream is uploaded to each of them")
+            .required(true)
+            .multiple(true))
+        .arg(Arg::with_name("playlist")
+            .long("playlist")
+            .takes_value(true)
+            .help("Send the WebM files listed in this file (one per line, relative to the playlist) in order, instead of reading stdin"))
+        .arg(Arg::with_name("loop")
+            .long("loop")
+            .requires("playlist")
+            .help("Start the playlist over after its last file, indefinitely"))
+        .arg(Arg::with_name("reconnect")
+            .long("reconnect")
+            .help("If the upload fails, keep reading input and retry with increasing delays, resuming from the header & the next keyframe"))
+        .arg(Arg::with_name("method")
+            .long("method")
+            .takes_value(true)
+            .possible_values(&["put", "post"])
+            .default_value("put")
+            .help("The HTTP method to upload with"))
+        .arg(Arg::with_name("header")
+            .long("header")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Add a header to the upload request, given as \"Name: value\"; may be repeated"))
+        .arg(Arg::with_name("bearer_token")
+            .long("bearer-token")
+            .takes_value(true)
+            .help("Authenticate with an \"Authorization: Bearer\" header"))
+        .arg(Arg::with_name("ca_bundle")
+            .long("ca-bundle")
+            .takes_value(true)
+            .help("Also trust the certificate authorities in this PEM file when uploading over HTTPS"))
+        .arg(Arg::with_name("insecure")
+            .long("insecure")
+            .help("Don't verify the server's HTTPS certificate (for testing only!)"))
         .arg(Arg::with_name("throttle")
             .long("throttle")
             .help("Slow down upload to \"real time\" speed as determined by the timestamps (useful for streaming static files)"))
+        .args(&cluster_splitting_args())
+        .args(&throttle_args())
+        .args(&timecode_fixer_args())
 }
 
+/// Delay before the first reconnection attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// An upload that lasts this long before failing resets the delay
+const STABLE_UPLOAD: Duration = Duration::from_secs(60);
+/// How many chunks each destination can fall behind the input by
+const DESTINATION_BUFFER: usize = 16;
+/// How long a destination with a full buffer can hold up the others,
+/// before it's left to skip ahead to the next keyframe
+const LAG_TIMEOUT: Duration = Duration::from_secs(2);
+/// How much of the stream to hold onto while reconnecting
+const RESUME_BUFFER_LIMIT: usize = 16 * 1024 * 1024;
+
 type BoxedChunkStream = Box<
     dyn TryStream<Item = Result<Chunk, WebmetroError>, Ok = Chunk, Error = WebmetroError>
         + Send
-        + Sync
         + Unpin,
 >;
 
+/// Reads a playlist: one WebM file per line, with relative paths resolved
+/// against the playlist's own directory. Blank lines & "#" comments are skipped.
+fn read_playlist(playlist: &str) -> Result<Vec<PathBuf>, WebmetroError> {
+    let text = read_to_string(playlist)?;
+    let base = Path::new(playlist).parent().unwrap_or_else(|| Path::new(""));
+
+    let paths: Vec<PathBuf> = text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect();
+
+    if paths.is_empty() {
+        return Err("Playlist is empty".into());
+    }
+    Ok(paths)
+}
+
+fn tracks_mismatch(name: &str) -> WebmetroError {
+    WebmetroError::ApplicationError {
+        message: format!("{}: tracks don't match the rest of the playlist", name)
+    }
+}
+
+/// Reads the tracks of every file in a playlist before anything is sent,
+/// returning the first file's tracks if all the others match them.
+fn check_playlist_tracks(paths: &[PathBuf]) -> Result<Vec<TrackEntry>, WebmetroError> {
+    let mut expected_tracks: Option<Vec<TrackEntry>> = None;
+    for path in paths {
+        let name = path.display().to_string();
+        let tracks = File::open(path)
+            .map_err(WebmetroError::from)
+            .and_then(|file| WebmFile::open(BufReader::new(file)))
+            .and_then(|mut file| file.tracks())
+            .map_err(|err| WebmetroError::ApplicationError {
+                message: format!("{}: {}", name, err)
+            })?
+            .map(|tracks| tracks.entries)
+            .unwrap_or_default();
+
+        match expected_tracks {
+            Some(ref expected) if !tracks_compatible(expected, &tracks) => return Err(tracks_mismatch(&name)),
+            Some(_) => {}
+            None => expected_tracks = Some(tracks)
+        }
+    }
+    Ok(expected_tracks.unwrap_or_default())
+}
+
+/// Errors on any headers that don't match the playlist's tracks,
+/// in case a file has changed since it was checked
+fn check_tracks(expected_tracks: &[TrackEntry], name: &str, chunk: Chunk) -> Result<Chunk, WebmetroError> {
+    if let Chunk::Headers {ref bytes} = chunk {
+        if !tracks_compatible(expected_tracks, &parse_header_tracks(bytes)) {
+            return Err(tracks_mismatch(name));
+        }
+    }
+    Ok(chunk)
+}
+
+/// Chunks each file of a playlist in turn, as one stream; errors up front if
+/// any file's tracks don't match the first file's, since they couldn't be spliced together.
+fn playlist_stream(paths: Vec<PathBuf>, looping: bool, splitting: ClusterSplitting) -> Result<BoxedChunkStream, WebmetroError> {
+    let expected_tracks = Arc::new(check_playlist_tracks(&paths)?);
+    let paths: Box<dyn Iterator<Item = PathBuf> + Send> = if looping {
+        Box::new(paths.into_iter().cycle())
+    } else {
+        Box::new(paths.into_iter())
+    };
+
+    Ok(Box::new(stream::iter(paths).map(move |path| {
+        info!("Sending {}", path.display());
+        let name = path.display().to_string();
+        let expected_tracks = expected_tracks.clone();
+
+        file_stream(path)
+            .parse_ebml()
+            .chunk_webm()
+            .with_cluster_splitting(splitting)
+            .map_err({
+                let name = name.clone();
+                move |err| WebmetroError::ApplicationError {
+                    message: format!("{}: {}", name, err)
+                }
+            })
+            .and_then(move |chunk| future::ready(check_tracks(&expected_tracks, &name, chunk)))
+    }).flatten()))
+}
+
+fn upload_client(args: &ArgMatches) -> Result<UploadClient, WebmetroError> {
+    let mut options = ClientOptions::new();
+    if args.value_of("method") == Some("post") {
+        options = options.with_method(Method::POST);
+    }
+    for header in args.values_of("header").into_iter().flatten() {
+        options = options.with_header(header)?;
+    }
+    if let Some(token) = args.value_of("bearer_token") {
+        options = options.with_bearer_token(token)?;
+    }
+    if let Some(ca_bundle) = args.value_of("ca_bundle") {
+        options = options.with_ca_bundle(&read(ca_bundle)?)?;
+    }
+    if args.is_present("insecure") {
+        options = options.insecure();
+    }
+    options.build()
+}
+
 pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
-    let mut timecode_fixer = ChunkTimecodeFixer::new();
-    let mut chunk_stream: BoxedChunkStream = Box::new(
-        stdin_stream()
+    let splitting = cluster_splitting(args)?;
+    let timecode_fixer = Arc::new(Mutex::new(timecode_fixer(args)?));
+    let source: BoxedChunkStream = match args.value_of("playlist") {
+        Some(playlist) => playlist_stream(read_playlist(playlist)?, args.is_present("loop"), splitting)?,
+        None => Box::new(input_stream(args)?
             .parse_ebml()
             .chunk_webm()
-            .map_ok(move |chunk| timecode_fixer.process(chunk)),
+            .with_cluster_splitting(splitting))
+    };
+    let fixer = timecode_fixer.clone();
+    let mut chunk_stream: BoxedChunkStream = Box::new(
+        source.map_ok(move |chunk| stream::iter(fixer.lock().unwrap().process(chunk).into_iter().map(Ok)))
+            .try_flatten(),
     );
 
-    let url_str = match args.value_of("url") {
-        Some(url) => String::from(url),
-        _ => return Err("Listen address wasn't provided".into()),
+    let urls: Vec<String> = match args.values_of("url") {
+        Some(urls) => urls.map(String::from).collect(),
+        _ => return Err("At least one destination URL is required".into()),
     };
 
     if args.is_present("throttle") {
-        chunk_stream = Box::new(Throttle::new(chunk_stream));
+        chunk_stream = Box::new(throttle(args, chunk_stream)?);
     }
 
-    let chunk_stream = chunk_stream
-        .map_ok(|webm_chunk| webm_chunk.into_bytes())
-        .map_err(|err| {
-            warn!("{}", &err);
-            err
+    let reconnect = args.is_present("reconnect");
+    let client = upload_client(args)?;
+
+    let mut destinations: Vec<Destination> = urls.into_iter().map(Destination::new).collect();
+    let (lanes, receivers): (Vec<_>, Vec<_>) = destinations.iter()
+        .map(|destination| {
+            let (sender, receiver) = mpsc_channel(DESTINATION_BUFFER);
+            (Lane::new(destination.url.clone(), sender), receiver)
+        })
+        .unzip();
+
+    let (distributed, results) = Runtime::new().unwrap().block_on(future::join(
+        distribute(chunk_stream, lanes),
+        future::join_all(destinations.iter_mut().zip(receivers).map(|(destination, receiver)| {
+            send_to(&client, destination, receiver, reconnect)
+        }))
+    ));
+    if let Some(summary) = repair_summary(&timecode_fixer.lock().unwrap()) {
+        eprintln!("{}", summary);
+    }
+    distributed?;
+
+    if destinations.len() == 1 {
+        return results.into_iter().next().unwrap();
+    }
+
+    let mut failures = 0;
+    for (destination, result) in destinations.iter().zip(results) {
+        match result {
+            Ok(()) => eprintln!("{}: sent {} bytes, reconnected {} times",
+                destination.url, destination.bytes_sent, destination.reconnects),
+            Err(err) => {
+                failures += 1;
+                eprintln!("{}: failed after sending {} bytes, reconnected {} times: {}",
+                    destination.url, destination.bytes_sent, destination.reconnects, err);
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(WebmetroError::ApplicationError {
+            message: format!("{} of {} uploads failed", failures, destinations.len())
         });
+    }
+    Ok(())
+}
+
+/// The channel feeding one destination's upload
+struct Lane {
+    url: String,
+    sender: Sender<Chunk>,
+    /// whether chunks were dropped because the channel was full,
+    /// so nothing more can be sent until the next keyframe
+    lagging: bool,
+    /// the latest headers dropped while lagging, to send before resuming
+    missed_headers: Option<Chunk>
+}
+
+impl Lane {
+    fn new(url: String, sender: Sender<Chunk>) -> Lane {
+        Lane {
+            url,
+            sender,
+            lagging: false,
+            missed_headers: None
+        }
+    }
+
+    /// whether this lane would take the chunk, if it had room
+    fn wants(&self, chunk: &Chunk) -> bool {
+        match chunk {
+            _ if !self.lagging => true,
+            Chunk::ClusterHead(cluster_head) => cluster_head.keyframe,
+            _ => false
+        }
+    }
 
-    let request_payload = Body::wrap_stream(chunk_stream);
+    /// Queues a chunk without waiting, falling behind if the channel is full;
+    /// errors once the destination has stopped taking chunks.
+    fn offer(&mut self, chunk: &Chunk) -> Result<(), ()> {
+        if !self.wants(chunk) {
+            if let Chunk::Headers {..} = chunk {
+                self.missed_headers = Some(chunk.clone());
+            }
+            return Ok(());
+        }
 
-    let request = Request::put(url_str).body(request_payload)?;
-    let client = Client::builder().build(HttpConnector::new());
+        if let Some(headers) = self.missed_headers.take() {
+            match self.sender.try_send(headers) {
+                Ok(()) => {},
+                Err(err) if err.is_full() => {
+                    self.missed_headers = Some(err.into_inner());
+                    return Ok(());
+                },
+                Err(_) => return Err(())
+            }
+        }
+        match self.sender.try_send(chunk.clone()) {
+            Ok(()) => {
+                if self.lagging {
+                    info!("{} caught up, resuming at a keyframe", self.url);
+                }
+                self.lagging = false;
+                Ok(())
+            },
+            Err(err) if err.is_full() => {
+                if !self.lagging {
+                    warn!("{} is falling behind, skipping to the next keyframe", self.url);
+                }
+                self.lagging = true;
+                Ok(())
+            },
+            Err(_) => Err(())
+        }
+    }
+}
+
+/// Copies each chunk of the input to every destination, until the input ends or
+/// all destinations have failed. A destination that holds up the rest for more
+/// than `LAG_TIMEOUT` drops chunks until it can resume at a keyframe; a
+/// destination that's reconnecting keeps accepting chunks.
+async fn distribute(mut input: BoxedChunkStream, mut lanes: Vec<Lane>) -> Result<(), WebmetroError> {
+    while let Some(chunk) = input.next().await.transpose()? {
+        // wait for every destination in sync to have room...
+        let all_ready = future::poll_fn(|cx| {
+            if lanes.iter_mut().filter(|lane| !lane.lagging).any(|lane| lane.sender.poll_ready(cx).is_pending()) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        });
+        let timed_out = matches!(future::select(all_ready, delay(Instant::now() + LAG_TIMEOUT)).await, Either::Right(_));
+
+        // ...or if that's taking too long, for just one that wants the chunk
+        if timed_out {
+            future::poll_fn(|cx| {
+                let mut wanted = false;
+                for lane in lanes.iter_mut().filter(|lane| lane.wants(&chunk)) {
+                    wanted = true;
+                    if lane.sender.poll_ready(cx).is_ready() {
+                        return Poll::Ready(());
+                    }
+                }
+                if wanted { Poll::Pending } else { Poll::Ready(()) }
+            }).await;
+        }
+
+        let mut index = 0;
+        while index < lanes.len() {
+            if lanes[index].offer(&chunk).is_ok() {
+                index += 1;
+            } else {
+                lanes.swap_remove(index);
+            }
+        }
+
+        if lanes.is_empty() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct Destination {
+    url: String,
+    bytes_sent: u64,
+    reconnects: u64
+}
+
+impl Destination {
+    fn new(url: String) -> Destination {
+        Destination {
+            url,
+            bytes_sent: 0,
+            reconnects: 0
+        }
+    }
+}
+
+/// Uploads a stream of chunks to one destination, reconnecting if allowed
+async fn send_to(
+    client: &UploadClient,
+    destination: &mut Destination,
+    receiver: Receiver<Chunk>,
+    reconnect: bool
+) -> Result<(), WebmetroError> {
+    let mut input = Input {
+        stream: Box::new(receiver.map(Ok)),
+        done: false
+    };
+    let mut resume = ResumeBuffer::new(RESUME_BUFFER_LIMIT);
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        let err = match upload(client, destination, &mut input, &mut resume).await? {
+            Upload::Finished => return Ok(()),
+            Upload::Interrupted(err) => err
+        };
+        if !reconnect {
+            return Err(err);
+        }
+
+        if started.elapsed() >= STABLE_UPLOAD {
+            backoff = INITIAL_BACKOFF;
+        }
+        warn!("Upload to {} interrupted ({}), reconnecting in {:?}", destination.url, err, backoff);
+        resume.disconnect();
+        destination.reconnects += 1;
+
+        // keep reading while waiting, so live sources don't stall
+        let mut wait = delay(Instant::now() + backoff);
+        while !input.done {
+            match future::select(&mut wait, input.stream.next()).await {
+                Either::Left(_) => break,
+                Either::Right((Some(chunk), _)) => resume.push(chunk?),
+                Either::Right((None, _)) => input.done = true
+            }
+        }
+        if input.done {
+            wait.await;
+        }
+
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+struct Input {
+    stream: BoxedChunkStream,
+    done: bool
+}
+
+enum Upload {
+    Finished,
+    Interrupted(WebmetroError)
+}
+
+/// Makes one upload attempt, sending chunks from the resume buffer as the input
+/// fills it, until either the input ends or the connection fails.
+async fn upload(
+    client: &UploadClient,
+    destination: &mut Destination,
+    input: &mut Input,
+    resume: &mut ResumeBuffer
+) -> Result<Upload, WebmetroError> {
+    let (mut sender, body) = Body::channel();
+    let response = client.upload(&destination.url, body)?;
+    let bytes_sent = &mut destination.bytes_sent;
+
+    let send_chunks = async move {
+        // the body channel holds one chunk before the connection takes it, so
+        // a chunk only counts as sent once the channel is ready for the next
+        let mut unconfirmed_size = 0;
+        loop {
+            while let Some(chunk) = resume.pop() {
+                let bytes = chunk.into_bytes();
+                let size = bytes.len() as u64;
+                if let Err(err) = sender.send_data(bytes.into()).await {
+                    return Ok(Upload::Interrupted(err.into()));
+                }
+                *bytes_sent += unconfirmed_size;
+                unconfirmed_size = size;
+            }
+
+            if input.done {
+                // dropping the sender ends the request body
+                *bytes_sent += unconfirmed_size;
+                return Ok(Upload::Finished);
+            }
+            match input.stream.next().await {
+                Some(chunk) => resume.push(chunk?),
+                None => input.done = true
+            }
+        }
+    };
+    // the relay responds straight away, and streams its response while the upload continues
+    let receive_response = async {
+        let response = match response.await {
+            Ok(response) => response,
+            Err(err) => return Ok(Upload::Interrupted(err.into()))
+        };
+        if response.status().is_client_error() {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("Server rejected upload: {}", response.status())
+            });
+        }
 
-    Runtime::new().unwrap().block_on(async {
-        let response = client.request(request).await?;
         let mut response_stream = response.into_body();
-        while let Some(response_chunk) = response_stream.next().await.transpose()? {
-            stdout().write_all(&response_chunk)?;
+        while let Some(response_chunk) = response_stream.next().await {
+            match response_chunk {
+                Ok(response_chunk) => stdout().write_all(&response_chunk)?,
+                Err(err) => return Ok(Upload::Interrupted(err.into()))
+            }
+        }
+        Ok(Upload::Finished)
+    };
+    pin_mut!(send_chunks);
+    pin_mut!(receive_response);
+
+    match future::select(send_chunks, receive_response).await {
+        Either::Left((Ok(Upload::Finished), receive_response)) => receive_response.await,
+        Either::Left((result, _)) => result,
+        Either::Right((Ok(Upload::Finished), _)) => Ok(Upload::Interrupted("server ended the upload early".into())),
+        Either::Right((result, _)) => result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+    use webmetro::{
+        chunk::ClusterHead,
+        ebml::{EbmlNode, EbmlValue},
+        webm::{encode_webm_element, EbmlHeader, SimpleBlock, WebmElement}
+    };
+
+    use super::*;
+
+    /// A scratch directory for one test's files, removed afterwards
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("webmetro-send-{}-{}", std::process::id(), name));
+            create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    /// a WebM file with one Opus track, with the given CodecPrivate
+    fn webm_file(codec_private: &[u8]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut output).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut output).unwrap();
+        EbmlNode::master(0x0654AE6B, vec![
+            EbmlNode::master(0x2E, vec![
+                EbmlNode::value(0x57, EbmlValue::Uint(1)),
+                EbmlNode::value(0x33C5, EbmlValue::Uint(1)),
+                EbmlNode::value(0x03, EbmlValue::Uint(2)),
+                EbmlNode::value(0x06, EbmlValue::String("A_OPUS".into())),
+                EbmlNode::value(0x23A2, EbmlValue::Binary(codec_private.to_vec().into()))
+            ])
+        ]).encode(&mut output).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut output).unwrap();
+        encode_webm_element(WebmElement::Timecode(0), &mut output).unwrap();
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 1,
+            timecode: 0,
+            flags: 0x80,
+            data: &[0; 4]
+        }), &mut output).unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn playlist_paths_are_relative_to_it() {
+        let dir = TempDir::new("relative");
+        let playlist = dir.write("lists/show.txt", b"# tonight's show\n\nintro.webm\n  ../main.webm  \n\n# the end\n/abs/outro.webm\n");
+
+        assert_eq!(read_playlist(playlist.to_str().unwrap()).unwrap(), vec![
+            dir.0.join("lists/intro.webm"),
+            dir.0.join("lists/../main.webm"),
+            PathBuf::from("/abs/outro.webm")
+        ]);
+    }
+
+    #[test]
+    fn empty_playlist() {
+        let dir = TempDir::new("empty");
+        let playlist = dir.write("empty.txt", b"\n# only comments\n   \n");
+
+        let err = read_playlist(playlist.to_str().unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Playlist is empty");
+        assert!(read_playlist(dir.0.join("missing.txt").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn playlist_tracks_must_match() {
+        let dir = TempDir::new("tracks");
+        let first = dir.write("first.webm", &webm_file(&[1, 2, 3]));
+        let same = dir.write("same.webm", &webm_file(&[1, 2, 3]));
+        let different = dir.write("different.webm", &webm_file(&[1, 2, 4]));
+
+        let tracks = check_playlist_tracks(&[first.clone(), same.clone()]).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].codec_private, Some(vec![1, 2, 3]));
+
+        let err = check_playlist_tracks(&[first, same, different.clone()]).unwrap_err();
+        assert_eq!(err.to_string(), format!("{}: tracks don't match the rest of the playlist", different.display()));
+    }
+
+    #[test]
+    fn playlist_files_must_be_webm() {
+        let dir = TempDir::new("invalid");
+        let first = dir.write("first.webm", &webm_file(&[1]));
+        let text = dir.write("notes.webm", b"not a WebM file");
+
+        let err = check_playlist_tracks(&[first, text.clone()]).unwrap_err();
+        assert!(err.to_string().starts_with(&format!("{}: ", text.display())));
+    }
+
+    fn headers(name: &'static [u8]) -> Chunk {
+        Chunk::Headers {bytes: Bytes::from_static(name)}
+    }
+
+    fn cluster_head(timecode: u64, keyframe: bool) -> Chunk {
+        let mut cluster_head = ClusterHead::new(timecode);
+        cluster_head.keyframe = keyframe;
+        Chunk::ClusterHead(cluster_head)
+    }
+
+    fn body() -> Chunk {
+        Chunk::ClusterBody {bytes: Bytes::from_static(b"body")}
+    }
+
+    /// a short description of each chunk waiting in a lane's channel
+    fn drain(receiver: &mut Receiver<Chunk>) -> Vec<String> {
+        let mut chunks = Vec::new();
+        while let Ok(Some(chunk)) = receiver.try_next() {
+            chunks.push(match chunk {
+                Chunk::Headers {bytes} => format!("headers {}", String::from_utf8_lossy(&bytes)),
+                Chunk::ClusterHead(head) => format!("cluster {}{}", head.start, if head.keyframe { " keyframe" } else { "" }),
+                Chunk::ClusterBody {..} | Chunk::ClusterBodyPart {..} => "body".into()
+            });
         }
-        Ok(())
-    })
+        chunks
+    }
+
+    #[test]
+    fn full_lane_lags() {
+        // room for just one chunk
+        let (sender, mut receiver) = mpsc_channel(0);
+        let mut lane = Lane::new("test".into(), sender);
+
+        lane.offer(&cluster_head(0, true)).unwrap();
+        assert!(!lane.lagging);
+        lane.offer(&body()).unwrap();
+        assert!(lane.lagging);
+        assert_eq!(drain(&mut receiver), vec!["cluster 0 keyframe"]);
+    }
+
+    #[test]
+    fn lagging_lane_resumes_at_keyframe() {
+        let (sender, mut receiver) = mpsc_channel(0);
+        let mut lane = Lane::new("test".into(), sender);
+        lane.offer(&cluster_head(0, true)).unwrap();
+        lane.offer(&body()).unwrap();
+        assert!(lane.lagging);
+        drain(&mut receiver);
+
+        // with room again, it still skips everything up to a keyframe cluster
+        assert!(!lane.wants(&body()));
+        lane.offer(&body()).unwrap();
+        lane.offer(&cluster_head(1000, false)).unwrap();
+        lane.offer(&body()).unwrap();
+        assert_eq!(drain(&mut receiver), Vec::<String>::new());
+        assert!(lane.lagging);
+
+        lane.offer(&cluster_head(2000, true)).unwrap();
+        assert!(!lane.lagging);
+        assert_eq!(drain(&mut receiver), vec!["cluster 2000 keyframe"]);
+    }
+
+    #[test]
+    fn lagging_lane_replays_missed_headers() {
+        // room for two chunks
+        let (sender, mut receiver) = mpsc_channel(1);
+        let mut lane = Lane::new("test".into(), sender);
+        lane.offer(&cluster_head(0, true)).unwrap();
+        lane.offer(&body()).unwrap();
+        lane.offer(&body()).unwrap();
+        assert!(lane.lagging);
+        drain(&mut receiver);
+
+        // only the latest headers are kept
+        lane.offer(&headers(b"old")).unwrap();
+        lane.offer(&cluster_head(1000, false)).unwrap();
+        lane.offer(&headers(b"new")).unwrap();
+        lane.offer(&cluster_head(2000, true)).unwrap();
+        assert!(!lane.lagging);
+        assert_eq!(drain(&mut receiver), vec!["headers new", "cluster 2000 keyframe"]);
+    }
+
+    #[test]
+    fn missed_headers_wait_for_room() {
+        let (sender, mut receiver) = mpsc_channel(0);
+        let mut lane = Lane::new("test".into(), sender);
+        lane.offer(&cluster_head(0, true)).unwrap();
+        lane.offer(&body()).unwrap();
+        lane.offer(&headers(b"new")).unwrap();
+        drain(&mut receiver);
+
+        // the headers go out first, leaving no room for the keyframe yet
+        lane.offer(&cluster_head(1000, true)).unwrap();
+        assert!(lane.lagging);
+        assert_eq!(drain(&mut receiver), vec!["headers new"]);
+        lane.offer(&cluster_head(2000, true)).unwrap();
+        assert_eq!(drain(&mut receiver), vec!["cluster 2000 keyframe"]);
+    }
+
+    #[test]
+    fn closed_lane_is_dropped() {
+        let (open_sender, mut open_receiver) = mpsc_channel(DESTINATION_BUFFER);
+        let (closed_sender, closed_receiver) = mpsc_channel(DESTINATION_BUFFER);
+        drop(closed_receiver);
+
+        let mut closed = Lane::new("closed".into(), closed_sender);
+        assert!(closed.offer(&body()).is_err());
+
+        let lanes = vec![closed, Lane::new("open".into(), open_sender)];
+        let input: BoxedChunkStream = Box::new(stream::iter(vec![
+            headers(b"h"), cluster_head(0, true), body(), cluster_head(1000, false), body()
+        ]).map(Ok));
+        Runtime::new().unwrap().block_on(distribute(input, lanes)).unwrap();
+
+        assert_eq!(drain(&mut open_receiver), vec![
+            "headers h", "cluster 0 keyframe", "body", "cluster 1000", "body"
+        ]);
+    }
 }
@@ -0,0 +1,592 @@
+use std::collections::BTreeMap;
+
+use bytes::Buf;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures3::prelude::*;
+use serde_json::json;
+use tokio2::runtime::Runtime;
+
+use super::{input_arg, input_stream};
+use webmetro::{
+    ebml::EbmlValue,
+    error::WebmetroError,
+    schema::{lookup, Nesting, SchemaElement},
+    stream_parser::{ElementPosition, StreamEbml},
+    webm::{SimpleBlock, WebmElement},
+    FromEbml
+};
+
+/// Block & SimpleBlock share a layout, so both can be decoded as a SimpleBlock
+const SIMPLE_BLOCK_ID: u64 = 0x23;
+
+/// the codecs the WebM format allows
+const WEBM_CODECS: [&str; 5] = ["V_VP8", "V_VP9", "V_AV1", "A_VORBIS", "A_OPUS"];
+
+const VIDEO_TRACK_TYPE: u64 = 1;
+const AUDIO_TRACK_TYPE: u64 = 2;
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("validate")
+        .about("Checks a WebM input against the WebM format & Media Source Extensions requirements, reporting problems by byte offset")
+        .arg(input_arg())
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("Print problems as text, or as one JSON object per line"))
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let json = args.value_of("format") == Some("json");
+    let validator = Runtime::new().unwrap().block_on(validate(input_stream(args)?));
+
+    for problem in &validator.problems {
+        if json {
+            println!("{}", json!({
+                "offset": problem.offset,
+                "severity": problem.severity.name(),
+                "message": problem.message
+            }));
+        } else {
+            println!("offset {}: {}: {}", problem.offset, problem.severity.name(), problem.message);
+        }
+    }
+
+    let errors = validator.count(Severity::Error);
+    let warnings = validator.count(Severity::Warning);
+    if !json {
+        println!("{} errors, {} warnings", errors, warnings);
+    }
+    if errors > 0 {
+        return Err(format!("Validation failed with {} errors", errors).as_str().into());
+    }
+    Ok(())
+}
+
+/// Checks every element of the input, including whether it can be parsed to the end
+async fn validate<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin>(input: S) -> Validator {
+    let mut elements = input.parse_ebml();
+    let mut validator = Validator::default();
+
+    let parsed = async {
+        while let Some((position, element)) = elements.next_with_position::<SchemaElement>().await? {
+            validator.process(&position, element);
+        }
+        Result::<_, WebmetroError>::Ok(())
+    }.await;
+    match parsed {
+        Err(err) => {
+            validator.report(elements.consumed(), Severity::Error, format!("couldn't parse the rest of the input: {}", err));
+        },
+        Ok(()) if elements.unparsed_len() > 0 => {
+            let message = format!("input ends partway through an element, leaving {} bytes unparsed", elements.unparsed_len());
+            validator.report(elements.consumed(), Severity::Error, message);
+        },
+        Ok(()) => {}
+    }
+    validator.finish();
+    validator
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Severity {
+    /// breaks playback, or the rules of the format
+    Error,
+    /// likely to cause trouble with some players, or with the relay
+    Warning
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        }
+    }
+}
+
+struct Problem {
+    offset: u64,
+    severity: Severity,
+    message: String
+}
+
+/// The fields of the EBML header that matter for validation
+#[derive(Default)]
+struct Header {
+    offset: u64,
+    doc_type: Option<String>,
+    ebml_version: Option<u64>,
+    ebml_read_version: Option<u64>,
+    max_id_length: Option<u64>,
+    max_size_length: Option<u64>,
+    doc_type_read_version: Option<u64>
+}
+
+/// The fields of a TrackEntry that matter for validation
+#[derive(Default)]
+struct Track {
+    offset: u64,
+    number: Option<u64>,
+    uid: Option<u64>,
+    track_type: Option<u64>,
+    codec_id: Option<String>,
+    pixel_width: Option<u64>,
+    pixel_height: Option<u64>,
+    sampling_frequency: Option<f64>
+}
+
+#[derive(Default)]
+struct Validator {
+    nesting: Nesting,
+    problems: Vec<Problem>,
+    /// the end of the last element read
+    end: u64,
+
+    header: Option<Header>,
+    seen_header: bool,
+    seen_segment: bool,
+
+    /// the TrackEntries of the Tracks element being read
+    new_tracks: Option<Vec<Track>>,
+    /// the types of the tracks in the latest Tracks element, by number
+    tracks: Option<BTreeMap<u64, u64>>,
+
+    clusters: u64,
+    cluster_timecode: Option<i64>,
+    previous_cluster_timecode: Option<i64>,
+    /// video tracks which have had a block in the current cluster
+    cluster_video_started: Vec<u64>,
+    /// the latest timestamp of each track
+    track_timestamps: BTreeMap<u64, i64>
+}
+
+impl Validator {
+    fn process(&mut self, position: &ElementPosition, element: SchemaElement) {
+        let depth = self.nesting.depth_of(position, matches!(element, SchemaElement::Master(_)));
+        self.end = self.end.max(position.offset + position.header_size as u64 + position.payload_size.unwrap_or(0));
+
+        // the header & Tracks are checked once they've been read in full
+        if depth == 0 {
+            self.finish_header();
+        }
+        if depth <= 1 {
+            self.finish_tracks();
+        }
+
+        let schema = match lookup(position.element_id) {
+            Some(schema) => schema,
+            None => return
+        };
+        let value = match element {
+            SchemaElement::Master(_) => None,
+            SchemaElement::Value(_, bytes) => match EbmlValue::decode(schema.element_type, bytes) {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    self.report(position.offset, Severity::Error, format!("{} has an invalid {:?} value", schema.name, schema.element_type));
+                    return;
+                }
+            }
+        };
+        let uint = match value {
+            Some(EbmlValue::Uint(value)) => Some(value),
+            _ => None
+        };
+
+        match schema.name {
+            "EBML" => {
+                if self.seen_segment {
+                    self.report(position.offset, Severity::Warning, "a new stream begins here; players using Media Source Extensions need a new SourceBuffer".into());
+                }
+                self.seen_header = true;
+                self.header = Some(Header {offset: position.offset, ..Header::default()});
+            },
+            "Segment" => {
+                if !self.seen_header {
+                    self.report(position.offset, Severity::Error, "Segment without an EBML header".into());
+                }
+                self.seen_segment = true;
+            },
+            "DocType" => if let (Some(header), Some(EbmlValue::String(doc_type)) | Some(EbmlValue::Utf8(doc_type))) = (&mut self.header, value) {
+                header.doc_type = Some(doc_type);
+            },
+            "EBMLVersion" => self.header_field(|header| &mut header.ebml_version, uint),
+            "EBMLReadVersion" => self.header_field(|header| &mut header.ebml_read_version, uint),
+            "EBMLMaxIDLength" => self.header_field(|header| &mut header.max_id_length, uint),
+            "EBMLMaxSizeLength" => self.header_field(|header| &mut header.max_size_length, uint),
+            "DocTypeReadVersion" => self.header_field(|header| &mut header.doc_type_read_version, uint),
+
+            "Tracks" => self.new_tracks = Some(Vec::new()),
+            "TrackEntry" => if let Some(tracks) = &mut self.new_tracks {
+                tracks.push(Track {offset: position.offset, ..Track::default()});
+            },
+            "TrackNumber" => self.track_field(|track| track.number = uint),
+            "TrackUID" => self.track_field(|track| track.uid = uint),
+            "TrackType" => self.track_field(|track| track.track_type = uint),
+            "PixelWidth" => self.track_field(|track| track.pixel_width = uint),
+            "PixelHeight" => self.track_field(|track| track.pixel_height = uint),
+            "CodecID" => if let Some(EbmlValue::String(codec_id)) | Some(EbmlValue::Utf8(codec_id)) = value {
+                self.track_field(|track| track.codec_id = Some(codec_id));
+            },
+            "SamplingFrequency" => if let Some(EbmlValue::Float(frequency)) = value {
+                self.track_field(|track| track.sampling_frequency = Some(frequency));
+            },
+
+            "Cluster" => {
+                if self.tracks.is_none() {
+                    self.report(position.offset, Severity::Error, "Cluster before any Tracks element".into());
+                }
+                self.clusters += 1;
+                self.previous_cluster_timecode = self.cluster_timecode.or(self.previous_cluster_timecode);
+                self.cluster_timecode = None;
+                self.cluster_video_started.clear();
+            },
+            "Timecode" => if let Some(timecode) = uint {
+                let timecode = timecode as i64;
+                if let Some(previous) = self.previous_cluster_timecode.filter(|&previous| timecode < previous) {
+                    self.report(position.offset, Severity::Error, format!("cluster timecode went backwards, from {}ms to {}ms", previous, timecode));
+                }
+                self.cluster_timecode = Some(timecode);
+            },
+            "SimpleBlock" | "Block" => if let SchemaElement::Value(_, bytes) = element {
+                match WebmElement::decode(SIMPLE_BLOCK_ID, bytes) {
+                    Ok(WebmElement::SimpleBlock(block)) => self.check_block(position, block, schema.name == "SimpleBlock"),
+                    _ => self.report(position.offset, Severity::Error, format!("{} is corrupt", schema.name))
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn header_field<F: FnOnce(&mut Header) -> &mut Option<u64>>(&mut self, field: F, value: Option<u64>) {
+        if let Some(header) = &mut self.header {
+            *field(header) = value;
+        }
+    }
+
+    fn track_field<F: FnOnce(&mut Track)>(&mut self, update: F) {
+        if let Some(track) = self.new_tracks.as_mut().and_then(|tracks| tracks.last_mut()) {
+            update(track);
+        }
+    }
+
+    fn finish_header(&mut self) {
+        let header = match self.header.take() {
+            Some(header) => header,
+            None => return
+        };
+        let offset = header.offset;
+
+        match header.doc_type.as_deref() {
+            Some("webm") => {},
+            Some(doc_type) => self.report(offset, Severity::Error, format!("DocType is \"{}\", not \"webm\"", doc_type)),
+            None => self.report(offset, Severity::Error, "DocType is missing, so defaults to \"matroska\" rather than \"webm\"".into())
+        }
+        if header.ebml_version.unwrap_or(1) != 1 {
+            self.report(offset, Severity::Error, format!("unsupported EBMLVersion {}", header.ebml_version.unwrap_or(1)));
+        }
+        if header.ebml_read_version.unwrap_or(1) != 1 {
+            self.report(offset, Severity::Error, format!("unsupported EBMLReadVersion {}", header.ebml_read_version.unwrap_or(1)));
+        }
+        if header.max_id_length.unwrap_or(4) > 4 {
+            self.report(offset, Severity::Error, format!("EBMLMaxIDLength {} is over WebM's limit of 4", header.max_id_length.unwrap_or(4)));
+        }
+        if header.max_size_length.unwrap_or(8) > 8 {
+            self.report(offset, Severity::Error, format!("EBMLMaxSizeLength {} is over WebM's limit of 8", header.max_size_length.unwrap_or(8)));
+        }
+        if header.doc_type_read_version.unwrap_or(1) > 2 {
+            self.report(offset, Severity::Warning, format!("DocTypeReadVersion {} may not be supported by players", header.doc_type_read_version.unwrap_or(1)));
+        }
+    }
+
+    fn finish_tracks(&mut self) {
+        let new_tracks = match self.new_tracks.take() {
+            Some(tracks) => tracks,
+            None => return
+        };
+
+        let mut tracks = BTreeMap::new();
+        for track in new_tracks {
+            let offset = track.offset;
+            let number = match track.number {
+                Some(number) => number,
+                None => {
+                    self.report(offset, Severity::Error, "TrackEntry is missing its TrackNumber".into());
+                    continue;
+                }
+            };
+            let missing = |field| format!("track {} is missing its {}", number, field);
+
+            if track.uid.is_none() {
+                self.report(offset, Severity::Warning, missing("TrackUID"));
+            }
+            match track.codec_id.as_ref() {
+                Some(codec_id) if !WEBM_CODECS.contains(&codec_id.as_str()) => {
+                    self.report(offset, Severity::Warning, format!("track {} uses {}, which isn't a WebM codec", number, codec_id));
+                },
+                Some(_) => {},
+                None => self.report(offset, Severity::Error, missing("CodecID"))
+            }
+            match track.track_type {
+                Some(VIDEO_TRACK_TYPE) => if track.pixel_width.is_none() || track.pixel_height.is_none() {
+                    self.report(offset, Severity::Error, missing("PixelWidth/PixelHeight"));
+                },
+                Some(AUDIO_TRACK_TYPE) => if track.sampling_frequency.is_none() {
+                    self.report(offset, Severity::Warning, missing("SamplingFrequency"));
+                },
+                Some(_) => {},
+                None => self.report(offset, Severity::Error, missing("TrackType"))
+            }
+            if tracks.insert(number, track.track_type.unwrap_or(0)).is_some() {
+                self.report(offset, Severity::Error, format!("track number {} is used more than once", number));
+            }
+        }
+
+        if let Some(previous) = &self.tracks {
+            if previous != &tracks {
+                self.report(self.end, Severity::Warning, "the tracks changed from the previous Tracks element".into());
+            }
+        }
+        self.tracks = Some(tracks);
+    }
+
+    fn check_block(&mut self, position: &ElementPosition, block: SimpleBlock, simple: bool) {
+        let offset = position.offset;
+        let track_type = match self.tracks.as_ref().map(|tracks| tracks.get(&block.track)) {
+            Some(Some(&track_type)) => track_type,
+            Some(None) => {
+                self.report(offset, Severity::Error, format!("block on track {}, which isn't described in the Tracks element", block.track));
+                return;
+            },
+            None => return
+        };
+
+        if block.frame_sizes().is_err() {
+            self.report(offset, Severity::Error, format!("block on track {} has invalid lacing", block.track));
+        }
+        if block.timecode < 0 {
+            self.report(offset, Severity::Warning, format!("block on track {} is {}ms before its cluster's timecode", block.track, -block.timecode));
+        }
+
+        let timestamp = match self.cluster_timecode {
+            Some(timecode) => timecode + block.timecode as i64,
+            None => {
+                self.report(offset, Severity::Error, format!("block on track {} before its cluster's Timecode", block.track));
+                return;
+            }
+        };
+        if let Some(&previous) = self.track_timestamps.get(&block.track) {
+            let cluster_timecode = timestamp - block.timecode as i64;
+            if previous >= cluster_timecode && previous - timestamp > i16::MAX as i64 {
+                // an encoder counting past the 16 bits a block has for its timecode wraps around
+                self.report(offset, Severity::Error, format!("block on track {} wrapped around to {}ms after {}ms; blocks must be within {}ms of their cluster's timecode",
+                    block.track, timestamp, previous, i16::MAX));
+            } else if timestamp < previous {
+                self.report(offset, Severity::Error, format!("track {} timestamp went backwards, from {}ms to {}ms", block.track, previous, timestamp));
+            }
+        }
+        self.track_timestamps.insert(block.track, timestamp);
+
+        // BlockGroups mark keyframes differently, so only SimpleBlocks are checked
+        if simple && track_type == VIDEO_TRACK_TYPE && !self.cluster_video_started.contains(&block.track) {
+            self.cluster_video_started.push(block.track);
+            if !block.is_keyframe() {
+                // Media Source Extensions require the first; the relay starts viewers at any cluster
+                let severity = if self.clusters == 1 { Severity::Error } else { Severity::Warning };
+                self.report(offset, severity, format!("cluster doesn't start with a keyframe on video track {}", block.track));
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finish_header();
+        self.finish_tracks();
+        if !self.seen_header {
+            self.report(0, Severity::Error, "no EBML header found".into());
+        } else if !self.seen_segment {
+            self.report(self.end, Severity::Error, "no Segment found".into());
+        }
+    }
+
+    fn report(&mut self, offset: u64, severity: Severity, message: String) {
+        self.problems.push(Problem {offset, severity, message});
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.problems.iter().filter(|problem| problem.severity == severity).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+    use futures3::{future::FutureExt, stream};
+    use webmetro::{
+        ebml::{EbmlNode, EbmlValue},
+        webm::{encode_webm_element, EbmlHeader, SimpleBlock, WebmElement}
+    };
+
+    use super::*;
+
+    const KEYFRAME: u8 = 0x80;
+
+    fn header(header: EbmlHeader) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(header), &mut output).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut output).unwrap();
+        output.into_inner()
+    }
+
+    /// a Tracks element with the given (number, type) tracks, otherwise complete
+    fn tracks(entries: &[(u64, u64)]) -> Vec<u8> {
+        let entries = entries.iter().map(|&(number, track_type)| {
+            let mut children = vec![
+                EbmlNode::value(0x57, EbmlValue::Uint(number)),
+                EbmlNode::value(0x33C5, EbmlValue::Uint(number)),
+                EbmlNode::value(0x03, EbmlValue::Uint(track_type))
+            ];
+            if track_type == VIDEO_TRACK_TYPE {
+                children.push(EbmlNode::value(0x06, EbmlValue::String("V_VP9".into())));
+                children.push(EbmlNode::master(0x60, vec![
+                    EbmlNode::value(0x30, EbmlValue::Uint(320)),
+                    EbmlNode::value(0x3A, EbmlValue::Uint(240))
+                ]));
+            } else {
+                children.push(EbmlNode::value(0x06, EbmlValue::String("A_OPUS".into())));
+                children.push(EbmlNode::master(0x61, vec![
+                    EbmlNode::value(0x35, EbmlValue::Float(48000.0))
+                ]));
+            }
+            EbmlNode::master(0x2E, children)
+        }).collect();
+
+        let mut output = Vec::new();
+        EbmlNode::master(0x0654AE6B, entries).encode(&mut output).unwrap();
+        output
+    }
+
+    /// a Cluster with (track, timecode, flags, data) blocks
+    fn cluster(timecode: u64, blocks: &[(u64, i16, u8, &[u8])]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::Cluster, &mut output).unwrap();
+        encode_webm_element(WebmElement::Timecode(timecode), &mut output).unwrap();
+        for &(track, timecode, flags, data) in blocks {
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {track, timecode, flags, data}), &mut output).unwrap();
+        }
+        output.into_inner()
+    }
+
+    /// a valid stream, with a video track 1 & an audio track 2
+    fn stream_with(clusters: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = header(EbmlHeader::webm());
+        bytes.extend(tracks(&[(1, VIDEO_TRACK_TYPE), (2, AUDIO_TRACK_TYPE)]));
+        for cluster in clusters {
+            bytes.extend(cluster);
+        }
+        bytes
+    }
+
+    fn problems(bytes: Vec<u8>) -> Vec<(Severity, String)> {
+        let input = stream::iter(vec![Ok(Cursor::new(Bytes::from(bytes)))]);
+        let validator = validate(input).now_or_never().expect("Test tried to block on I/O");
+        validator.problems.into_iter().map(|problem| (problem.severity, problem.message)).collect()
+    }
+
+    fn has_error(problems: &[(Severity, String)], message: &str) -> bool {
+        problems.iter().any(|(severity, text)| *severity == Severity::Error && text.contains(message))
+    }
+
+    #[test]
+    fn valid_stream() {
+        let bytes = stream_with(&[
+            cluster(0, &[(1, 0, KEYFRAME, &[0; 4]), (2, 0, KEYFRAME, &[0; 4]), (1, 40, 0, &[0; 4])]),
+            cluster(80, &[(1, 0, KEYFRAME, &[0; 4]), (2, 10, KEYFRAME, &[0; 4])])
+        ]);
+        assert_eq!(problems(bytes), vec![]);
+    }
+
+    #[test]
+    fn missing_doc_type() {
+        let mut bytes = header(EbmlHeader::default());
+        bytes.extend(tracks(&[(1, VIDEO_TRACK_TYPE)]));
+        bytes.extend(cluster(0, &[(1, 0, KEYFRAME, &[0; 4])]));
+
+        let problems = problems(bytes);
+        assert_eq!(problems.len(), 1);
+        assert!(has_error(&problems, "DocType is missing"));
+    }
+
+    #[test]
+    fn duplicate_track_numbers() {
+        let mut bytes = header(EbmlHeader::webm());
+        bytes.extend(tracks(&[(1, VIDEO_TRACK_TYPE), (1, AUDIO_TRACK_TYPE)]));
+
+        assert!(has_error(&problems(bytes), "track number 1 is used more than once"));
+    }
+
+    #[test]
+    fn first_cluster_without_keyframe() {
+        let bytes = stream_with(&[
+            cluster(0, &[(2, 0, KEYFRAME, &[0; 4]), (1, 0, 0, &[0; 4])]),
+            cluster(40, &[(1, 0, 0, &[0; 4])])
+        ]);
+        let problems = problems(bytes);
+
+        // MSE needs the first cluster to start with a keyframe, but later ones only matter to the relay
+        assert_eq!(problems, vec![
+            (Severity::Error, "cluster doesn't start with a keyframe on video track 1".into()),
+            (Severity::Warning, "cluster doesn't start with a keyframe on video track 1".into())
+        ]);
+    }
+
+    #[test]
+    fn timecodes_backwards() {
+        let bytes = stream_with(&[
+            cluster(1000, &[(1, 0, KEYFRAME, &[0; 4]), (1, 40, 0, &[0; 4]), (1, 20, 0, &[0; 4])]),
+            cluster(500, &[(1, 0, KEYFRAME, &[0; 4])])
+        ]);
+        let problems = problems(bytes);
+
+        assert!(has_error(&problems, "track 1 timestamp went backwards, from 1040ms to 1020ms"));
+        assert!(has_error(&problems, "cluster timecode went backwards, from 1000ms to 500ms"));
+    }
+
+    #[test]
+    fn block_timecode_wraps_around() {
+        let bytes = stream_with(&[
+            cluster(0, &[(1, 0, KEYFRAME, &[0; 4]), (1, 32760, 0, &[0; 4]), (1, -32736, 0, &[0; 4])])
+        ]);
+        let problems = problems(bytes);
+
+        assert!(has_error(&problems, "block on track 1 wrapped around to -32736ms after 32760ms"));
+        assert!(!has_error(&problems, "went backwards"));
+    }
+
+    #[test]
+    fn bad_lacing() {
+        // Xiph lacing claiming 3 frames, with nothing to size them
+        let bytes = stream_with(&[
+            cluster(0, &[(1, 0, KEYFRAME | 0x02, &[0x02])])
+        ]);
+        assert!(has_error(&problems(bytes), "block on track 1 has invalid lacing"));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let mut bytes = stream_with(&[
+            cluster(0, &[(1, 0, KEYFRAME, &[0; 4])])
+        ]);
+        bytes.truncate(bytes.len() - 2);
+
+        assert!(has_error(&problems(bytes), "input ends partway through an element, leaving 8 bytes unparsed"));
+    }
+
+    #[test]
+    fn no_header() {
+        let problems = problems(cluster(0, &[]));
+        assert!(has_error(&problems, "no EBML header found"));
+    }
+}
@@ -1,6 +1,11 @@
 use bytes::{BigEndian, ByteOrder, BufMut};
 use custom_error::custom_error;
-use std::io::{Cursor, Error as IoError, ErrorKind, Result as IoResult, Write, Seek, SeekFrom};
+use std::borrow::Cow;
+use std::io::{Cursor, Error as IoError, ErrorKind, Result as IoResult, Write};
+
+use crate::schema::ElementType;
+
+pub use webmetro_derive::{EbmlEncode, FromEbml};
 
 pub const EBML_HEAD_ID: u64 = 0x0A45DFA3;
 pub const DOC_TYPE_ID: u64 = 0x0282;
@@ -11,13 +16,16 @@ custom_error!{pub EbmlError
     UnknownElementId     = r#"EBML element ID was "unknown"#,
     UnknownElementLength = r#"EBML element length was "unknown" for an element not allowing that"#,
     CorruptPayload       = r#"EBML element payload could not be parsed"#,
+    MissingElement       = r#"EBML master element was missing a required child"#,
+    TooDeep              = r#"EBML elements were nested too deeply"#,
 }
 
 custom_error!{pub WriteError
-    OutOfRange = "EBML Varint out of range"
+    OutOfRange = "EBML Varint out of range",
+    InvalidString = "EBML String values must be printable ASCII"
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Varint {
     /// a numeric value
     Value(u64),
@@ -109,12 +117,65 @@ pub fn decode_uint(bytes: &[u8]) -> Result<u64, EbmlError> {
     Ok(BigEndian::read_uint(bytes, bytes.len()))
 }
 
+/// Decodes a 4 or 8-byte big-endian IEEE float
+pub fn decode_float(bytes: &[u8]) -> Result<f64, EbmlError> {
+    match bytes.len() {
+        4 => Ok(BigEndian::read_f32(bytes) as f64),
+        8 => Ok(BigEndian::read_f64(bytes)),
+        _ => Err(EbmlError::CorruptPayload)
+    }
+}
+
+/// Decodes a two's-complement signed integer of 1 to 8 bytes
+pub fn decode_int(bytes: &[u8]) -> Result<i64, EbmlError> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    Ok(BigEndian::read_int(bytes, bytes.len()))
+}
+
+/// Strips the zero padding strings are allowed to end with;
+/// padding must run to the end of the payload.
+fn strip_padding(bytes: &[u8]) -> Result<&[u8], EbmlError> {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    if bytes[end..].iter().any(|&byte| byte != 0) {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    Ok(&bytes[..end])
+}
+
+/// Decodes a String element: printable ASCII, optionally zero-padded
+pub fn decode_string(bytes: &[u8]) -> Result<&str, EbmlError> {
+    let bytes = strip_padding(bytes)?;
+    if !bytes.iter().all(|byte| (0x20..=0x7E).contains(byte)) {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    // printable ASCII is always valid UTF-8
+    std::str::from_utf8(bytes).map_err(|_| EbmlError::CorruptPayload)
+}
+
+/// Decodes a UTF-8 element, optionally zero-padded
+pub fn decode_utf8(bytes: &[u8]) -> Result<&str, EbmlError> {
+    std::str::from_utf8(strip_padding(bytes)?).map_err(|_| EbmlError::CorruptPayload)
+}
+
+/// Decodes a Date element, in nanoseconds since 2001-01-01T00:00:00 UTC
+pub fn decode_date(bytes: &[u8]) -> Result<i64, EbmlError> {
+    match bytes.len() {
+        8 => Ok(BigEndian::read_i64(bytes)),
+        _ => Err(EbmlError::CorruptPayload)
+    }
+}
+
 const SMALL_FLAG: u64 = 0x80;
 const EIGHT_FLAG: u64 = 0x01 << (8*7);
 const EIGHT_MAX: u64 = EIGHT_FLAG - 2;
 
 /// Tries to write an EBML varint using minimal space
-pub fn encode_varint<T: Write>(varint: Varint, output: &mut T) -> IoResult<()> {
+pub fn encode_varint<T: Write + ?Sized>(varint: Varint, output: &mut T) -> IoResult<()> {
     let (size, number) = match varint {
         Varint::Unknown => (1, 0xFF),
         Varint::Value(too_big) if too_big > EIGHT_MAX => {
@@ -145,7 +206,7 @@ const FOUR_FLAG: u64 = 0x10 << (8*3);
 const FOUR_MAX: u64 = FOUR_FLAG - 2;
 
 // tries to write a varint with a fixed 4-byte representation
-pub fn encode_varint_4<T: Write>(varint: Varint, output: &mut T) -> IoResult<()> {
+pub fn encode_varint_4<T: Write + ?Sized>(varint: Varint, output: &mut T) -> IoResult<()> {
     let number = match varint {
         Varint::Unknown => FOUR_FLAG | (FOUR_FLAG - 1),
         Varint::Value(too_big) if too_big > FOUR_MAX => {
@@ -160,34 +221,101 @@ pub fn encode_varint_4<T: Write>(varint: Varint, output: &mut T) -> IoResult<()>
     output.write_all(&buffer.get_ref()[..])
 }
 
-pub fn encode_element<T: Write + Seek, F: Fn(&mut T) -> IoResult<X>, X>(tag: u64, output: &mut T, content: F) -> IoResult<()> {
-    encode_varint(Varint::Value(tag), output)?;
-    encode_varint_4(Varint::Unknown, output)?;
+/// Counts the bytes written to it, without storing them
+#[derive(Default)]
+struct ByteCounter(u64);
 
-    let start = output.seek(SeekFrom::Current(0))?;
-    content(output)?;
-    let end = output.seek(SeekFrom::Current(0))?;
+impl Write for ByteCounter {
+    fn write(&mut self, bytes: &[u8]) -> IoResult<usize> {
+        self.0 += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
 
-    output.seek(SeekFrom::Start(start - 4))?;
-    encode_varint_4(Varint::Value(end - start), output)?;
-    output.seek(SeekFrom::Start(end))?;
+/// Tries to write a master element, whose children are written by `content`.
+/// `content` is called twice: once to measure the children's size, so the
+/// element's size can be written first in as few bytes as possible, then to
+/// write them. This means any `Write` can be the output, including sockets,
+/// hashers, and `BufMut`s (via `BufMut::writer`).
+///
+/// Since each level of nested calls doubles the passes, masters holding other
+/// masters should measure their children up front and use `encode_sized_element`,
+/// as `EbmlNode` and derived `EbmlEncode` impls do.
+pub fn encode_element<T: Write + ?Sized, F: Fn(&mut dyn Write) -> IoResult<X>, X>(tag: u64, output: &mut T, content: F) -> IoResult<()> {
+    let mut counter = ByteCounter::default();
+    content(&mut counter)?;
+
+    encode_sized_element(tag, counter.0, output, |output| content(output).map(|_| ()))
+}
+
+/// Tries to write a master element whose children, written once by `content`,
+/// are already known to take up `size` bytes
+pub fn encode_sized_element<T: Write + ?Sized, F: FnOnce(&mut dyn Write) -> IoResult<()>>(tag: u64, size: u64, output: &mut T, content: F) -> IoResult<()> {
+    encode_tag_header(tag, Varint::Value(size), output)?;
+
+    let mut output = CountingWriter { output, written: 0 };
+    content(&mut output)?;
+    if output.written != size {
+        return Err(IoError::new(ErrorKind::InvalidData, "EBML element content changed size between passes"));
+    }
 
     Ok(())
 }
 
-pub fn encode_tag_header<T: Write>(tag: u64, size: Varint, output: &mut T) -> IoResult<()> {
+/// The size of an element once encoded, given the size of its payload
+pub fn element_size(tag: u64, payload_size: u64) -> IoResult<u64> {
+    let mut counter = ByteCounter::default();
+    encode_tag_header(tag, Varint::Value(payload_size), &mut counter)?;
+    Ok(counter.0 + payload_size)
+}
+
+/// Passes writes through, counting how many bytes went by
+struct CountingWriter<'a, T: Write + ?Sized> {
+    output: &'a mut T,
+    written: u64
+}
+
+impl<'a, T: Write + ?Sized> Write for CountingWriter<'a, T> {
+    fn write(&mut self, bytes: &[u8]) -> IoResult<usize> {
+        let written = self.output.write(bytes)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.output.flush()
+    }
+}
+
+pub fn encode_tag_header<T: Write + ?Sized>(tag: u64, size: Varint, output: &mut T) -> IoResult<()> {
     encode_varint(Varint::Value(tag), output)?;
     encode_varint(size, output)
 }
 
 /// Tries to write a simple EBML tag with a string or binary value
-pub fn encode_bytes<T: Write>(tag: u64, bytes: &[u8], output: &mut T) -> IoResult<()> {
+pub fn encode_bytes<T: Write + ?Sized>(tag: u64, bytes: &[u8], output: &mut T) -> IoResult<()> {
     encode_tag_header(tag, Varint::Value(bytes.len() as u64), output)?;
     output.write_all(bytes)
 }
 
-/// Tries to write a simple EBML tag with an integer value
-pub fn encode_integer<T: Write>(tag: u64, value: u64, output: &mut T) -> IoResult<()> {
+/// Tries to write a simple EBML tag with an integer value, using as few bytes as will hold it
+pub fn encode_integer<T: Write + ?Sized>(tag: u64, value: u64, output: &mut T) -> IoResult<()> {
+    let size = (8 - value.leading_zeros() as usize / 8).max(1);
+    encode_tag_header(tag, Varint::Value(size as u64), output)?;
+
+    let mut buffer = Cursor::new([0; 8]);
+    buffer.put_uint_be(value, size);
+
+    output.write_all(&buffer.get_ref()[..size])
+}
+
+/// Tries to write a simple EBML tag with an integer value, always using 8 bytes,
+/// so the element keeps the same size whatever value it's rewritten with
+pub fn encode_fixed_integer<T: Write + ?Sized>(tag: u64, value: u64, output: &mut T) -> IoResult<()> {
     encode_tag_header(tag, Varint::Value(8), output)?;
 
     let mut buffer = Cursor::new([0; 8]);
@@ -196,6 +324,57 @@ pub fn encode_integer<T: Write>(tag: u64, value: u64, output: &mut T) -> IoResul
     output.write_all(&buffer.get_ref()[..])
 }
 
+/// Tries to write a simple EBML tag with a signed integer value, using as few bytes as
+/// will hold it in two's complement
+pub fn encode_signed_integer<T: Write + ?Sized>(tag: u64, value: i64, output: &mut T) -> IoResult<()> {
+    // a byte can be dropped while the next one's top bit still carries the sign
+    let mut size = 8;
+    while size > 1 && (value << (64 - 8 * (size - 1)) >> (64 - 8 * (size - 1))) == value {
+        size -= 1;
+    }
+    encode_tag_header(tag, Varint::Value(size as u64), output)?;
+
+    let mut buffer = Cursor::new([0; 8]);
+    buffer.put_int_be(value, size);
+
+    output.write_all(&buffer.get_ref()[..size])
+}
+
+/// Tries to write a simple EBML tag with a float value; values that
+/// survive the round trip through single precision are written in 4 bytes
+pub fn encode_float<T: Write + ?Sized>(tag: u64, value: f64, output: &mut T) -> IoResult<()> {
+    let single = value as f32;
+    if single as f64 == value || value.is_nan() {
+        let mut buffer = [0; 4];
+        BigEndian::write_f32(&mut buffer, single);
+        encode_bytes(tag, &buffer, output)
+    } else {
+        let mut buffer = [0; 8];
+        BigEndian::write_f64(&mut buffer, value);
+        encode_bytes(tag, &buffer, output)
+    }
+}
+
+/// Tries to write a simple EBML tag with a String value, which must be printable ASCII
+pub fn encode_string<T: Write + ?Sized>(tag: u64, value: &str, output: &mut T) -> IoResult<()> {
+    if !value.bytes().all(|byte| (0x20..=0x7E).contains(&byte)) {
+        return Err(IoError::new(ErrorKind::InvalidInput, WriteError::InvalidString));
+    }
+    encode_bytes(tag, value.as_bytes(), output)
+}
+
+/// Tries to write a simple EBML tag with a UTF-8 value
+pub fn encode_utf8<T: Write + ?Sized>(tag: u64, value: &str, output: &mut T) -> IoResult<()> {
+    encode_bytes(tag, value.as_bytes(), output)
+}
+
+/// Tries to write a simple EBML tag with a Date value, in nanoseconds since 2001-01-01T00:00:00 UTC
+pub fn encode_date<T: Write + ?Sized>(tag: u64, value: i64, output: &mut T) -> IoResult<()> {
+    let mut buffer = [0; 8];
+    BigEndian::write_i64(&mut buffer, value);
+    encode_bytes(tag, &buffer, output)
+}
+
 pub struct EbmlLayout {
     pub element_id: u64,
     pub body_offset: usize,
@@ -260,6 +439,321 @@ pub trait FromEbml<'a>: Sized {
     }
 }
 
+/// Calls `visit` with the ID & payload of each element filling the given slice,
+/// such as the children in a master element's payload.
+/// Elements must have a known size, and fit within the slice.
+pub fn for_each_child<'a, F: FnMut(u64, &'a[u8]) -> Result<(), EbmlError>>(bytes: &'a[u8], mut visit: F) -> Result<(), EbmlError> {
+    let mut remaining = bytes;
+
+    while !remaining.is_empty() {
+        let (id, size, header_size) = decode_tag(remaining)?.ok_or(EbmlError::CorruptPayload)?;
+        let size = match size {
+            Varint::Value(size) => size as usize,
+            Varint::Unknown => return Err(EbmlError::UnknownElementLength)
+        };
+        if size > remaining.len() - header_size {
+            return Err(EbmlError::CorruptPayload);
+        }
+        visit(id, &remaining[header_size..header_size + size])?;
+        remaining = &remaining[header_size + size..];
+    }
+
+    Ok(())
+}
+
+/// Types that can be decoded from a child element's payload,
+/// for use as fields of a `#[derive(FromEbml)]` master element
+pub trait FromEbmlField: Sized {
+    fn from_field(bytes: &[u8]) -> Result<Self, EbmlError>;
+}
+
+/// Types that can be written as a child element with a given ID,
+/// for use as fields of a `#[derive(EbmlEncode)]` master element
+pub trait EncodeEbmlField {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()>;
+
+    /// The size `encode_field` will write; by default found by encoding into a counter,
+    /// which master elements override so their children aren't encoded just to be measured
+    fn encoded_field_size(&self, id: u64) -> IoResult<u64> {
+        let mut counter = ByteCounter::default();
+        self.encode_field(id, &mut counter)?;
+        Ok(counter.0)
+    }
+}
+
+/// An element that knows its own ID, and so can write itself out
+pub trait EbmlEncode {
+    fn encode<T: Write + ?Sized>(&self, output: &mut T) -> IoResult<()>;
+}
+
+impl FromEbmlField for u64 {
+    fn from_field(bytes: &[u8]) -> Result<u64, EbmlError> {
+        if bytes.is_empty() { Ok(0) } else { decode_uint(bytes) }
+    }
+}
+
+impl EncodeEbmlField for u64 {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_integer(id, *self, output)
+    }
+}
+
+impl FromEbmlField for i64 {
+    fn from_field(bytes: &[u8]) -> Result<i64, EbmlError> {
+        if bytes.is_empty() { Ok(0) } else { decode_int(bytes) }
+    }
+}
+
+impl EncodeEbmlField for i64 {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_signed_integer(id, *self, output)
+    }
+}
+
+impl FromEbmlField for f64 {
+    fn from_field(bytes: &[u8]) -> Result<f64, EbmlError> {
+        if bytes.is_empty() { Ok(0.0) } else { decode_float(bytes) }
+    }
+}
+
+impl EncodeEbmlField for f64 {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_float(id, *self, output)
+    }
+}
+
+/// flags are unsigned integers that must be 0 or 1
+impl FromEbmlField for bool {
+    fn from_field(bytes: &[u8]) -> Result<bool, EbmlError> {
+        match u64::from_field(bytes)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(EbmlError::CorruptPayload)
+        }
+    }
+}
+
+impl EncodeEbmlField for bool {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_integer(id, *self as u64, output)
+    }
+}
+
+/// accepts both String & UTF-8 elements, since ASCII is valid UTF-8
+impl FromEbmlField for String {
+    fn from_field(bytes: &[u8]) -> Result<String, EbmlError> {
+        decode_utf8(bytes).map(String::from)
+    }
+}
+
+impl EncodeEbmlField for String {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_utf8(id, self, output)
+    }
+}
+
+impl FromEbmlField for Vec<u8> {
+    fn from_field(bytes: &[u8]) -> Result<Vec<u8>, EbmlError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl EncodeEbmlField for Vec<u8> {
+    fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+        encode_bytes(id, self, output)
+    }
+}
+
+/// An element payload, decoded according to its type. Binary data borrows
+/// from the bytes it was decoded from, until made owned with `into_owned`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EbmlValue<'b> {
+    Uint(u64),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Utf8(String),
+    /// nanoseconds since 2001-01-01T00:00:00 UTC
+    Date(i64),
+    Binary(Cow<'b, [u8]>)
+}
+
+impl<'b> EbmlValue<'b> {
+    /// Decodes a payload, treating a master element's as binary data;
+    /// empty numbers & dates take their default value of 0, as derived fields do
+    pub fn decode(element_type: ElementType, bytes: &'b [u8]) -> Result<EbmlValue<'b>, EbmlError> {
+        Ok(match element_type {
+            ElementType::Master | ElementType::Binary => EbmlValue::Binary(Cow::Borrowed(bytes)),
+            ElementType::Uint => EbmlValue::Uint(u64::from_field(bytes)?),
+            ElementType::Int => EbmlValue::Int(i64::from_field(bytes)?),
+            ElementType::Float => EbmlValue::Float(f64::from_field(bytes)?),
+            ElementType::String => EbmlValue::String(decode_string(bytes)?.to_string()),
+            ElementType::Utf8 => EbmlValue::Utf8(String::from_field(bytes)?),
+            ElementType::Date if bytes.is_empty() => EbmlValue::Date(0),
+            ElementType::Date => EbmlValue::Date(decode_date(bytes)?)
+        })
+    }
+
+    /// Copies any borrowed data, so the value can outlive what it was decoded from
+    pub fn into_owned(self) -> EbmlValue<'static> {
+        match self {
+            EbmlValue::Uint(value) => EbmlValue::Uint(value),
+            EbmlValue::Int(value) => EbmlValue::Int(value),
+            EbmlValue::Float(value) => EbmlValue::Float(value),
+            EbmlValue::String(value) => EbmlValue::String(value),
+            EbmlValue::Utf8(value) => EbmlValue::Utf8(value),
+            EbmlValue::Date(value) => EbmlValue::Date(value),
+            EbmlValue::Binary(bytes) => EbmlValue::Binary(Cow::Owned(bytes.into_owned()))
+        }
+    }
+
+    /// Tries to write this value as an element with the given tag
+    pub fn encode<T: Write + ?Sized>(&self, tag: u64, output: &mut T) -> IoResult<()> {
+        match *self {
+            EbmlValue::Uint(value) => encode_integer(tag, value, output),
+            EbmlValue::Int(value) => encode_signed_integer(tag, value, output),
+            EbmlValue::Float(value) => encode_float(tag, value, output),
+            EbmlValue::String(ref value) => encode_string(tag, value, output),
+            EbmlValue::Utf8(ref value) => encode_utf8(tag, value, output),
+            EbmlValue::Date(value) => encode_date(tag, value, output),
+            EbmlValue::Binary(ref bytes) => encode_bytes(tag, bytes, output)
+        }
+    }
+}
+
+/// How deeply `EbmlNode::parse` will follow master elements; Matroska's
+/// deepest elements are only nested about 7 levels down
+pub const MAX_NODE_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EbmlContent {
+    Value(EbmlValue<'static>),
+    Children(Vec<EbmlNode>)
+}
+
+/// An owned element tree, which can be parsed, edited, and written back out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct EbmlNode {
+    /// the element ID, without its length marker
+    pub id: u64,
+    pub content: EbmlContent
+}
+
+impl EbmlNode {
+    pub fn master(id: u64, children: Vec<EbmlNode>) -> EbmlNode {
+        EbmlNode { id, content: EbmlContent::Children(children) }
+    }
+
+    pub fn value(id: u64, value: EbmlValue<'static>) -> EbmlNode {
+        EbmlNode { id, content: EbmlContent::Value(value) }
+    }
+
+    /// Parses a sequence of elements filling the given slice, using `schema` to give the
+    /// type of each element ID; unknown elements are kept as Binary.
+    /// Master elements must have a known size, and be nested at most `MAX_NODE_DEPTH` deep.
+    pub fn parse(bytes: &[u8], schema: &dyn Fn(u64) -> Option<ElementType>) -> Result<Vec<EbmlNode>, EbmlError> {
+        EbmlNode::parse_nested(bytes, schema, 0)
+    }
+
+    fn parse_nested(bytes: &[u8], schema: &dyn Fn(u64) -> Option<ElementType>, depth: usize) -> Result<Vec<EbmlNode>, EbmlError> {
+        if depth > MAX_NODE_DEPTH {
+            return Err(EbmlError::TooDeep);
+        }
+        let mut nodes = Vec::new();
+        for_each_child(bytes, |id, payload| {
+            nodes.push(match schema(id).unwrap_or(ElementType::Binary) {
+                ElementType::Master => EbmlNode::master(id, EbmlNode::parse_nested(payload, schema, depth + 1)?),
+                element_type => EbmlNode::value(id, EbmlValue::decode(element_type, payload)?.into_owned())
+            });
+            Ok(())
+        })?;
+        Ok(nodes)
+    }
+
+    /// The element's children; empty if it's not a master element
+    pub fn children(&self) -> &[EbmlNode] {
+        match self.content {
+            EbmlContent::Children(ref children) => children,
+            EbmlContent::Value(_) => &[]
+        }
+    }
+
+    /// The element's children, for inserting, removing or reordering;
+    /// None if it's not a master element
+    pub fn children_mut(&mut self) -> Option<&mut Vec<EbmlNode>> {
+        match self.content {
+            EbmlContent::Children(ref mut children) => Some(children),
+            EbmlContent::Value(_) => None
+        }
+    }
+
+    /// The element's value; None if it's a master element
+    pub fn get_value(&self) -> Option<&EbmlValue<'static>> {
+        match self.content {
+            EbmlContent::Value(ref value) => Some(value),
+            EbmlContent::Children(_) => None
+        }
+    }
+
+    /// Finds the first child with the given ID
+    pub fn child(&self, id: u64) -> Option<&EbmlNode> {
+        self.children().iter().find(|child| child.id == id)
+    }
+
+    pub fn child_mut(&mut self, id: u64) -> Option<&mut EbmlNode> {
+        self.children_mut()?.iter_mut().find(|child| child.id == id)
+    }
+
+    /// Sets the value of the first child with the given ID, adding it if absent
+    pub fn set_child_value(&mut self, id: u64, value: EbmlValue<'static>) {
+        if let Some(children) = self.children_mut() {
+            match children.iter_mut().find(|child| child.id == id) {
+                Some(child) => child.content = EbmlContent::Value(value),
+                None => children.push(EbmlNode::value(id, value))
+            }
+        }
+    }
+
+    /// Removes every child with the given ID, returning how many there were
+    pub fn remove_children(&mut self, id: u64) -> usize {
+        match self.children_mut() {
+            Some(children) => {
+                let before = children.len();
+                children.retain(|child| child.id != id);
+                before - children.len()
+            },
+            None => 0
+        }
+    }
+
+    /// Tries to write the element & its children, with their sizes filled in
+    pub fn encode<T: Write + ?Sized>(&self, output: &mut T) -> IoResult<()> {
+        match self.content {
+            EbmlContent::Value(ref value) => value.encode(self.id, output),
+            EbmlContent::Children(ref children) => {
+                encode_tag_header(self.id, Varint::Value(self.children_size()?), output)?;
+                children.iter().try_for_each(|child| child.encode(output))
+            }
+        }
+    }
+
+    /// The size of the element once encoded, including its tag header
+    pub fn encoded_size(&self) -> IoResult<u64> {
+        match self.content {
+            EbmlContent::Value(ref value) => {
+                let mut counter = ByteCounter::default();
+                value.encode(self.id, &mut counter)?;
+                Ok(counter.0)
+            },
+            EbmlContent::Children(_) => element_size(self.id, self.children_size()?)
+        }
+    }
+
+    fn children_size(&self) -> IoResult<u64> {
+        self.children().iter().map(EbmlNode::encoded_size).sum()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::{BytesMut};
@@ -267,6 +761,7 @@ mod tests {
     use crate::ebml::EbmlError::{CorruptVarint, UnknownElementId};
     use crate::ebml::Varint::{Unknown, Value};
     use std::io::Cursor;
+    use crate::schema::element_type;
     use crate::tests::TEST_FILE;
 
     #[test]
@@ -404,6 +899,458 @@ mod tests {
         assert_eq!(decode_uint(&[0x80,0,0,0,0,0,0,1]).unwrap(), 9223372036854775809);
     }
 
+    #[test]
+    fn parse_ints() {
+        assert!(matches!(decode_int(&[]), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_int(&[0; 9]), Err(EbmlError::CorruptPayload)));
+
+        assert_eq!(decode_int(&[0]).unwrap(), 0);
+        assert_eq!(decode_int(&[0x7F]).unwrap(), 127);
+        assert_eq!(decode_int(&[0x80]).unwrap(), -128);
+        assert_eq!(decode_int(&[0xFF]).unwrap(), -1);
+        assert_eq!(decode_int(&[0x00, 0x80]).unwrap(), 128);
+        assert_eq!(decode_int(&[0xFF, 0x7F]).unwrap(), -129);
+        assert_eq!(decode_int(&[0xFF; 8]).unwrap(), -1);
+        assert_eq!(decode_int(&[0x80,0,0,0,0,0,0,0]).unwrap(), i64::MIN);
+        assert_eq!(decode_int(&[0x7F,0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0xFF]).unwrap(), i64::MAX);
+    }
+
+    #[test]
+    fn parse_floats() {
+        for &len in [0, 1, 2, 3, 5, 6, 7, 9, 10].iter() {
+            assert!(matches!(decode_float(&vec![0; len]), Err(EbmlError::CorruptPayload)));
+        }
+
+        assert_eq!(decode_float(&[0, 0, 0, 0]).unwrap(), 0.0);
+        assert_eq!(decode_float(&[0x3F, 0xC0, 0x00, 0x00]).unwrap(), 1.5);
+        assert_eq!(decode_float(&[0xC7, 0x3B, 0x80, 0x00]).unwrap(), -48000.0);
+        assert_eq!(decode_float(&[0x40, 0xE7, 0x70, 0, 0, 0, 0, 0]).unwrap(), 48000.0);
+        assert_eq!(decode_float(&[0x3F, 0xB9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9A]).unwrap(), 0.1);
+        assert!(decode_float(&[0x7F, 0xC0, 0x00, 0x00]).unwrap().is_nan());
+    }
+
+    #[test]
+    fn parse_strings() {
+        assert_eq!(decode_string(b"").unwrap(), "");
+        assert_eq!(decode_string(b"webm").unwrap(), "webm");
+        assert_eq!(decode_string(b"V_VP8\0\0\0").unwrap(), "V_VP8");
+        assert_eq!(decode_string(b"\0\0").unwrap(), "");
+        assert_eq!(decode_string(b" ~").unwrap(), " ~");
+
+        // data after the padding, control characters & non-ASCII are rejected
+        assert!(matches!(decode_string(b"web\0m"), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_string(b"web\nm"), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_string(b"\x7F"), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_string("café".as_bytes()), Err(EbmlError::CorruptPayload)));
+    }
+
+    #[test]
+    fn parse_utf8() {
+        assert_eq!(decode_utf8(b"").unwrap(), "");
+        assert_eq!(decode_utf8("café".as_bytes()).unwrap(), "café");
+        assert_eq!(decode_utf8(b"caf\xC3\xA9\0\0").unwrap(), "café");
+        assert_eq!(decode_utf8(b"line\nbreak").unwrap(), "line\nbreak");
+
+        assert!(matches!(decode_utf8(b"caf\xC3"), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_utf8(b"\xFF"), Err(EbmlError::CorruptPayload)));
+        assert!(matches!(decode_utf8(b"a\0b"), Err(EbmlError::CorruptPayload)));
+    }
+
+    #[test]
+    fn parse_dates() {
+        for &len in [0, 1, 4, 7, 9].iter() {
+            assert!(matches!(decode_date(&vec![0; len]), Err(EbmlError::CorruptPayload)));
+        }
+
+        assert_eq!(decode_date(&[0; 8]).unwrap(), 0);
+        assert_eq!(decode_date(&[0, 0, 0, 0, 0x3B, 0x9A, 0xCA, 0x00]).unwrap(), 1_000_000_000);
+        assert_eq!(decode_date(&[0xFF, 0xFF, 0xFF, 0xFF, 0xC4, 0x65, 0x36, 0x00]).unwrap(), -1_000_000_000);
+    }
+
+    /// encodes an element with the given function, returning the written bytes
+    fn encoded<F: Fn(&mut Vec<u8>) -> IoResult<()>>(encode: F) -> Vec<u8> {
+        let mut output = Vec::new();
+        encode(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn encode_integers() {
+        assert_eq!(encoded(|out| encode_integer(0x67, 0, out)), &[0xE7, 0x81, 0x00]);
+        assert_eq!(encoded(|out| encode_integer(0x67, 255, out)), &[0xE7, 0x81, 0xFF]);
+        assert_eq!(encoded(|out| encode_integer(0x67, 256, out)), &[0xE7, 0x82, 0x01, 0x00]);
+        assert_eq!(encoded(|out| encode_integer(0x67, 1000, out)), &[0xE7, 0x82, 0x03, 0xE8]);
+        assert_eq!(encoded(|out| encode_integer(0x67, 0x01000000, out)), &[0xE7, 0x84, 0x01, 0, 0, 0]);
+        assert_eq!(encoded(|out| encode_integer(0x67, u64::MAX, out)),
+            &[0xE7, 0x88, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        for shift in 0..64 {
+            let value = 1u64 << shift;
+            let bytes = encoded(|out| encode_integer(0x67, value, out));
+            assert_eq!(bytes[1] as usize & 0x7F, shift / 8 + 1);
+            assert_eq!(decode_uint(&bytes[2..]).unwrap(), value);
+        }
+
+        assert_eq!(encoded(|out| encode_fixed_integer(0x67, 0, out)), &[0xE7, 0x88, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encoded(|out| encode_fixed_integer(0x67, 1000, out)), &[0xE7, 0x88, 0, 0, 0, 0, 0, 0, 0x03, 0xE8]);
+    }
+
+    #[test]
+    fn encode_signed_integers() {
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, 0, out)), &[0xFB, 0x81, 0x00]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, 127, out)), &[0xFB, 0x81, 0x7F]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, -1, out)), &[0xFB, 0x81, 0xFF]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, -128, out)), &[0xFB, 0x81, 0x80]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, 128, out)), &[0xFB, 0x82, 0x00, 0x80]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, -129, out)), &[0xFB, 0x82, 0xFF, 0x7F]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, i64::MAX, out)),
+            &[0xFB, 0x88, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(encoded(|out| encode_signed_integer(0x7B, i64::MIN, out)),
+            &[0xFB, 0x88, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+
+        // round trip across the byte boundaries
+        for &value in [0, 1, -1, 255, -256, 0x7FFF, -0x8000, 0x8000, 1 << 40, -(1 << 40)].iter() {
+            let bytes = encoded(|out| encode_signed_integer(0x7B, value, out));
+            assert_eq!(decode_int(&bytes[2..]).unwrap(), value);
+            assert_eq!(bytes[1] as usize & 0x7F, bytes.len() - 2);
+        }
+    }
+
+    #[test]
+    fn encode_floats() {
+        // exactly representable in single precision
+        assert_eq!(encoded(|out| encode_float(0x35, 48000.0, out)), &[0xB5, 0x84, 0x47, 0x3B, 0x80, 0x00]);
+        assert_eq!(encoded(|out| encode_float(0x35, 0.0, out)), &[0xB5, 0x84, 0, 0, 0, 0]);
+        // needs double precision
+        assert_eq!(encoded(|out| encode_float(0x35, 0.1, out)),
+            &[0xB5, 0x88, 0x3F, 0xB9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9A]);
+
+        for &value in [1.5, -2.25, 0.1, 44100.0, 1e300, f64::INFINITY].iter() {
+            let bytes = encoded(|out| encode_float(0x35, value, out));
+            assert_eq!(decode_float(&bytes[2..]).unwrap(), value);
+        }
+        let bytes = encoded(|out| encode_float(0x35, f64::NAN, out));
+        assert!(decode_float(&bytes[2..]).unwrap().is_nan());
+    }
+
+    #[test]
+    fn encode_strings() {
+        assert_eq!(encoded(|out| encode_string(0x0282, "webm", out)), b"\x42\x82\x84webm");
+        assert_eq!(encoded(|out| encode_string(0x0282, "", out)), b"\x42\x82\x80");
+        assert_eq!(encoded(|out| encode_utf8(0x136E, "café", out)), b"\x53\x6E\x85caf\xC3\xA9");
+
+        let mut output = Vec::new();
+        assert_eq!(encode_string(0x0282, "café", &mut output).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(encode_string(0x0282, "a\tb", &mut output).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn encode_dates() {
+        assert_eq!(encoded(|out| encode_date(0x0461, 0, out)), &[0x44, 0x61, 0x88, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        for &value in [1_000_000_000, -1_000_000_000, i64::MAX, i64::MIN].iter() {
+            let bytes = encoded(|out| encode_date(0x0461, value, out));
+            assert_eq!(bytes.len(), 11);
+            assert_eq!(decode_date(&bytes[3..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn encode_elements_without_seeking() {
+        // BytesMut can't seek
+        let mut buffer = BytesMut::with_capacity(16).writer();
+        encode_element(0x2E, &mut buffer, |output| {
+            encode_integer(0x57, 1, output)?;
+            encode_element(0x60, output, |output| encode_integer(0x30, 640, output))
+        }).unwrap();
+        assert_eq!(buffer.get_ref().as_ref(), &[0xAE, 0x89, 0xD7, 0x81, 0x01, 0xE0, 0x84, 0xB0, 0x82, 0x02, 0x80]);
+
+        // empty elements
+        let mut buffer = BytesMut::with_capacity(2).writer();
+        encode_element(0x2E, &mut buffer, |_| Ok(())).unwrap();
+        assert_eq!(buffer.get_ref().as_ref(), &[0xAE, 0x80]);
+    }
+
+    /// discards what's written to it, keeping only a prefix & the total length
+    #[derive(Default)]
+    struct Sink {
+        prefix: Vec<u8>,
+        length: u64
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, bytes: &[u8]) -> IoResult<usize> {
+            let wanted = 16usize.saturating_sub(self.prefix.len()).min(bytes.len());
+            self.prefix.extend_from_slice(&bytes[..wanted]);
+            self.length += bytes.len() as u64;
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encode_huge_elements() {
+        // bigger than a 4-byte size varint can hold
+        let megabyte = vec![0; 1 << 20];
+        let mut sink = Sink::default();
+        encode_element(0x0F43B675, &mut sink, |output| {
+            (0..300).try_for_each(|_| output.write_all(&megabyte))
+        }).unwrap();
+        assert_eq!(&sink.prefix[..9], &[0x1F, 0x43, 0xB6, 0x75, 0x08, 0x12, 0xC0, 0x00, 0x00]);
+        assert_eq!(sink.length, 4 + 5 + (300 << 20));
+    }
+
+    #[test]
+    fn inconsistent_element_content() {
+        let calls = std::cell::Cell::new(0);
+        let mut output = Vec::new();
+        let result = encode_element(0x2E, &mut output, |output| {
+            calls.set(calls.get() + 1);
+            encode_integer(0x57, calls.get() * 200, output)
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    const TRACK_ENTRY: u64 = 0x2E;
+    const TRACK_NUMBER: u64 = 0x57;
+    const CODEC_ID: u64 = 0x06;
+    const DEFAULT_DURATION: u64 = 0x03E383;
+    const NAME: u64 = 0x136E;
+
+    #[test]
+    fn parse_node_tree() {
+        let tracks = EbmlNode::parse(&TEST_FILE[358..421], &element_type).unwrap();
+        assert_eq!(tracks.len(), 1);
+
+        let entry = &tracks[0];
+        assert_eq!(entry.id, TRACK_ENTRY);
+        assert_eq!(entry.child(TRACK_NUMBER).unwrap().get_value(), Some(&EbmlValue::Uint(1)));
+        assert_eq!(entry.child(CODEC_ID).unwrap().get_value(), Some(&EbmlValue::String("V_VP9".into())));
+        assert!(entry.get_value().is_none());
+
+        // without a schema, everything is left as binary
+        let opaque = EbmlNode::parse(&TEST_FILE[358..421], &|_| None).unwrap();
+        assert_eq!(opaque, vec![EbmlNode::value(TRACK_ENTRY, EbmlValue::Binary(TEST_FILE[367..421].to_vec().into()))]);
+    }
+
+    #[test]
+    fn bad_node_trees() {
+        // truncated payload
+        assert!(matches!(EbmlNode::parse(&TEST_FILE[358..420], &element_type), Err(EbmlError::CorruptPayload)));
+        // unknown-size master
+        assert!(matches!(EbmlNode::parse(&[0xAE, 0xFF, 0xD7, 0x81, 0x01], &element_type), Err(EbmlError::UnknownElementLength)));
+        // invalid value
+        assert!(matches!(EbmlNode::parse(&[0x86, 0x81, 0x0A], &element_type), Err(EbmlError::CorruptPayload)));
+
+        // masters nested past the limit
+        let nest = |depth| (0..depth).fold(Vec::new(), |inner: Vec<u8>, _| {
+            let mut outer = vec![0xAE, 0x80 | inner.len() as u8];
+            outer.extend(inner);
+            outer
+        });
+        assert!(EbmlNode::parse(&nest(MAX_NODE_DEPTH), &element_type).is_ok());
+        assert!(matches!(EbmlNode::parse(&nest(MAX_NODE_DEPTH + 1), &element_type), Err(EbmlError::TooDeep)));
+    }
+
+    #[test]
+    fn edit_node_tree() {
+        let mut tracks = EbmlNode::parse(&TEST_FILE[358..421], &element_type).unwrap();
+        let entry = &mut tracks[0];
+
+        entry.set_child_value(CODEC_ID, EbmlValue::String("V_VP8".into()));
+        entry.set_child_value(NAME, EbmlValue::Utf8("Caméra".into()));
+        assert_eq!(entry.remove_children(DEFAULT_DURATION), 1);
+        assert_eq!(entry.remove_children(DEFAULT_DURATION), 0);
+        entry.children_mut().unwrap().insert(0, EbmlNode::value(0x33C5, EbmlValue::Uint(1234)));
+
+        let mut cursor = Cursor::new(Vec::new());
+        for node in tracks.iter() {
+            node.encode(&mut cursor).unwrap();
+        }
+        let reparsed = EbmlNode::parse(cursor.get_ref(), &element_type).unwrap();
+        assert_eq!(reparsed, tracks);
+        assert_eq!(tracks[0].encoded_size().unwrap(), cursor.get_ref().len() as u64);
+
+        let entry = &reparsed[0];
+        assert_eq!(entry.children()[0], EbmlNode::value(0x33C5, EbmlValue::Uint(1234)));
+        assert_eq!(entry.child(CODEC_ID).unwrap().get_value(), Some(&EbmlValue::String("V_VP8".into())));
+        assert_eq!(entry.child(NAME).unwrap().get_value(), Some(&EbmlValue::Utf8("Caméra".into())));
+        assert!(entry.child(DEFAULT_DURATION).is_none());
+    }
+
+    #[test]
+    fn encode_node_values() {
+        let mut cursor = Cursor::new(Vec::new());
+        EbmlNode::master(TRACK_ENTRY, vec![
+            EbmlNode::value(TRACK_NUMBER, EbmlValue::Uint(2)),
+            EbmlNode::value(0x23A2, EbmlValue::Binary(vec![1, 2, 3].into()))
+        ]).encode(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref(), &[0xAE, 0x89, 0xD7, 0x81, 0x02, 0x63, 0xA2, 0x83, 1, 2, 3]);
+
+        let mut output = Cursor::new(Vec::new());
+        let bad_string = EbmlNode::value(CODEC_ID, EbmlValue::String("é".into()));
+        assert_eq!(bad_string.encode(&mut output).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // values can't have children
+        let mut value = EbmlNode::value(TRACK_NUMBER, EbmlValue::Uint(1));
+        assert!(value.children().is_empty());
+        assert!(value.children_mut().is_none());
+        value.set_child_value(TRACK_NUMBER, EbmlValue::Uint(2));
+        assert_eq!(value, EbmlNode::value(TRACK_NUMBER, EbmlValue::Uint(1)));
+    }
+
+    #[derive(Debug, PartialEq, FromEbml, EbmlEncode)]
+    #[ebml(id = 0x0ABCDE)]
+    struct DerivedElement {
+        #[ebml(id = 0x01)]
+        required: u64,
+        #[ebml(id = 0x02, default = 7)]
+        defaulted: u64,
+        #[ebml(id = 0x03, default = "und")]
+        language: String,
+        #[ebml(id = 0x04)]
+        optional: Option<i64>,
+        #[ebml(id = 0x05)]
+        repeated: Vec<u64>,
+        #[ebml(id = 0x06, default)]
+        binary: Vec<u8>,
+        #[ebml(id = 0x07, default)]
+        flag: bool,
+        #[ebml(id = 0x08)]
+        children: Vec<DerivedChild>
+    }
+
+    #[derive(Debug, PartialEq, FromEbml, EbmlEncode)]
+    #[ebml(id = 0x08)]
+    struct DerivedChild {
+        #[ebml(id = 0x09)]
+        value: f64
+    }
+
+    #[test]
+    fn derived_defaults() {
+        // only the required child, plus an unrecognized one
+        let bytes = [0x81, 0x81, 0x2A, 0xC0, 0x81, 0x00];
+        assert_eq!(DerivedElement::decode(0x0ABCDE, &bytes).unwrap(), DerivedElement {
+            required: 42,
+            defaulted: 7,
+            language: "und".into(),
+            optional: None,
+            repeated: vec![],
+            binary: vec![],
+            flag: false,
+            children: vec![]
+        });
+
+        assert!(matches!(DerivedElement::decode(0x0ABCDE, &[0x82, 0x81, 0x01]), Err(EbmlError::MissingElement)));
+        assert!(matches!(DerivedElement::decode(0x0ABCDF, &bytes), Err(EbmlError::CorruptPayload)));
+        // invalid flag
+        assert!(matches!(DerivedElement::decode(0x0ABCDE, &[0x81, 0x81, 0x2A, 0x87, 0x81, 0x02]), Err(EbmlError::CorruptPayload)));
+    }
+
+    #[test]
+    fn derived_round_trip() {
+        let element = DerivedElement {
+            required: 1,
+            defaulted: 300,
+            language: "eng".into(),
+            optional: Some(-5),
+            repeated: vec![1, 2, 3],
+            binary: vec![0xFF, 0x00],
+            flag: true,
+            children: vec![DerivedChild { value: 1.5 }, DerivedChild { value: 0.1 }]
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        element.encode(&mut cursor).unwrap();
+        let (decoded, length) = DerivedElement::decode_element(cursor.get_ref()).unwrap().unwrap();
+        assert_eq!(length, cursor.get_ref().len());
+        assert_eq!(decoded, element);
+
+        // children are written in field order, repeated ones in sequence
+        let mut cursor = Cursor::new(Vec::new());
+        DerivedChild { value: 1.5 }.encode(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref(), &[0x88, 0x86, 0x89, 0x84, 0x3F, 0xC0, 0x00, 0x00]);
+    }
+
+    /// a child that counts how often it's encoded
+    #[derive(Default)]
+    struct CountedChild(std::cell::Cell<usize>);
+
+    impl EncodeEbmlField for CountedChild {
+        fn encode_field<T: Write + ?Sized>(&self, id: u64, output: &mut T) -> IoResult<()> {
+            self.0.set(self.0.get() + 1);
+            encode_integer(id, 1, output)
+        }
+    }
+
+    #[derive(EbmlEncode)]
+    #[ebml(id = 0x10)]
+    struct Innermost {
+        #[ebml(id = 0x11)]
+        counted: CountedChild
+    }
+
+    #[derive(EbmlEncode)]
+    #[ebml(id = 0x12)]
+    struct Middle {
+        #[ebml(id = 0x10)]
+        inner: Innermost
+    }
+
+    #[derive(EbmlEncode)]
+    #[ebml(id = 0x13)]
+    struct Outermost {
+        #[ebml(id = 0x12)]
+        middle: Middle
+    }
+
+    #[test]
+    fn derived_nesting_is_measured_once_per_level() {
+        let element = Outermost { middle: Middle { inner: Innermost { counted: CountedChild::default() } } };
+        let mut cursor = Cursor::new(Vec::new());
+        element.encode(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref(), &[0x93, 0x87, 0x92, 0x85, 0x90, 0x83, 0x91, 0x81, 0x01][..]);
+        // measured for each enclosing master, then written, rather than 2^3 times
+        assert_eq!(element.middle.inner.counted.0.get(), 4);
+    }
+
+    /// fields sharing names with the generated code's parameters & locals
+    #[derive(Debug, PartialEq, FromEbml, EbmlEncode)]
+    #[ebml(id = 0x0A)]
+    struct ShadowingElement {
+        #[ebml(id = 0x01)]
+        id: u64,
+        #[ebml(id = 0x02, default)]
+        bytes: Vec<u8>,
+        #[ebml(id = 0x03)]
+        payload: Option<String>,
+        #[ebml(id = 0x04)]
+        child_id: Vec<u64>,
+        #[ebml(id = 0x05, default)]
+        output: bool
+    }
+
+    #[test]
+    fn derived_field_names() {
+        let element = ShadowingElement {
+            id: 5,
+            bytes: vec![1, 2],
+            payload: Some("x".into()),
+            child_id: vec![3, 4],
+            output: true
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        element.encode(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref()[..5], [0x8A, 0x93, 0x81, 0x81, 0x05]);
+        assert_eq!(ShadowingElement::decode(0x0A, &cursor.get_ref()[2..]).unwrap(), element);
+    }
+
     #[derive(Debug, PartialEq)]
     struct GenericElement(u64, usize);
 
@@ -8,6 +8,7 @@ custom_error!{pub WebmetroError
     HyperError{source: hyper::Error} = "Hyper error: {source}",
     Hyper13Error{source: hyper13::Error} = "Hyper error: {source}",
     IoError{source: std::io::Error} = "IO error: {source}",
+    TlsError{source: native_tls::Error} = "TLS error: {source}",
     WarpError{source: warp::Error} = "Warp error: {source}",
     ApplicationError{message: String} = "{message}"
 }
@@ -1,3 +1,6 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::Cursor;
 use std::pin::Pin;
 use std::task::{
     Context,
@@ -5,19 +8,56 @@ use std::task::{
 };
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use futures3::prelude::*;
 use tokio2::timer::{
     delay,
     Delay
 };
 
-use crate::chunk::Chunk;
+use crate::chunk::{Chunk, ClusterHead};
 use crate::error::WebmetroError;
+use crate::webm::{
+    encode_webm_element,
+    parse_header_tracks,
+    parse_webm,
+    TrackEntry,
+    WebmElement
+};
+
+/// Frame duration to assume when nothing better is known (30fps)
+const FALLBACK_FRAME_DURATION: u64 = 33;
+/// How many gaps between blocks to consider when learning a track's frame duration
+const LEARNED_GAP_WINDOW: usize = 15;
 
+/// Rewrites cluster timecodes so they're monotonic, even when a new stream is
+/// spliced in whose timecodes start over. The new stream is placed one frame
+/// after the last observed block, using the frame duration given by
+/// `with_frame_duration`, else the largest DefaultDuration in the Tracks header,
+/// else the typical gap between blocks learned from the stream itself.
+///
+/// If `with_max_gap` is set, timecodes that jump forward by more than that
+/// are pulled back the same way, so players don't stall waiting for the gap.
+///
+/// If `with_track_repair` is set, block timecodes are then passed through
+/// a `TrackTimecodeFixer`.
+#[derive(Clone)]
 pub struct ChunkTimecodeFixer {
-    current_offset: u64,
+    current_offset: i64,
     last_observed_timecode: u64,
-    assumed_duration: u64
+    seen_cluster: bool,
+    max_gap: Option<u64>,
+    discontinuities: u64,
+    duration_override: Option<u64>,
+    /// frame duration from the current stream's headers
+    default_duration: Option<u64>,
+    /// headers of a new stream, to be applied once the splice is handled
+    next_tracks: Option<Vec<TrackEntry>>,
+    /// the (unadjusted) timecode of the cluster being processed
+    cluster_start: u64,
+    /// per track: the last block's timecode and the most recent gaps between blocks
+    block_timing: HashMap<u64, (u64, VecDeque<u64>)>,
+    track_fixer: Option<TrackTimecodeFixer>
 }
 
 impl ChunkTimecodeFixer {
@@ -25,27 +65,302 @@ impl ChunkTimecodeFixer {
         ChunkTimecodeFixer {
             current_offset: 0,
             last_observed_timecode: 0,
-            assumed_duration: 33
+            seen_cluster: false,
+            max_gap: None,
+            discontinuities: 0,
+            duration_override: None,
+            default_duration: None,
+            next_tracks: None,
+            cluster_start: 0,
+            block_timing: HashMap::new(),
+            track_fixer: None
         }
     }
-    pub fn process<'a>(&mut self, mut chunk: Chunk) -> Chunk {
+
+    /// always assume frames last this many milliseconds when splicing streams
+    pub fn with_frame_duration(mut self, duration: u64) -> Self {
+        self.duration_override = Some(duration);
+        self
+    }
+
+    /// treat a forward jump of more than this many milliseconds between
+    /// clusters as a discontinuity, and close the gap
+    pub fn with_max_gap(mut self, max_gap: u64) -> Self {
+        self.max_gap = Some(max_gap);
+        self
+    }
+
+    /// also repair block timecodes within clusters; see `TrackTimecodeFixer`
+    pub fn with_track_repair(mut self) -> Self {
+        self.track_fixer = Some(TrackTimecodeFixer::new());
+        self
+    }
+
+    /// how many timecode discontinuities (backwards or forwards) have been repaired
+    pub fn discontinuities_repaired(&self) -> u64 {
+        self.discontinuities
+    }
+
+    /// how many block timecodes have been rewritten by `with_track_repair`
+    pub fn repaired_blocks(&self) -> u64 {
+        self.track_fixer.as_ref().map_or(0, TrackTimecodeFixer::repaired_blocks)
+    }
+
+    /// the duration, in milliseconds, assumed for the last frame before a splice
+    pub fn frame_duration(&self) -> u64 {
+        self.duration_override
+            .or(self.default_duration)
+            .or_else(|| self.block_timing.values().filter_map(|(_, gaps)| median(gaps)).max())
+            .unwrap_or(FALLBACK_FRAME_DURATION)
+    }
+
+    /// Returns the chunks to pass on; this is always just the given chunk,
+    /// unless track repair holds a ClusterHead back (see `TrackTimecodeFixer::process`)
+    pub fn process(&mut self, mut chunk: Chunk) -> Vec<Chunk> {
         match chunk {
+            Chunk::Headers {ref bytes} => {
+                self.next_tracks = Some(parse_header_tracks(bytes));
+            }
             Chunk::ClusterHead(ref mut cluster_head) => {
                 let start = cluster_head.start;
-                if start < self.last_observed_timecode {
-                    let next_timecode = self.last_observed_timecode + self.assumed_duration;
-                    self.current_offset = next_timecode - start;
+                let adjusted_start = (start as i64 + self.current_offset).max(0) as u64;
+                let last_observed = self.last_observed_timecode;
+
+                let jumped_back = adjusted_start < last_observed;
+                let jumped_forward = self.seen_cluster && self.max_gap
+                    .is_some_and(|max_gap| adjusted_start > last_observed + max_gap);
+
+                if jumped_back || jumped_forward {
+                    let next_timecode = last_observed + self.frame_duration();
+                    self.current_offset = next_timecode as i64 - start as i64;
+                    self.discontinuities += 1;
+
+                    if jumped_forward {
+                        info!("Closed forward timecode jump of {}ms at {}ms (discontinuity #{})",
+                            adjusted_start - last_observed, last_observed, self.discontinuities);
+                    } else {
+                        info!("Corrected backward timecode jump of {}ms at {}ms (discontinuity #{})",
+                            last_observed - adjusted_start, last_observed, self.discontinuities);
+                    }
+                }
+                self.seen_cluster = true;
+
+                if let Some(tracks) = self.next_tracks.take() {
+                    // calibrate against the new stream from now on
+                    self.default_duration = tracks.iter()
+                        .filter_map(|track| track.default_duration)
+                        .map(|nanoseconds| (nanoseconds + 500_000) / 1_000_000)
+                        .max();
+                    self.block_timing.clear();
                 }
 
-                cluster_head.update_timecode(start + self.current_offset);
+                self.cluster_start = start;
+                cluster_head.update_timecode((start as i64 + self.current_offset) as u64);
                 self.last_observed_timecode = cluster_head.end;
             }
-            _ => {}
+            Chunk::ClusterBody {ref bytes} => {
+                self.observe_blocks(bytes);
+            }
+            Chunk::ClusterBodyPart {ref bytes, ref mut timecode} => {
+                self.observe_blocks(bytes);
+                // when streaming partial clusters, the ClusterHead can't know where the cluster ends
+                *timecode = (*timecode as i64 + self.current_offset).max(0) as u64;
+                self.last_observed_timecode = self.last_observed_timecode.max(*timecode);
+            }
+        }
+
+        let chunks = match self.track_fixer {
+            Some(ref mut track_fixer) => track_fixer.process(chunk),
+            None => return vec![chunk]
+        };
+        // repaired blocks may have moved past where the cluster was thought to end
+        for chunk in &chunks {
+            match chunk {
+                Chunk::ClusterHead(cluster_head) => {
+                    self.last_observed_timecode = self.last_observed_timecode.max(cluster_head.end);
+                },
+                Chunk::ClusterBodyPart {timecode, ..} => {
+                    self.last_observed_timecode = self.last_observed_timecode.max(*timecode);
+                },
+                _ => {}
+            }
+        }
+        chunks
+    }
+
+    fn observe_blocks(&mut self, bytes: &[u8]) {
+        if self.duration_override.is_some() || self.default_duration.is_some() {
+            // no need to learn anything
+            return;
+        }
+
+        for element in parse_webm(bytes) {
+            if let WebmElement::SimpleBlock(block) = element {
+                let timecode = (self.cluster_start as i64 + block.timecode as i64).max(0) as u64;
+                let (last_timecode, gaps) = self.block_timing.entry(block.track)
+                    .or_insert_with(|| (timecode, VecDeque::new()));
+                if timecode > *last_timecode {
+                    if gaps.len() == LEARNED_GAP_WINDOW {
+                        gaps.pop_front();
+                    }
+                    gaps.push_back(timecode - *last_timecode);
+                }
+                *last_timecode = timecode;
+            }
         }
-        chunk
     }
 }
 
+/// Rewrites SimpleBlock timecodes so that each track's timecodes strictly increase,
+/// and so that when a new stream is spliced in, its earliest block starts at the
+/// splice point. Every track of the new stream is moved by the same amount, so
+/// the tracks keep their sync with each other.
+///
+/// Expects cluster timecodes to already be monotonic, so it belongs after a
+/// `ChunkTimecodeFixer`. Not suitable for codecs with B-frames, whose blocks
+/// legitimately have out-of-order timecodes.
+#[derive(Clone, Default)]
+pub struct TrackTimecodeFixer {
+    seen_header: bool,
+    awaiting_splice: bool,
+    /// set after a splice until the new stream's first blocks are seen
+    splice_point: Option<u64>,
+    /// how far the current stream's blocks are moved
+    offset: i64,
+    last_timecodes: HashMap<u64, u64>,
+    /// the head of the current cluster, held back until its end is known
+    cluster_head: Option<ClusterHead>,
+    cluster_start: u64,
+    repaired_blocks: u64
+}
+
+impl TrackTimecodeFixer {
+    pub fn new() -> TrackTimecodeFixer {
+        TrackTimecodeFixer::default()
+    }
+
+    /// how many blocks have had their timecodes changed
+    pub fn repaired_blocks(&self) -> u64 {
+        self.repaired_blocks
+    }
+
+    /// Returns the chunks to pass on. Each ClusterHead is held back until the
+    /// cluster's (first) body has been repaired, so its end covers the blocks' new timecodes.
+    pub fn process(&mut self, mut chunk: Chunk) -> Vec<Chunk> {
+        let mut output = Vec::new();
+        match chunk {
+            Chunk::Headers {..} => {
+                output.extend(self.cluster_head.take().map(Chunk::ClusterHead));
+                if self.seen_header {
+                    self.awaiting_splice = true;
+                }
+                self.seen_header = true;
+            }
+            Chunk::ClusterHead(cluster_head) => {
+                output.extend(self.cluster_head.take().map(Chunk::ClusterHead));
+                self.cluster_start = cluster_head.start;
+                if self.awaiting_splice {
+                    self.awaiting_splice = false;
+                    self.splice_point = Some(cluster_head.start);
+                }
+                self.cluster_head = Some(cluster_head);
+                return output;
+            }
+            Chunk::ClusterBody {ref mut bytes} => {
+                self.fix_blocks(bytes);
+            }
+            Chunk::ClusterBodyPart {ref mut bytes, ref mut timecode} => {
+                if let Some(last_timecode) = self.fix_blocks(bytes) {
+                    *timecode = last_timecode;
+                }
+            }
+        }
+        output.extend(self.cluster_head.take().map(Chunk::ClusterHead));
+        output.push(chunk);
+        output
+    }
+
+    /// rewrites the blocks in a cluster body as needed, returning the last block's new timecode
+    fn fix_blocks(&mut self, bytes: &mut Bytes) -> Option<u64> {
+        let cluster_start = self.cluster_start as i64;
+        if let Some(cluster_head) = self.cluster_head.as_mut() {
+            cluster_head.end = cluster_head.start;
+        }
+        if let Some(splice_point) = self.splice_point {
+            // the new stream's earliest block lines up with the splice point
+            let earliest = parse_webm(bytes.as_ref()).filter_map(|element| match element {
+                WebmElement::SimpleBlock(block) => Some(cluster_start + block.timecode as i64),
+                _ => None
+            }).min();
+            if let Some(earliest) = earliest {
+                self.offset = splice_point as i64 - earliest;
+                self.splice_point = None;
+            }
+        }
+
+        let mut changed = false;
+        let mut last_timecode = None;
+        let mut buffer = Cursor::new(Vec::new());
+
+        for element in parse_webm(bytes) {
+            let element = match element {
+                WebmElement::SimpleBlock(mut block) => {
+                    let timecode = cluster_start + block.timecode as i64;
+                    let fixed_timecode = self.fix_timecode(block.track, timecode);
+
+                    match i16::try_from(fixed_timecode as i64 - cluster_start) {
+                        Ok(relative_timecode) if relative_timecode != block.timecode => {
+                            block.timecode = relative_timecode;
+                            self.repaired_blocks += 1;
+                            changed = true;
+                        },
+                        Ok(_) => {},
+                        Err(_) => warn!("Can't move block on track {} to {}ms, outside its cluster's range", block.track, fixed_timecode)
+                    }
+
+                    let applied_timecode = (cluster_start + block.timecode as i64).max(0) as u64;
+                    self.last_timecodes.insert(block.track, applied_timecode);
+                    if let Some(cluster_head) = self.cluster_head.as_mut() {
+                        cluster_head.observe_simpleblock_timecode(block.timecode);
+                    }
+                    last_timecode = Some(applied_timecode);
+                    WebmElement::SimpleBlock(block)
+                },
+                other => other
+            };
+
+            if let Err(err) = encode_webm_element(element, &mut buffer) {
+                warn!("Can't re-encode cluster body, passing it through unchanged: {}", err);
+                return last_timecode;
+            }
+        }
+
+        if changed {
+            *bytes = Bytes::from(buffer.into_inner());
+        }
+        last_timecode
+    }
+
+    /// where a block should move to, keeping its track's timecodes increasing
+    fn fix_timecode(&self, track: u64, timecode: i64) -> u64 {
+        let mut fixed_timecode = (timecode + self.offset).max(0) as u64;
+
+        if let Some(&last_timecode) = self.last_timecodes.get(&track) {
+            if fixed_timecode <= last_timecode {
+                debug!("Moving block on track {} from {}ms to {}ms to keep timecodes increasing", track, fixed_timecode, last_timecode + 1);
+                fixed_timecode = last_timecode + 1;
+            }
+        }
+        fixed_timecode
+    }
+}
+
+fn median(values: &VecDeque<u64>) -> Option<u64> {
+    let mut sorted: Vec<u64> = values.iter().cloned().collect();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).cloned()
+}
+
 pub struct StartingPointFinder<S> {
     stream: S,
     seen_header: bool,
@@ -70,7 +385,7 @@ impl<S: TryStream<Ok = Chunk> + Unpin> Stream for StartingPointFinder<S>
                         continue;
                     }
                 },
-                chunk @ Poll::Ready(Some(Ok(Chunk::ClusterBody {..}))) => {
+                chunk @ Poll::Ready(Some(Ok(Chunk::ClusterBody {..} | Chunk::ClusterBodyPart {..}))) => {
                     if self.seen_keyframe {
                         chunk
                     } else {
@@ -93,41 +408,463 @@ impl<S: TryStream<Ok = Chunk> + Unpin> Stream for StartingPointFinder<S>
     }
 }
 
-pub struct Throttle<S> {
+/// Queues chunks on their way to a connection that may drop, so the stream can
+/// be resumed over a new one. While disconnected, only the chunks since the
+/// latest keyframe cluster are kept; a new connection is sent the latest header
+/// chunk and then picks up from that keyframe cluster.
+pub struct ResumeBuffer {
+    header: Option<Chunk>,
+    queue: VecDeque<Chunk>,
+    queued_size: usize,
+    size_limit: usize,
+    /// whether queued chunks can follow on directly from what was last sent
+    in_sync: bool,
+    needs_header: bool
+}
+
+impl ResumeBuffer {
+    /// `size_limit` caps how many bytes to hold while disconnected; if a
+    /// keyframe cluster grows past it, it's dropped to wait for the next one.
+    pub fn new(size_limit: usize) -> ResumeBuffer {
+        ResumeBuffer {
+            header: None,
+            queue: VecDeque::new(),
+            queued_size: 0,
+            size_limit,
+            in_sync: true,
+            needs_header: false
+        }
+    }
+
+    pub fn push(&mut self, chunk: Chunk) {
+        if self.in_sync {
+            self.queue_chunk(chunk);
+            return;
+        }
+
+        match chunk {
+            Chunk::Headers {..} => {
+                // a new stream is starting, so wait for one of its keyframes
+                self.header = Some(chunk);
+                self.clear();
+            },
+            Chunk::ClusterHead(ref cluster_head) if cluster_head.keyframe => {
+                self.clear();
+                self.queue_chunk(chunk);
+            },
+            _ if self.queue.is_empty() => {},
+            _ => {
+                self.queue_chunk(chunk);
+                if self.queued_size > self.size_limit {
+                    warn!("Resume buffer exceeded {} bytes, waiting for the next keyframe", self.size_limit);
+                    self.clear();
+                }
+            }
+        }
+    }
+
+    /// The next chunk that's safe to send, if any
+    pub fn pop(&mut self) -> Option<Chunk> {
+        if self.needs_header {
+            if self.queue.is_empty() {
+                return None;
+            }
+            self.needs_header = false;
+            self.in_sync = true;
+            if let Some(ref header) = self.header {
+                return Some(header.clone());
+            }
+        }
+
+        let chunk = self.queue.pop_front()?;
+        self.queued_size -= chunk.as_ref().len();
+        if let Chunk::Headers {..} = chunk {
+            self.header = Some(chunk.clone());
+        }
+        Some(chunk)
+    }
+
+    /// Notes that the connection was lost, along with anything sent but not
+    /// yet received; the next chunks popped will restart the stream.
+    pub fn disconnect(&mut self) {
+        self.in_sync = false;
+        self.needs_header = true;
+        self.clear();
+    }
+
+    fn queue_chunk(&mut self, chunk: Chunk) {
+        self.queued_size += chunk.as_ref().len();
+        self.queue.push_back(chunk);
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.queued_size = 0;
+    }
+}
+
+/// A source of time for `Throttle`, so it can be driven by a fake clock in tests
+pub trait Clock {
+    type Sleep: Future<Output = ()> + Unpin;
+
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant) -> Self::Sleep;
+}
+
+/// The real clock, using Tokio's timer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    type Sleep = Delay;
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn sleep_until(&self, deadline: Instant) -> Delay {
+        delay(deadline)
+    }
+}
+
+/// Slows a chunk stream down to "real time" as determined by its timecodes,
+/// measured from the first cluster. By default each ClusterHead is released
+/// at its start time and its body once real time has caught up to its end.
+pub struct Throttle<S, C: Clock = TokioClock> {
     stream: S,
-    start_time: Instant,
-    sleep: Delay
+    clock: C,
+    speed: f64,
+    preroll: u64,
+    pace_blocks: bool,
+    /// when the first cluster was seen, and its timecode
+    start: Option<(Instant, u64)>,
+    cluster_start: u64,
+    cluster_end: u64,
+    /// chunks waiting to be released, with the timecode to release them at
+    pending: VecDeque<(Option<u64>, Chunk)>,
+    sleep: Option<(Instant, C::Sleep)>
 }
 
 impl<S> Throttle<S> {
     pub fn new(wrap: S) -> Throttle<S> {
-        let now = Instant::now();
+        Throttle::with_clock(wrap, TokioClock)
+    }
+}
+
+impl<S, C: Clock> Throttle<S, C> {
+    pub fn with_clock(wrap: S, clock: C) -> Throttle<S, C> {
         Throttle {
             stream: wrap,
-            start_time: now,
-            sleep: delay(now)
+            clock,
+            speed: 1.0,
+            preroll: 0,
+            pace_blocks: false,
+            start: None,
+            cluster_start: 0,
+            cluster_end: 0,
+            pending: VecDeque::new(),
+            sleep: None
         }
     }
+
+    /// play back this many times faster than real time
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// send the first this-many milliseconds of the stream immediately,
+    /// staying that far ahead of real time, so viewers can buffer
+    pub fn with_preroll(mut self, preroll: u64) -> Self {
+        self.preroll = preroll;
+        self
+    }
+
+    /// release each SimpleBlock at its own timecode, splitting cluster bodies
+    /// into `ClusterBodyPart`s, instead of releasing whole clusters at once
+    pub fn pace_blocks(mut self) -> Self {
+        self.pace_blocks = true;
+        self
+    }
+
+    fn enqueue(&mut self, chunk: Chunk) -> Result<(), WebmetroError> {
+        match chunk {
+            Chunk::Headers {..} => self.pending.push_back((None, chunk)),
+            Chunk::ClusterHead(ref cluster_head) => {
+                if self.start.is_none() {
+                    self.start = Some((self.clock.now(), cluster_head.start));
+                }
+                self.cluster_start = cluster_head.start;
+                self.cluster_end = cluster_head.end;
+                self.pending.push_back((Some(self.cluster_start), chunk));
+            },
+            Chunk::ClusterBody {ref bytes} if self.pace_blocks => {
+                for (timecode, part) in split_cluster_body(bytes, self.cluster_start)? {
+                    self.pending.push_back((Some(timecode), part));
+                }
+            },
+            Chunk::ClusterBody {..} => {
+                let end = self.cluster_end;
+                self.pending.push_back((Some(end), chunk));
+            },
+            Chunk::ClusterBodyPart {timecode, ..} => self.pending.push_back((Some(timecode), chunk))
+        }
+        Ok(())
+    }
+
+    /// when the chunk at the given timecode should be released
+    fn deadline(&self, timecode: u64) -> Option<Instant> {
+        self.start.map(|(start_time, start_timecode)| {
+            let stream_time = timecode.saturating_sub(start_timecode).saturating_sub(self.preroll);
+            start_time + Duration::from_secs_f64(stream_time as f64 / 1000.0 / self.speed)
+        })
+    }
+}
+
+/// Splits a cluster body into one part per SimpleBlock, with each block's timecode
+fn split_cluster_body(bytes: &Bytes, cluster_start: u64) -> Result<Vec<(u64, Chunk)>, WebmetroError> {
+    let mut parts = Vec::new();
+    let mut timecode = cluster_start;
+
+    for element in parse_webm(bytes) {
+        if let WebmElement::SimpleBlock(block) = element {
+            timecode = (cluster_start as i64 + block.timecode as i64).max(0) as u64;
+        }
+
+        let mut buffer = Cursor::new(Vec::new());
+        encode_webm_element(element, &mut buffer)?;
+        parts.push((timecode, Chunk::ClusterBodyPart {
+            bytes: Bytes::from(buffer.into_inner()),
+            timecode
+        }));
+    }
+
+    Ok(parts)
 }
 
-impl<S: TryStream<Ok = Chunk, Error = WebmetroError> + Unpin> Stream for Throttle<S>
+impl<S: TryStream<Ok = Chunk, Error = WebmetroError> + Unpin, C: Clock + Unpin> Stream for Throttle<S, C>
 {
     type Item = Result<Chunk, WebmetroError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
-        match self.sleep.poll_unpin(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(()) => { /* can continue */ },
+        let throttle = &mut *self;
+        loop {
+            if let Some(timecode) = throttle.pending.front().map(|(timecode, _)| *timecode) {
+                if let Some(deadline) = timecode.and_then(|timecode| throttle.deadline(timecode)) {
+                    if throttle.clock.now() < deadline {
+                        // snooze until real time has "caught up" to the stream
+                        let clock = &throttle.clock;
+                        match throttle.sleep {
+                            Some((sleep_deadline, _)) if sleep_deadline == deadline => {},
+                            _ => throttle.sleep = Some((deadline, clock.sleep_until(deadline)))
+                        }
+                        if let Some((_, ref mut sleep)) = throttle.sleep {
+                            if sleep.poll_unpin(cx).is_pending() {
+                                return Poll::Pending;
+                            }
+                        }
+                    }
+                }
+
+                throttle.sleep = None;
+                let (_, chunk) = throttle.pending.pop_front().expect("Checked pending chunk exists");
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+
+            match throttle.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if let Err(err) = throttle.enqueue(chunk) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                },
+                other => return other
+            }
+        }
+    }
+}
+
+/// Cuts a time range out of a chunk stream. Output begins at the latest keyframe
+/// cluster at or before the start time, blocks at or after the end time are dropped,
+/// and cluster timecodes are rebased so the output starts at zero.
+pub struct RangeClipper<S> {
+    stream: S,
+    start: u64,
+    end: Option<u64>,
+    /// the most recent header, held back until output begins
+    header: Option<Chunk>,
+    /// clusters since the last keyframe cluster, held back until the start time is reached
+    buffer: Vec<Chunk>,
+    pending: VecDeque<Chunk>,
+    started: bool,
+    keeping_cluster: bool,
+    /// the original timecode of the cluster currently being emitted
+    cluster_start: u64,
+    rebase_offset: Option<u64>,
+    finished: bool
+}
+
+impl<S> RangeClipper<S> {
+    pub fn new(wrap: S, start: u64, end: Option<u64>) -> RangeClipper<S> {
+        RangeClipper {
+            stream: wrap,
+            start,
+            end,
+            header: None,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            started: false,
+            keeping_cluster: false,
+            cluster_start: 0,
+            rebase_offset: None,
+            finished: false
+        }
+    }
+
+    fn accept(&mut self, chunk: Chunk) -> Result<(), WebmetroError> {
+        match chunk {
+            Chunk::Headers {..} => {
+                if self.started {
+                    self.emit(chunk)?;
+                } else {
+                    // clusters from a previous stream can't be played with the new header
+                    self.header = Some(chunk);
+                    self.buffer.clear();
+                }
+            },
+            Chunk::ClusterHead(ref cluster_head) => {
+                if let Some(end) = self.end {
+                    if cluster_head.start >= end {
+                        return self.finish();
+                    }
+                }
+
+                if self.started {
+                    self.keeping_cluster = true;
+                    self.emit(chunk)?;
+                } else if cluster_head.start <= self.start {
+                    if cluster_head.keyframe {
+                        self.buffer.clear();
+                    }
+                    self.keeping_cluster = !self.buffer.is_empty() || cluster_head.keyframe;
+                    if self.keeping_cluster {
+                        self.buffer.push(chunk);
+                    }
+                } else if !self.buffer.is_empty() || cluster_head.keyframe {
+                    self.flush_buffer()?;
+                    self.keeping_cluster = true;
+                    self.emit(chunk)?;
+                } else {
+                    self.keeping_cluster = false;
+                }
+            },
+            Chunk::ClusterBody {..} | Chunk::ClusterBodyPart {..} => {
+                if !self.keeping_cluster {
+                    // no keyframe to start from yet
+                } else if self.started {
+                    self.emit(chunk)?;
+                } else {
+                    self.buffer.push(chunk);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// stops taking input; if the start time was never passed,
+    /// output the last keyframe cluster(s) we have
+    fn finish(&mut self) -> Result<(), WebmetroError> {
+        self.finished = true;
+        if !self.started && !self.buffer.is_empty() {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+
+    fn flush_buffer(&mut self) -> Result<(), WebmetroError> {
+        self.started = true;
+        if let Some(header) = self.header.take() {
+            self.emit(header)?;
+        }
+        for chunk in std::mem::take(&mut self.buffer) {
+            self.emit(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, mut chunk: Chunk) -> Result<(), WebmetroError> {
+        match chunk {
+            Chunk::ClusterHead(ref mut cluster_head) => {
+                let start = cluster_head.start;
+                let offset = *self.rebase_offset.get_or_insert(start);
+                self.cluster_start = start;
+                cluster_head.update_timecode(start.saturating_sub(offset));
+            },
+            Chunk::ClusterBody {ref mut bytes} => {
+                if let Some(end) = self.end {
+                    *bytes = trim_cluster_body(bytes, self.cluster_start, end)?;
+                }
+            },
+            Chunk::ClusterBodyPart {ref mut bytes, ref mut timecode} => {
+                if let Some(end) = self.end {
+                    *bytes = trim_cluster_body(bytes, self.cluster_start, end)?;
+                    if bytes.is_empty() {
+                        return Ok(());
+                    }
+                }
+                *timecode = timecode.saturating_sub(self.rebase_offset.unwrap_or(0));
+            },
+            Chunk::Headers {..} => {}
         }
+        self.pending.push_back(chunk);
+        Ok(())
+    }
+}
+
+/// Re-encodes a cluster body without any SimpleBlocks at or past the end time
+fn trim_cluster_body(bytes: &Bytes, cluster_start: u64, end: u64) -> Result<Bytes, WebmetroError> {
+    let is_past_end = |element: &WebmElement| match element {
+        WebmElement::SimpleBlock(block) => cluster_start as i64 + block.timecode as i64 >= end as i64,
+        _ => false
+    };
+
+    if !parse_webm(bytes).any(|element| is_past_end(&element)) {
+        return Ok(bytes.clone());
+    }
+
+    let mut buffer = Cursor::new(Vec::new());
+    for element in parse_webm(bytes).filter(|element| !is_past_end(element)) {
+        encode_webm_element(element, &mut buffer)?;
+    }
+    Ok(Bytes::from(buffer.into_inner()))
+}
+
+impl<S: TryStream<Ok = Chunk, Error = WebmetroError> + Unpin> Stream for RangeClipper<S>
+{
+    type Item = Result<Chunk, WebmetroError>;
 
-        let next_chunk = self.stream.try_poll_next_unpin(cx);
-        if let Poll::Ready(Some(Ok(Chunk::ClusterHead(ref cluster_head)))) = next_chunk {
-            // snooze until real time has "caught up" to the stream
-            let offset = Duration::from_millis(cluster_head.end);
-            let sleep_until = self.start_time + offset;
-            self.sleep.reset(sleep_until);
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
+        loop {
+            if let Some(chunk) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            if self.finished {
+                return Poll::Ready(None);
+            }
+
+            match self.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if let Err(err) = self.accept(chunk) {
+                        self.finished = true;
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                },
+                Poll::Ready(None) => {
+                    if let Err(err) = self.finish() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                },
+                other => return other
+            }
         }
-        next_chunk
     }
 }
 
@@ -151,6 +888,642 @@ pub trait ChunkStream where Self : Sized + TryStream<Ok = Chunk> {
     fn throttle(self) -> Throttle<Self> {
         Throttle::new(self)
     }
+
+    fn clip_range(self, start: u64, end: Option<u64>) -> RangeClipper<Self> {
+        RangeClipper::new(self, start, end)
+    }
 }
 
 impl<T: TryStream<Ok = Chunk>> ChunkStream for T {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use futures3::{stream, task::noop_waker_ref, FutureExt, StreamExt, TryStreamExt};
+    use matches::assert_matches;
+
+    use crate::chunk::{Chunk, ClusterHead};
+    use crate::ebml::{encode_element, encode_integer};
+    use crate::error::WebmetroError;
+    use crate::fixers::*;
+    use crate::webm::*;
+
+    fn cluster(timecode: u64, keyframe: bool, block_timecodes: &[i16]) -> Vec<Chunk> {
+        let mut cluster_head = ClusterHead::new(timecode);
+        cluster_head.keyframe = keyframe;
+
+        let mut body = Cursor::new(Vec::new());
+        for &block_timecode in block_timecodes {
+            cluster_head.observe_simpleblock_timecode(block_timecode);
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                track: 1,
+                timecode: block_timecode,
+                flags: 0,
+                data: &[0; 4]
+            }), &mut body).unwrap();
+        }
+
+        vec![
+            Chunk::ClusterHead(cluster_head),
+            Chunk::ClusterBody {bytes: Bytes::from(body.into_inner())}
+        ]
+    }
+
+    fn headers() -> Chunk {
+        Chunk::Headers {bytes: Bytes::from(&b"headers"[..])}
+    }
+
+    fn run<S: ChunkStream + TryStream<Ok = Chunk, Error = WebmetroError> + Unpin>(stream: S) -> Vec<Chunk> {
+        stream.try_collect().now_or_never().expect("Test tried to block on I/O").unwrap()
+    }
+
+    fn source(chunks: Vec<Chunk>) -> impl TryStream<Ok = Chunk, Error = WebmetroError, Item = Result<Chunk, WebmetroError>> + Unpin {
+        stream::iter(chunks).map(Ok)
+    }
+
+    fn cluster_starts(chunks: &[Chunk]) -> Vec<u64> {
+        chunks.iter().filter_map(|chunk| match chunk {
+            Chunk::ClusterHead(cluster_head) => Some(cluster_head.start),
+            _ => None
+        }).collect()
+    }
+
+    fn block_timecodes(chunk: &Chunk) -> Vec<i16> {
+        parse_webm(chunk).filter_map(|element| match element {
+            WebmElement::SimpleBlock(block) => Some(block.timecode),
+            _ => None
+        }).collect()
+    }
+
+    fn test_stream() -> Vec<Chunk> {
+        let mut chunks = vec![headers()];
+        chunks.extend(cluster(0, true, &[0, 500]));
+        chunks.extend(cluster(1000, false, &[0, 500]));
+        chunks.extend(cluster(2000, true, &[0, 500]));
+        chunks.extend(cluster(3000, false, &[0, 500]));
+        chunks.extend(cluster(4000, false, &[0, 500]));
+        chunks
+    }
+
+    #[test]
+    fn clip_starts_at_previous_keyframe() {
+        let output = run(source(test_stream()).clip_range(3500, None));
+
+        assert_eq!(output.len(), 7);
+        assert_matches!(output[0], Chunk::Headers {..});
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000]);
+    }
+
+    #[test]
+    fn clip_start_on_keyframe() {
+        let output = run(source(test_stream()).clip_range(2000, None));
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000]);
+
+        let output = run(source(test_stream()).clip_range(0, None));
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000, 3000, 4000]);
+    }
+
+    #[test]
+    fn clip_drops_blocks_past_end() {
+        let output = run(source(test_stream()).clip_range(1500, Some(3200)));
+
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000, 3000]);
+        assert_eq!(block_timecodes(&output[2]), vec![0, 500]);
+        assert_eq!(block_timecodes(&output[8]), vec![0]);
+    }
+
+    #[test]
+    fn clip_end_within_first_cluster() {
+        let output = run(source(test_stream()).clip_range(0, Some(400)));
+
+        assert_eq!(cluster_starts(&output), vec![0]);
+        assert_eq!(block_timecodes(&output[2]), vec![0]);
+    }
+
+    #[test]
+    fn clip_spliced_input_without_fixer() {
+        // a second source's clusters restart below the first emitted cluster
+        let mut chunks = test_stream();
+        chunks.extend(cluster(500, true, &[0, 500]));
+        let output = run(source(chunks).clip_range(2000, None));
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000, 0]);
+    }
+
+    #[test]
+    fn clip_past_end_of_stream() {
+        let output = run(source(test_stream()).clip_range(10000, None));
+        assert_eq!(cluster_starts(&output), vec![0, 1000, 2000]);
+    }
+
+    fn partial_cluster(timecode: u64, keyframe: bool, block_timecodes: &[i16]) -> Vec<Chunk> {
+        let mut cluster_head = ClusterHead::new(timecode);
+        cluster_head.keyframe = keyframe;
+
+        let mut chunks = vec![Chunk::ClusterHead(cluster_head)];
+        for &block_timecode in block_timecodes {
+            let mut body = Cursor::new(Vec::new());
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                track: 1,
+                timecode: block_timecode,
+                flags: 0,
+                data: &[0; 4]
+            }), &mut body).unwrap();
+            chunks.push(Chunk::ClusterBodyPart {
+                bytes: Bytes::from(body.into_inner()),
+                timecode: timecode + block_timecode as u64
+            });
+        }
+        chunks
+    }
+
+    #[test]
+    fn starting_point_skips_partial_cluster() {
+        // a listener joining partway through a cluster
+        let mut chunks = vec![headers()];
+        chunks.extend(partial_cluster(0, true, &[0, 100, 200]).into_iter().skip(2));
+        chunks.extend(partial_cluster(300, false, &[0, 100]));
+        chunks.extend(partial_cluster(500, true, &[0, 100]));
+
+        let output = run(source(chunks).find_starting_point());
+        assert_eq!(output.len(), 4);
+        assert_matches!(output[0], Chunk::Headers {..});
+        assert_matches!(output[1], Chunk::ClusterHead(ClusterHead {start: 500, ..}));
+        assert_matches!(output[3], Chunk::ClusterBodyPart {timecode: 600, ..});
+    }
+
+    #[test]
+    fn fix_timecodes_of_partial_clusters() {
+        let mut chunks = partial_cluster(1000, true, &[0, 400, 800]);
+        chunks.extend(partial_cluster(0, true, &[0, 100]));
+
+        let mut fixer = ChunkTimecodeFixer::new().with_frame_duration(33);
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+
+        // the second stream starts after the last block of the first, not just its first block
+        assert_matches!(output[4], Chunk::ClusterHead(ClusterHead {start: 1833, ..}));
+        assert_matches!(output[6], Chunk::ClusterBodyPart {timecode: 1933, ..});
+    }
+
+    fn headers_with_duration(default_duration: Option<u64>) -> Chunk {
+        let mut track_entry = Cursor::new(Vec::new());
+        encode_element(0x2E, &mut track_entry, |output| {
+            encode_integer(0x57, 1, output)?;
+            encode_integer(0x03, 1, output)?;
+            if let Some(duration) = default_duration {
+                encode_integer(0x03E383, duration, output)?;
+            }
+            Ok(())
+        }).unwrap();
+
+        let mut header = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut header).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut header).unwrap();
+        encode_webm_element(WebmElement::Tracks(track_entry.get_ref()), &mut header).unwrap();
+        Chunk::Headers {bytes: Bytes::from(header.into_inner())}
+    }
+
+    /// 2 seconds of frames at the given rate, in clusters of 1 second
+    fn frames(frame_rate: u64) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        for cluster_start in &[0, 1000] {
+            let block_timecodes: Vec<i16> = (0..frame_rate)
+                .map(|frame| ((frame * 1000 + frame_rate / 2) / frame_rate) as i16)
+                .collect();
+            chunks.extend(cluster(*cluster_start, true, &block_timecodes));
+        }
+        chunks
+    }
+
+    /// the timecode of the first cluster of the second of two spliced streams
+    fn splice(mut fixer: ChunkTimecodeFixer, first: Vec<Chunk>, second: Vec<Chunk>) -> u64 {
+        let first_len = first.len();
+        let output: Vec<Chunk> = first.into_iter().chain(second)
+            .flat_map(|chunk| fixer.process(chunk))
+            .collect();
+        match output[first_len..].iter().find(|chunk| matches!(chunk, Chunk::ClusterHead(_))) {
+            Some(Chunk::ClusterHead(cluster_head)) => cluster_head.start,
+            _ => panic!("No cluster in second stream")
+        }
+    }
+
+    fn stream(header: Chunk, body: Vec<Chunk>) -> Vec<Chunk> {
+        let mut chunks = vec![header];
+        chunks.extend(body);
+        chunks
+    }
+
+    #[test]
+    fn splice_learns_frame_rate() {
+        for &(frame_rate, expected) in &[(25, 2000), (30, 2000), (50, 2000), (60, 2000)] {
+            let start = splice(ChunkTimecodeFixer::new(),
+                stream(headers(), frames(frame_rate)),
+                stream(headers(), frames(frame_rate)));
+            assert_eq!(start, expected, "at {}fps", frame_rate);
+        }
+    }
+
+    #[test]
+    fn splice_audio_only() {
+        // 20ms Opus frames
+        let audio: Vec<Chunk> = cluster(0, true, &(0..50).map(|frame| frame * 20).collect::<Vec<i16>>());
+        let start = splice(ChunkTimecodeFixer::new(),
+            stream(headers(), audio.clone()),
+            stream(headers(), audio));
+        assert_eq!(start, 1000);
+    }
+
+    #[test]
+    fn splice_uses_default_duration() {
+        // DefaultDuration wins over the (irregular) observed block timing
+        let start = splice(ChunkTimecodeFixer::new(),
+            stream(headers_with_duration(Some(40_000_000)), cluster(0, true, &[0, 10, 200, 210])),
+            stream(headers_with_duration(Some(20_000_000)), frames(50)));
+        assert_eq!(start, 250);
+    }
+
+    #[test]
+    fn splice_uses_frame_duration_override() {
+        let start = splice(ChunkTimecodeFixer::new().with_frame_duration(100),
+            stream(headers_with_duration(Some(40_000_000)), frames(25)),
+            stream(headers(), frames(25)));
+        assert_eq!(start, 2060);
+    }
+
+    #[test]
+    fn splice_without_timing_information() {
+        let start = splice(ChunkTimecodeFixer::new(),
+            stream(headers(), cluster(0, true, &[500])),
+            stream(headers(), cluster(0, true, &[0])));
+        assert_eq!(start, 533);
+    }
+
+    fn fix_cluster_starts(mut fixer: ChunkTimecodeFixer, chunks: Vec<Chunk>) -> (Vec<u64>, u64) {
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+        (cluster_starts(&output), fixer.discontinuities_repaired())
+    }
+
+    /// clusters of 1 second at 25fps, starting at the given times
+    fn clusters_at(starts: &[u64]) -> Vec<Chunk> {
+        let block_timecodes: Vec<i16> = (0..25).map(|frame| frame * 40).collect();
+        let mut chunks = vec![headers()];
+        for &start in starts {
+            chunks.extend(cluster(start, true, &block_timecodes));
+        }
+        chunks
+    }
+
+    #[test]
+    fn forward_jumps_ignored_by_default() {
+        let (starts, discontinuities) = fix_cluster_starts(ChunkTimecodeFixer::new(),
+            clusters_at(&[0, 1000, 3_600_000, 3_601_000]));
+        assert_eq!(starts, vec![0, 1000, 3_600_000, 3_601_000]);
+        assert_eq!(discontinuities, 0);
+    }
+
+    #[test]
+    fn close_forward_jump() {
+        let (starts, discontinuities) = fix_cluster_starts(ChunkTimecodeFixer::new().with_max_gap(5000),
+            clusters_at(&[0, 1000, 3_600_000, 3_601_000, 3_604_000]));
+        assert_eq!(starts, vec![0, 1000, 2000, 3000, 6000]);
+        assert_eq!(discontinuities, 1);
+    }
+
+    #[test]
+    fn forward_jump_from_first_cluster_is_kept() {
+        // joining a stream that's been running for an hour isn't a discontinuity
+        let (starts, discontinuities) = fix_cluster_starts(ChunkTimecodeFixer::new().with_max_gap(5000),
+            clusters_at(&[3_600_000, 3_601_000]));
+        assert_eq!(starts, vec![3_600_000, 3_601_000]);
+        assert_eq!(discontinuities, 0);
+    }
+
+    #[test]
+    fn count_backward_and_forward_jumps() {
+        let (starts, discontinuities) = fix_cluster_starts(ChunkTimecodeFixer::new().with_max_gap(5000),
+            clusters_at(&[0, 1000, 0, 1000, 60_000, 2000]));
+        assert_eq!(starts, vec![0, 1000, 2000, 3000, 4000, 5000]);
+        assert_eq!(discontinuities, 3);
+    }
+
+    #[test]
+    fn keep_natural_gaps_after_splice() {
+        // a stream with a 300ms gap between clusters, spliced after itself
+        let (starts, _) = fix_cluster_starts(ChunkTimecodeFixer::new(),
+            clusters_at(&[0, 1300, 0, 1300]));
+        assert_eq!(starts, vec![0, 1300, 2300, 3600]);
+    }
+
+    fn multitrack_cluster(timecode: u64, blocks: &[(u64, i16)]) -> Vec<Chunk> {
+        let mut cluster_head = ClusterHead::new(timecode);
+        cluster_head.keyframe = true;
+
+        let mut body = Cursor::new(Vec::new());
+        for &(track, block_timecode) in blocks {
+            cluster_head.observe_simpleblock_timecode(block_timecode);
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                track,
+                timecode: block_timecode,
+                flags: 0,
+                data: &[0; 4]
+            }), &mut body).unwrap();
+        }
+
+        vec![
+            Chunk::ClusterHead(cluster_head),
+            Chunk::ClusterBody {bytes: Bytes::from(body.into_inner())}
+        ]
+    }
+
+    /// (track, absolute timecode) of every block
+    fn absolute_blocks(chunks: &[Chunk]) -> Vec<(u64, u64)> {
+        let mut cluster_start = 0;
+        let mut blocks = Vec::new();
+        for chunk in chunks {
+            match chunk {
+                Chunk::ClusterHead(cluster_head) => cluster_start = cluster_head.start,
+                Chunk::ClusterBody {..} | Chunk::ClusterBodyPart {..} => {
+                    for element in parse_webm(chunk) {
+                        if let WebmElement::SimpleBlock(block) = element {
+                            blocks.push((block.track, (cluster_start as i64 + block.timecode as i64) as u64));
+                        }
+                    }
+                },
+                Chunk::Headers {..} => {}
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn track_timecodes_increase() {
+        let mut chunks = vec![headers()];
+        chunks.extend(multitrack_cluster(0, &[(1, 0), (2, 0), (1, 40), (2, 20), (1, 30), (2, 20), (1, 80)]));
+        chunks.extend(multitrack_cluster(100, &[(1, -30), (2, 0)]));
+
+        let mut fixer = TrackTimecodeFixer::new();
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+
+        assert_eq!(absolute_blocks(&output), vec![
+            (1, 0), (2, 0), (1, 40), (2, 20), (1, 41), (2, 21), (1, 80),
+            (1, 81), (2, 100)
+        ]);
+        assert_eq!(fixer.repaired_blocks(), 3);
+    }
+
+    #[test]
+    fn align_tracks_at_splice() {
+        // the second stream's video track starts 200ms after its audio track
+        let mut chunks = vec![headers()];
+        chunks.extend(multitrack_cluster(0, &[(1, 0), (2, 0), (2, 20), (1, 40), (2, 40)]));
+        chunks.push(headers());
+        chunks.extend(multitrack_cluster(0, &[(2, 0), (2, 100), (1, 200), (2, 200), (1, 240)]));
+        chunks.extend(multitrack_cluster(1000, &[(1, 0), (2, 0)]));
+
+        let mut fixer = ChunkTimecodeFixer::new().with_frame_duration(20).with_track_repair();
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+
+        assert_eq!(absolute_blocks(&output), vec![
+            (1, 0), (2, 0), (2, 20), (1, 40), (2, 40),
+            // spliced at 60ms, with the audio still 200ms after the video
+            (2, 60), (2, 160), (1, 260), (2, 260), (1, 300),
+            (1, 1060), (2, 1060)
+        ]);
+        assert_eq!(fixer.repaired_blocks(), 0);
+    }
+
+    #[test]
+    fn moved_blocks_update_cluster_end() {
+        // the second stream's first blocks are 100ms into its first cluster
+        let mut chunks = vec![headers()];
+        chunks.extend(multitrack_cluster(0, &[(1, 0), (2, 20), (1, 40)]));
+        chunks.push(headers());
+        chunks.extend(multitrack_cluster(0, &[(1, 100), (2, 120), (1, 140)]));
+
+        let mut fixer = ChunkTimecodeFixer::new().with_frame_duration(20).with_track_repair();
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+
+        assert_eq!(absolute_blocks(&output), vec![
+            (1, 0), (2, 20), (1, 40),
+            (1, 60), (2, 80), (1, 100)
+        ]);
+        let ends: Vec<u64> = output.iter().filter_map(|chunk| match chunk {
+            Chunk::ClusterHead(cluster_head) => Some(cluster_head.end),
+            _ => None
+        }).collect();
+        assert_eq!(ends, vec![40, 100]);
+    }
+
+    #[test]
+    fn unmovable_block_keeps_its_timecode() {
+        let mut chunks = vec![headers()];
+        chunks.extend(multitrack_cluster(0, &[(1, i16::MAX), (1, i16::MAX)]));
+        chunks.extend(multitrack_cluster(i16::MAX as u64 + 1, &[(1, 0)]));
+
+        let mut fixer = TrackTimecodeFixer::new();
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+
+        // the second block can't move past the end of its cluster, and the
+        // next cluster's block is compared against where it really is
+        assert_eq!(absolute_blocks(&output), vec![(1, 32767), (1, 32767), (1, 32768)]);
+        assert_eq!(fixer.repaired_blocks(), 0);
+    }
+
+    #[test]
+    fn track_repair_is_optional() {
+        let mut chunks = vec![headers()];
+        chunks.extend(multitrack_cluster(0, &[(1, 40), (1, 30)]));
+
+        let mut fixer = ChunkTimecodeFixer::new();
+        let output: Vec<Chunk> = chunks.into_iter().flat_map(|chunk| fixer.process(chunk)).collect();
+        assert_eq!(absolute_blocks(&output), vec![(1, 40), (1, 30)]);
+        assert_eq!(fixer.repaired_blocks(), 0);
+    }
+
+    #[derive(Clone)]
+    struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        fn advance(&self, millis: u64) {
+            *self.0.lock().unwrap() += Duration::from_millis(millis);
+        }
+    }
+
+    struct MockSleep(MockClock, Instant);
+
+    impl Future for MockSleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<()> {
+            if self.0.now() >= self.1 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    impl Clock for MockClock {
+        type Sleep = MockSleep;
+
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+        fn sleep_until(&self, deadline: Instant) -> MockSleep {
+            MockSleep(self.clone(), deadline)
+        }
+    }
+
+    type MockThrottle = Throttle<Box<dyn TryStream<Ok = Chunk, Error = WebmetroError, Item = Result<Chunk, WebmetroError>> + Unpin>, MockClock>;
+
+    fn mock_throttle(configure: impl Fn(MockThrottle) -> MockThrottle) -> (MockThrottle, MockClock) {
+        let clock = MockClock(Arc::new(Mutex::new(Instant::now())));
+        let throttle = Throttle::with_clock(Box::new(source(test_stream())) as Box<_>, clock.clone());
+        (configure(throttle), clock)
+    }
+
+    fn describe(chunk: Chunk) -> String {
+        match chunk {
+            Chunk::Headers {..} => "headers".to_string(),
+            Chunk::ClusterHead(cluster_head) => format!("head@{}", cluster_head.start),
+            Chunk::ClusterBody {..} => "body".to_string(),
+            Chunk::ClusterBodyPart {timecode, ..} => format!("part@{}", timecode)
+        }
+    }
+
+    /// describes the chunks that can be released without waiting
+    fn released(throttle: &mut MockThrottle) -> Vec<String> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut released = Vec::new();
+        while let Poll::Ready(Some(chunk)) = throttle.poll_next_unpin(&mut cx) {
+            released.push(describe(chunk.unwrap()));
+        }
+        released
+    }
+
+    #[test]
+    fn throttle_releases_clusters_in_real_time() {
+        let (mut throttle, clock) = mock_throttle(|throttle| throttle);
+
+        assert_eq!(released(&mut throttle), vec!["headers", "head@0"]);
+        clock.advance(499);
+        assert_eq!(released(&mut throttle), Vec::<String>::new());
+        clock.advance(1);
+        assert_eq!(released(&mut throttle), vec!["body"]);
+        clock.advance(500);
+        assert_eq!(released(&mut throttle), vec!["head@1000"]);
+        clock.advance(10_000);
+        assert_eq!(released(&mut throttle).len(), 7);
+    }
+
+    #[test]
+    fn throttle_speed() {
+        let (mut throttle, clock) = mock_throttle(|throttle| throttle.with_speed(2.0));
+
+        assert_eq!(released(&mut throttle), vec!["headers", "head@0"]);
+        clock.advance(250);
+        assert_eq!(released(&mut throttle), vec!["body"]);
+        clock.advance(250);
+        assert_eq!(released(&mut throttle), vec!["head@1000"]);
+    }
+
+    #[test]
+    fn throttle_preroll() {
+        let (mut throttle, clock) = mock_throttle(|throttle| throttle.with_preroll(1000));
+
+        assert_eq!(released(&mut throttle), vec!["headers", "head@0", "body", "head@1000"]);
+        clock.advance(500);
+        assert_eq!(released(&mut throttle), vec!["body"]);
+        clock.advance(500);
+        assert_eq!(released(&mut throttle), vec!["head@2000"]);
+    }
+
+    #[test]
+    fn throttle_paces_blocks() {
+        let (mut throttle, clock) = mock_throttle(|throttle| throttle.pace_blocks());
+
+        assert_eq!(released(&mut throttle), vec!["headers", "head@0", "part@0"]);
+        clock.advance(500);
+        assert_eq!(released(&mut throttle), vec!["part@500"]);
+        clock.advance(500);
+        assert_eq!(released(&mut throttle), vec!["head@1000", "part@1000"]);
+    }
+
+    fn push_all(buffer: &mut ResumeBuffer, chunks: Vec<Chunk>) {
+        for chunk in chunks {
+            buffer.push(chunk);
+        }
+    }
+
+    fn pop_all(buffer: &mut ResumeBuffer) -> Vec<String> {
+        std::iter::from_fn(|| buffer.pop()).map(describe).collect()
+    }
+
+    #[test]
+    fn resume_buffer_passes_chunks_through() {
+        let mut buffer = ResumeBuffer::new(1024);
+
+        buffer.push(headers());
+        push_all(&mut buffer, cluster(0, true, &[0]));
+        assert_eq!(pop_all(&mut buffer), vec!["headers", "head@0", "body"]);
+
+        push_all(&mut buffer, cluster(1000, false, &[0]));
+        assert_eq!(pop_all(&mut buffer), vec!["head@1000", "body"]);
+    }
+
+    #[test]
+    fn resume_buffer_resumes_at_latest_keyframe() {
+        let mut buffer = ResumeBuffer::new(1024);
+        buffer.push(headers());
+        push_all(&mut buffer, cluster(0, true, &[0]));
+        pop_all(&mut buffer);
+
+        buffer.disconnect();
+        push_all(&mut buffer, cluster(1000, false, &[0]));
+        assert_eq!(pop_all(&mut buffer), Vec::<String>::new());
+
+        push_all(&mut buffer, cluster(2000, true, &[0]));
+        push_all(&mut buffer, cluster(3000, false, &[0]));
+        push_all(&mut buffer, cluster(4000, true, &[0]));
+        push_all(&mut buffer, cluster(5000, false, &[0]));
+        assert_eq!(pop_all(&mut buffer), vec!["headers", "head@4000", "body", "head@5000", "body"]);
+
+        push_all(&mut buffer, cluster(6000, false, &[0]));
+        assert_eq!(pop_all(&mut buffer), vec!["head@6000", "body"]);
+    }
+
+    #[test]
+    fn resume_buffer_waits_for_new_stream_keyframe() {
+        let mut buffer = ResumeBuffer::new(1024);
+        buffer.push(headers());
+        push_all(&mut buffer, cluster(0, true, &[0]));
+        pop_all(&mut buffer);
+
+        buffer.disconnect();
+        push_all(&mut buffer, cluster(1000, true, &[0]));
+        buffer.push(Chunk::Headers {bytes: Bytes::from(&b"new headers"[..])});
+        push_all(&mut buffer, cluster(0, false, &[0]));
+        assert_eq!(pop_all(&mut buffer), Vec::<String>::new());
+
+        push_all(&mut buffer, cluster(1000, true, &[0]));
+        let resumed: Vec<Chunk> = std::iter::from_fn(|| buffer.pop()).collect();
+        assert_matches!(resumed[0], Chunk::Headers {ref bytes} if bytes.as_ref() == b"new headers");
+        assert_eq!(cluster_starts(&resumed), vec![1000]);
+    }
+
+    #[test]
+    fn resume_buffer_size_limit() {
+        let mut buffer = ResumeBuffer::new(64);
+        buffer.push(headers());
+        pop_all(&mut buffer);
+
+        buffer.disconnect();
+        push_all(&mut buffer, cluster(0, true, &[0; 10]));
+        assert_eq!(pop_all(&mut buffer), Vec::<String>::new());
+
+        push_all(&mut buffer, cluster(1000, true, &[0]));
+        assert_eq!(pop_all(&mut buffer), vec!["headers", "head@1000", "body"]);
+    }
+}
@@ -1,4 +1,8 @@
 
+#[macro_use] extern crate log;
+// lets derived code refer to this crate by name from inside it, too
+extern crate self as webmetro;
+
 pub mod ebml;
 pub mod error;
 
@@ -7,9 +11,12 @@ pub mod stream_parser;
 
 pub mod chunk;
 pub mod fixers;
+pub mod schema;
 pub mod webm;
+pub mod webm_file;
 
 pub mod channel;
+pub mod client;
 
 pub use crate::ebml::{EbmlError, FromEbml};
 
@@ -17,4 +24,6 @@ pub use crate::ebml::{EbmlError, FromEbml};
 mod tests {
     pub const TEST_FILE: &'static [u8] = include_bytes!("data/test1.webm");
     pub const ENCODE_WEBM_TEST_FILE: &'static [u8] = include_bytes!("data/encode_webm_test.webm");
+    pub const TEST_CA: &[u8] = include_bytes!("data/test_ca.pem");
+    pub const TEST_SERVER_IDENTITY: &[u8] = include_bytes!("data/test_server.p12");
 }
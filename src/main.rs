@@ -9,7 +9,9 @@ use crate::commands::{
     relay,
     filter,
     send,
-    dump
+    dump,
+    probe,
+    validate
 };
 
 fn options() -> App<'static, 'static> {
@@ -22,6 +24,8 @@ fn options() -> App<'static, 'static> {
         .subcommand(filter::options())
         .subcommand(send::options())
         .subcommand(dump::options())
+        .subcommand(probe::options())
+        .subcommand(validate::options())
 }
 
 fn main() {
@@ -33,6 +37,8 @@ fn main() {
         ("relay", Some(sub_args)) => relay::run(sub_args),
         ("send", Some(sub_args)) => send::run(sub_args),
         ("dump", Some(sub_args)) => dump::run(sub_args),
+        ("probe", Some(sub_args)) => probe::run(sub_args),
+        ("validate", Some(sub_args)) => validate::run(sub_args),
         _ => {
             options().print_help().unwrap();
             println!("");
@@ -40,5 +46,6 @@ fn main() {
         }
     }.unwrap_or_else(|err| {
         error!("{}", err);
+        std::process::exit(1);
     });
 }
@@ -0,0 +1,478 @@
+use crate::ebml::{EbmlError, FromEbml};
+use crate::stream_parser::ElementPosition;
+
+/// How an element's payload is encoded
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ElementType {
+    Master,
+    Uint,
+    Int,
+    Float,
+    /// ASCII
+    String,
+    Utf8,
+    /// nanoseconds since 2001-01-01T00:00:00 UTC
+    Date,
+    Binary
+}
+
+use self::ElementType::*;
+
+/// Describes one kind of element in the Matroska/WebM format
+#[derive(Debug, PartialEq)]
+pub struct ElementSchema {
+    /// the element ID as written in the spec, with its length marker (e.g. 0x1A45DFA3)
+    pub id: u64,
+    pub name: &'static str,
+    pub element_type: ElementType,
+    /// IDs of the elements this can appear in; empty for top-level elements,
+    /// or None for elements (like Void) that can appear anywhere
+    pub parents: Option<&'static [u64]>
+}
+
+impl ElementSchema {
+    /// the element ID without its length marker, as reported by the parser
+    pub fn element_id(&self) -> u64 {
+        let id_size = 8 - self.id.leading_zeros() as u64 / 8;
+        self.id & !(1 << (7 * id_size))
+    }
+}
+
+const ROOT: Option<&[u64]> = Some(&[]);
+const ANYWHERE: Option<&[u64]> = None;
+
+const EBML: u64 = 0x1A45DFA3;
+const SEGMENT: u64 = 0x18538067;
+const SEEK_HEAD: u64 = 0x114D9B74;
+const SEEK: u64 = 0x4DBB;
+const INFO: u64 = 0x1549A966;
+const CLUSTER: u64 = 0x1F43B675;
+const BLOCK_GROUP: u64 = 0xA0;
+const BLOCK_ADDITIONS: u64 = 0x75A1;
+const BLOCK_MORE: u64 = 0xA6;
+const TRACKS: u64 = 0x1654AE6B;
+const TRACK_ENTRY: u64 = 0xAE;
+const VIDEO: u64 = 0xE0;
+const COLOUR: u64 = 0x55B0;
+const MASTERING_METADATA: u64 = 0x55D0;
+const PROJECTION: u64 = 0x7670;
+const AUDIO: u64 = 0xE1;
+const CONTENT_ENCODINGS: u64 = 0x6D80;
+const CONTENT_ENCODING: u64 = 0x6240;
+const CONTENT_COMPRESSION: u64 = 0x5034;
+const CONTENT_ENCRYPTION: u64 = 0x5035;
+const CONTENT_ENC_AES_SETTINGS: u64 = 0x47E7;
+const CUES: u64 = 0x1C53BB6B;
+const CUE_POINT: u64 = 0xBB;
+const CUE_TRACK_POSITIONS: u64 = 0xB7;
+const CUE_REFERENCE: u64 = 0xDB;
+const ATTACHMENTS: u64 = 0x1941A469;
+const ATTACHED_FILE: u64 = 0x61A7;
+const CHAPTERS: u64 = 0x1043A770;
+const EDITION_ENTRY: u64 = 0x45B9;
+const CHAPTER_ATOM: u64 = 0xB6;
+const CHAPTER_DISPLAY: u64 = 0x80;
+const TAGS: u64 = 0x1254C367;
+const TAG: u64 = 0x7373;
+const TARGETS: u64 = 0x63C0;
+const SIMPLE_TAG: u64 = 0x67C8;
+
+macro_rules! elements {
+    ($($id:expr, $name:expr, $element_type:ident, $parents:expr;)*) => {
+        &[$(ElementSchema {id: $id, name: $name, element_type: $element_type, parents: $parents}),*]
+    };
+}
+
+/// The elements of the Matroska format that WebM uses (and a few more besides)
+pub static SCHEMA: &[ElementSchema] = elements![
+    // EBML header
+    EBML, "EBML", Master, ROOT;
+    0x4286, "EBMLVersion", Uint, Some(&[EBML]);
+    0x42F7, "EBMLReadVersion", Uint, Some(&[EBML]);
+    0x42F2, "EBMLMaxIDLength", Uint, Some(&[EBML]);
+    0x42F3, "EBMLMaxSizeLength", Uint, Some(&[EBML]);
+    0x4282, "DocType", String, Some(&[EBML]);
+    0x4287, "DocTypeVersion", Uint, Some(&[EBML]);
+    0x4285, "DocTypeReadVersion", Uint, Some(&[EBML]);
+
+    // global elements
+    0xEC, "Void", Binary, ANYWHERE;
+    0xBF, "CRC-32", Binary, ANYWHERE;
+
+    SEGMENT, "Segment", Master, ROOT;
+
+    SEEK_HEAD, "SeekHead", Master, Some(&[SEGMENT]);
+    SEEK, "Seek", Master, Some(&[SEEK_HEAD]);
+    0x53AB, "SeekID", Binary, Some(&[SEEK]);
+    0x53AC, "SeekPosition", Uint, Some(&[SEEK]);
+
+    INFO, "Info", Master, Some(&[SEGMENT]);
+    0x73A4, "SegmentUID", Binary, Some(&[INFO]);
+    0x7384, "SegmentFilename", Utf8, Some(&[INFO]);
+    0x3CB923, "PrevUID", Binary, Some(&[INFO]);
+    0x3C83AB, "PrevFilename", Utf8, Some(&[INFO]);
+    0x3EB923, "NextUID", Binary, Some(&[INFO]);
+    0x3E83BB, "NextFilename", Utf8, Some(&[INFO]);
+    0x4444, "SegmentFamily", Binary, Some(&[INFO]);
+    0x2AD7B1, "TimecodeScale", Uint, Some(&[INFO]);
+    0x4489, "Duration", Float, Some(&[INFO]);
+    0x4461, "DateUTC", Date, Some(&[INFO]);
+    0x7BA9, "Title", Utf8, Some(&[INFO]);
+    0x4D80, "MuxingApp", Utf8, Some(&[INFO]);
+    0x5741, "WritingApp", Utf8, Some(&[INFO]);
+
+    CLUSTER, "Cluster", Master, Some(&[SEGMENT]);
+    0xE7, "Timecode", Uint, Some(&[CLUSTER]);
+    0xA7, "Position", Uint, Some(&[CLUSTER]);
+    0xAB, "PrevSize", Uint, Some(&[CLUSTER]);
+    0xA3, "SimpleBlock", Binary, Some(&[CLUSTER]);
+    BLOCK_GROUP, "BlockGroup", Master, Some(&[CLUSTER]);
+    0xA1, "Block", Binary, Some(&[BLOCK_GROUP]);
+    BLOCK_ADDITIONS, "BlockAdditions", Master, Some(&[BLOCK_GROUP]);
+    BLOCK_MORE, "BlockMore", Master, Some(&[BLOCK_ADDITIONS]);
+    0xEE, "BlockAddID", Uint, Some(&[BLOCK_MORE]);
+    0xA5, "BlockAdditional", Binary, Some(&[BLOCK_MORE]);
+    0x9B, "BlockDuration", Uint, Some(&[BLOCK_GROUP]);
+    0xFA, "ReferencePriority", Uint, Some(&[BLOCK_GROUP]);
+    0xFB, "ReferenceBlock", Int, Some(&[BLOCK_GROUP]);
+    0xA4, "CodecState", Binary, Some(&[BLOCK_GROUP]);
+    0x75A2, "DiscardPadding", Int, Some(&[BLOCK_GROUP]);
+
+    TRACKS, "Tracks", Master, Some(&[SEGMENT]);
+    TRACK_ENTRY, "TrackEntry", Master, Some(&[TRACKS]);
+    0xD7, "TrackNumber", Uint, Some(&[TRACK_ENTRY]);
+    0x73C5, "TrackUID", Uint, Some(&[TRACK_ENTRY]);
+    0x83, "TrackType", Uint, Some(&[TRACK_ENTRY]);
+    0xB9, "FlagEnabled", Uint, Some(&[TRACK_ENTRY]);
+    0x88, "FlagDefault", Uint, Some(&[TRACK_ENTRY]);
+    0x55AA, "FlagForced", Uint, Some(&[TRACK_ENTRY]);
+    0x9C, "FlagLacing", Uint, Some(&[TRACK_ENTRY]);
+    0x6DE7, "MinCache", Uint, Some(&[TRACK_ENTRY]);
+    0x6DF8, "MaxCache", Uint, Some(&[TRACK_ENTRY]);
+    0x23E383, "DefaultDuration", Uint, Some(&[TRACK_ENTRY]);
+    0x23314F, "TrackTimecodeScale", Float, Some(&[TRACK_ENTRY]);
+    0x55EE, "MaxBlockAdditionID", Uint, Some(&[TRACK_ENTRY]);
+    0x536E, "Name", Utf8, Some(&[TRACK_ENTRY]);
+    0x22B59C, "Language", String, Some(&[TRACK_ENTRY]);
+    0x22B59D, "LanguageIETF", String, Some(&[TRACK_ENTRY]);
+    0x86, "CodecID", String, Some(&[TRACK_ENTRY]);
+    0x63A2, "CodecPrivate", Binary, Some(&[TRACK_ENTRY]);
+    0x258688, "CodecName", Utf8, Some(&[TRACK_ENTRY]);
+    0x7446, "AttachmentLink", Uint, Some(&[TRACK_ENTRY]);
+    0xAA, "CodecDecodeAll", Uint, Some(&[TRACK_ENTRY]);
+    0x6FAB, "TrackOverlay", Uint, Some(&[TRACK_ENTRY]);
+    0x56AA, "CodecDelay", Uint, Some(&[TRACK_ENTRY]);
+    0x56BB, "SeekPreRoll", Uint, Some(&[TRACK_ENTRY]);
+
+    VIDEO, "Video", Master, Some(&[TRACK_ENTRY]);
+    0x9A, "FlagInterlaced", Uint, Some(&[VIDEO]);
+    0x9D, "FieldOrder", Uint, Some(&[VIDEO]);
+    0x53B8, "StereoMode", Uint, Some(&[VIDEO]);
+    0x53C0, "AlphaMode", Uint, Some(&[VIDEO]);
+    0xB0, "PixelWidth", Uint, Some(&[VIDEO]);
+    0xBA, "PixelHeight", Uint, Some(&[VIDEO]);
+    0x54AA, "PixelCropBottom", Uint, Some(&[VIDEO]);
+    0x54BB, "PixelCropTop", Uint, Some(&[VIDEO]);
+    0x54CC, "PixelCropLeft", Uint, Some(&[VIDEO]);
+    0x54DD, "PixelCropRight", Uint, Some(&[VIDEO]);
+    0x54B0, "DisplayWidth", Uint, Some(&[VIDEO]);
+    0x54BA, "DisplayHeight", Uint, Some(&[VIDEO]);
+    0x54B2, "DisplayUnit", Uint, Some(&[VIDEO]);
+    0x54B3, "AspectRatioType", Uint, Some(&[VIDEO]);
+    0x2EB524, "ColourSpace", Binary, Some(&[VIDEO]);
+    COLOUR, "Colour", Master, Some(&[VIDEO]);
+    0x55B1, "MatrixCoefficients", Uint, Some(&[COLOUR]);
+    0x55B2, "BitsPerChannel", Uint, Some(&[COLOUR]);
+    0x55B3, "ChromaSubsamplingHorz", Uint, Some(&[COLOUR]);
+    0x55B4, "ChromaSubsamplingVert", Uint, Some(&[COLOUR]);
+    0x55B5, "CbSubsamplingHorz", Uint, Some(&[COLOUR]);
+    0x55B6, "CbSubsamplingVert", Uint, Some(&[COLOUR]);
+    0x55B7, "ChromaSitingHorz", Uint, Some(&[COLOUR]);
+    0x55B8, "ChromaSitingVert", Uint, Some(&[COLOUR]);
+    0x55B9, "Range", Uint, Some(&[COLOUR]);
+    0x55BA, "TransferCharacteristics", Uint, Some(&[COLOUR]);
+    0x55BB, "Primaries", Uint, Some(&[COLOUR]);
+    0x55BC, "MaxCLL", Uint, Some(&[COLOUR]);
+    0x55BD, "MaxFALL", Uint, Some(&[COLOUR]);
+    MASTERING_METADATA, "MasteringMetadata", Master, Some(&[COLOUR]);
+    0x55D1, "PrimaryRChromaticityX", Float, Some(&[MASTERING_METADATA]);
+    0x55D2, "PrimaryRChromaticityY", Float, Some(&[MASTERING_METADATA]);
+    0x55D3, "PrimaryGChromaticityX", Float, Some(&[MASTERING_METADATA]);
+    0x55D4, "PrimaryGChromaticityY", Float, Some(&[MASTERING_METADATA]);
+    0x55D5, "PrimaryBChromaticityX", Float, Some(&[MASTERING_METADATA]);
+    0x55D6, "PrimaryBChromaticityY", Float, Some(&[MASTERING_METADATA]);
+    0x55D7, "WhitePointChromaticityX", Float, Some(&[MASTERING_METADATA]);
+    0x55D8, "WhitePointChromaticityY", Float, Some(&[MASTERING_METADATA]);
+    0x55D9, "LuminanceMax", Float, Some(&[MASTERING_METADATA]);
+    0x55DA, "LuminanceMin", Float, Some(&[MASTERING_METADATA]);
+    PROJECTION, "Projection", Master, Some(&[VIDEO]);
+    0x7671, "ProjectionType", Uint, Some(&[PROJECTION]);
+    0x7672, "ProjectionPrivate", Binary, Some(&[PROJECTION]);
+    0x7673, "ProjectionPoseYaw", Float, Some(&[PROJECTION]);
+    0x7674, "ProjectionPosePitch", Float, Some(&[PROJECTION]);
+    0x7675, "ProjectionPoseRoll", Float, Some(&[PROJECTION]);
+
+    AUDIO, "Audio", Master, Some(&[TRACK_ENTRY]);
+    0xB5, "SamplingFrequency", Float, Some(&[AUDIO]);
+    0x78B5, "OutputSamplingFrequency", Float, Some(&[AUDIO]);
+    0x9F, "Channels", Uint, Some(&[AUDIO]);
+    0x6264, "BitDepth", Uint, Some(&[AUDIO]);
+
+    CONTENT_ENCODINGS, "ContentEncodings", Master, Some(&[TRACK_ENTRY]);
+    CONTENT_ENCODING, "ContentEncoding", Master, Some(&[CONTENT_ENCODINGS]);
+    0x5031, "ContentEncodingOrder", Uint, Some(&[CONTENT_ENCODING]);
+    0x5032, "ContentEncodingScope", Uint, Some(&[CONTENT_ENCODING]);
+    0x5033, "ContentEncodingType", Uint, Some(&[CONTENT_ENCODING]);
+    CONTENT_COMPRESSION, "ContentCompression", Master, Some(&[CONTENT_ENCODING]);
+    0x4254, "ContentCompAlgo", Uint, Some(&[CONTENT_COMPRESSION]);
+    0x4255, "ContentCompSettings", Binary, Some(&[CONTENT_COMPRESSION]);
+    CONTENT_ENCRYPTION, "ContentEncryption", Master, Some(&[CONTENT_ENCODING]);
+    0x47E1, "ContentEncAlgo", Uint, Some(&[CONTENT_ENCRYPTION]);
+    0x47E2, "ContentEncKeyID", Binary, Some(&[CONTENT_ENCRYPTION]);
+    CONTENT_ENC_AES_SETTINGS, "ContentEncAESSettings", Master, Some(&[CONTENT_ENCRYPTION]);
+    0x47E8, "AESSettingsCipherMode", Uint, Some(&[CONTENT_ENC_AES_SETTINGS]);
+
+    CUES, "Cues", Master, Some(&[SEGMENT]);
+    CUE_POINT, "CuePoint", Master, Some(&[CUES]);
+    0xB3, "CueTime", Uint, Some(&[CUE_POINT]);
+    CUE_TRACK_POSITIONS, "CueTrackPositions", Master, Some(&[CUE_POINT]);
+    0xF7, "CueTrack", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    0xF1, "CueClusterPosition", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    0xF0, "CueRelativePosition", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    0xB2, "CueDuration", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    0x5378, "CueBlockNumber", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    0xEA, "CueCodecState", Uint, Some(&[CUE_TRACK_POSITIONS]);
+    CUE_REFERENCE, "CueReference", Master, Some(&[CUE_TRACK_POSITIONS]);
+    0x96, "CueRefTime", Uint, Some(&[CUE_REFERENCE]);
+
+    ATTACHMENTS, "Attachments", Master, Some(&[SEGMENT]);
+    ATTACHED_FILE, "AttachedFile", Master, Some(&[ATTACHMENTS]);
+    0x467E, "FileDescription", Utf8, Some(&[ATTACHED_FILE]);
+    0x466E, "FileName", Utf8, Some(&[ATTACHED_FILE]);
+    0x4660, "FileMimeType", String, Some(&[ATTACHED_FILE]);
+    0x465C, "FileData", Binary, Some(&[ATTACHED_FILE]);
+    0x46AE, "FileUID", Uint, Some(&[ATTACHED_FILE]);
+
+    CHAPTERS, "Chapters", Master, Some(&[SEGMENT]);
+    EDITION_ENTRY, "EditionEntry", Master, Some(&[CHAPTERS]);
+    0x45BC, "EditionUID", Uint, Some(&[EDITION_ENTRY]);
+    0x45BD, "EditionFlagHidden", Uint, Some(&[EDITION_ENTRY]);
+    0x45DB, "EditionFlagDefault", Uint, Some(&[EDITION_ENTRY]);
+    0x45DD, "EditionFlagOrdered", Uint, Some(&[EDITION_ENTRY]);
+    CHAPTER_ATOM, "ChapterAtom", Master, Some(&[EDITION_ENTRY, CHAPTER_ATOM]);
+    0x73C4, "ChapterUID", Uint, Some(&[CHAPTER_ATOM]);
+    0x5654, "ChapterStringUID", Utf8, Some(&[CHAPTER_ATOM]);
+    0x91, "ChapterTimeStart", Uint, Some(&[CHAPTER_ATOM]);
+    0x92, "ChapterTimeEnd", Uint, Some(&[CHAPTER_ATOM]);
+    0x98, "ChapterFlagHidden", Uint, Some(&[CHAPTER_ATOM]);
+    0x4598, "ChapterFlagEnabled", Uint, Some(&[CHAPTER_ATOM]);
+    0x6E67, "ChapterSegmentUID", Binary, Some(&[CHAPTER_ATOM]);
+    CHAPTER_DISPLAY, "ChapterDisplay", Master, Some(&[CHAPTER_ATOM]);
+    0x85, "ChapString", Utf8, Some(&[CHAPTER_DISPLAY]);
+    0x437C, "ChapLanguage", String, Some(&[CHAPTER_DISPLAY]);
+    0x437D, "ChapLanguageIETF", String, Some(&[CHAPTER_DISPLAY]);
+    0x437E, "ChapCountry", String, Some(&[CHAPTER_DISPLAY]);
+
+    TAGS, "Tags", Master, Some(&[SEGMENT]);
+    TAG, "Tag", Master, Some(&[TAGS]);
+    TARGETS, "Targets", Master, Some(&[TAG]);
+    0x68CA, "TargetTypeValue", Uint, Some(&[TARGETS]);
+    0x63CA, "TargetType", String, Some(&[TARGETS]);
+    0x63C5, "TagTrackUID", Uint, Some(&[TARGETS]);
+    0x63C9, "TagEditionUID", Uint, Some(&[TARGETS]);
+    0x63C4, "TagChapterUID", Uint, Some(&[TARGETS]);
+    0x63C6, "TagAttachmentUID", Uint, Some(&[TARGETS]);
+    SIMPLE_TAG, "SimpleTag", Master, Some(&[TAG, SIMPLE_TAG]);
+    0x45A3, "TagName", Utf8, Some(&[SIMPLE_TAG]);
+    0x447A, "TagLanguage", String, Some(&[SIMPLE_TAG]);
+    0x447B, "TagLanguageIETF", String, Some(&[SIMPLE_TAG]);
+    0x4484, "TagDefault", Uint, Some(&[SIMPLE_TAG]);
+    0x4487, "TagString", Utf8, Some(&[SIMPLE_TAG]);
+    0x4485, "TagBinary", Binary, Some(&[SIMPLE_TAG]);
+];
+
+/// Finds the schema for an element ID, as reported by the parser (without its length marker)
+pub fn lookup(element_id: u64) -> Option<&'static ElementSchema> {
+    SCHEMA.iter().find(|schema| schema.element_id() == element_id)
+}
+
+/// Gives an element's type by its ID, for parsing an `EbmlNode` tree
+pub fn element_type(element_id: u64) -> Option<ElementType> {
+    lookup(element_id).map(|schema| schema.element_type)
+}
+
+/// An element parsed according to the schema: masters are descended into,
+/// and everything else is given as its raw payload.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SchemaElement<'b> {
+    Master(u64),
+    Value(u64, &'b[u8])
+}
+
+impl<'b> FromEbml<'b> for SchemaElement<'b> {
+    fn should_unwrap(element_id: u64) -> bool {
+        lookup(element_id).map(|schema| schema.element_type) == Some(Master)
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<SchemaElement<'b>, EbmlError> {
+        if SchemaElement::should_unwrap(element_id) {
+            Ok(SchemaElement::Master(element_id))
+        } else {
+            Ok(SchemaElement::Value(element_id, bytes))
+        }
+    }
+}
+
+/// Works out how deeply each element is nested, from the schema's parent
+/// elements where known, otherwise from the sizes of the elements around it.
+#[derive(Default)]
+pub struct Nesting {
+    /// IDs of the currently-open master elements, with their ending offsets if known
+    open: Vec<(u64, Option<u64>)>
+}
+
+impl Nesting {
+    /// Gives the depth of the next element; `descending` says whether
+    /// the element's children will be reported next, rather than skipped.
+    pub fn depth_of(&mut self, position: &ElementPosition, descending: bool) -> usize {
+        while let Some(&(_, Some(end))) = self.open.last() {
+            if end > position.offset {
+                break;
+            }
+            self.open.pop();
+        }
+
+        let parents = lookup(position.element_id).and_then(|schema| schema.parents);
+        match parents {
+            Some(parents) => {
+                // an element of unknown size ends when something that can't be its child begins
+                let parent_index = self.open.iter().rposition(|&(element_id, _)| {
+                    parents.iter().any(|parent| lookup(element_id).map(|schema| schema.id) == Some(*parent))
+                });
+                match parent_index {
+                    Some(index) => self.open.truncate(index + 1),
+                    None if parents.is_empty() => self.open.clear(),
+                    None => {}
+                }
+            },
+            None => {
+                // unknown elements: at least a sibling (with the same ID) ends an element of unknown size
+                if let Some(index) = self.open.iter().position(|&(element_id, end)| {
+                    end.is_none() && element_id == position.element_id
+                }) {
+                    self.open.truncate(index);
+                }
+            }
+        }
+
+        let depth = self.open.len();
+        if descending {
+            let end = position.payload_size.map(|size| position.offset + position.header_size as u64 + size);
+            self.open.push((position.element_id, end));
+        }
+        depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures3::{stream, FutureExt, StreamExt};
+    use std::borrow::Cow;
+
+    use bytes::IntoBuf;
+
+    use crate::ebml::EbmlValue;
+    use crate::error::WebmetroError;
+    use crate::schema::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{ENCODE_WEBM_TEST_FILE, TEST_FILE};
+
+    #[test]
+    fn lookup_elements() {
+        let segment = lookup(0x08538067).unwrap();
+        assert_eq!(segment.name, "Segment");
+        assert_eq!(segment.element_type, Master);
+
+        assert_eq!(lookup(0x06).unwrap().name, "CodecID");
+        assert_eq!(lookup(0x03E383).unwrap().name, "DefaultDuration");
+        assert_eq!(lookup(0x6C).unwrap().name, "Void");
+        assert_eq!(lookup(0x3FFF), None);
+    }
+
+    #[test]
+    fn schema_ids_are_unique() {
+        for (index, schema) in SCHEMA.iter().enumerate() {
+            assert_eq!(lookup(schema.element_id()), Some(schema), "{} is shadowed", schema.name);
+            assert!(SCHEMA[index + 1..].iter().all(|other| other.id != schema.id), "{} is repeated", schema.name);
+        }
+    }
+
+    #[test]
+    fn decode_values() {
+        assert_eq!(EbmlValue::decode(Uint, &[]).unwrap(), EbmlValue::Uint(0));
+        assert_eq!(EbmlValue::decode(Uint, &[0x01, 0x00]).unwrap(), EbmlValue::Uint(256));
+        assert_eq!(EbmlValue::decode(Int, &[0xFF, 0xFE]).unwrap(), EbmlValue::Int(-2));
+        assert_eq!(EbmlValue::decode(Float, &[0x3F, 0xC0, 0x00, 0x00]).unwrap(), EbmlValue::Float(1.5));
+        assert_eq!(EbmlValue::decode(Float, &[0x40, 0x8F, 0x40, 0, 0, 0, 0, 0]).unwrap(), EbmlValue::Float(1000.0));
+        assert_eq!(EbmlValue::decode(String, b"webm\0\0").unwrap(), EbmlValue::String("webm".into()));
+        assert_eq!(EbmlValue::decode(Date, &[0, 0, 0, 0, 0, 0, 0x03, 0xE8]).unwrap(), EbmlValue::Date(1000));
+        assert_eq!(EbmlValue::decode(Binary, &[1, 2]).unwrap(), EbmlValue::Binary(Cow::Borrowed(&[1, 2])));
+
+        assert!(EbmlValue::decode(Float, &[0; 3]).is_err());
+        assert!(EbmlValue::decode(Int, &[0; 9]).is_err());
+        assert!(EbmlValue::decode(Date, &[0; 4]).is_err());
+        assert!(EbmlValue::decode(String, b"caf\xC3\xA9").is_err());
+        assert_eq!(EbmlValue::decode(Utf8, b"caf\xC3\xA9\0").unwrap(), EbmlValue::Utf8("café".into()));
+    }
+
+    /// parses a file according to the schema, giving each element's name & depth
+    fn tree(file: &'static [u8]) -> Vec<(&'static str, usize)> {
+        async {
+            let mut parser = stream::iter(file.chunks(100))
+                .map(|bytes| Ok(bytes.into_buf()))
+                .parse_ebml();
+            let mut nesting = Nesting::default();
+            let mut tree = Vec::new();
+
+            while let Some((position, element)) = parser.next_with_position::<SchemaElement>().await? {
+                let descending = matches!(element, SchemaElement::Master(_));
+                let depth = nesting.depth_of(&position, descending);
+                tree.push((lookup(position.element_id).map_or("?", |schema| schema.name), depth));
+            }
+
+            Result::<_, WebmetroError>::Ok(tree)
+        }
+        .now_or_never()
+        .expect("Test tried to block on I/O")
+        .expect("Parse failed")
+    }
+
+    #[test]
+    fn nesting_with_known_sizes() {
+        let tree = tree(TEST_FILE);
+
+        assert_eq!(&tree[..3], &[("EBML", 0), ("EBMLVersion", 1), ("EBMLReadVersion", 1)]);
+        assert!(tree.contains(&("Segment", 0)));
+        assert!(tree.contains(&("TrackEntry", 2)));
+        assert!(tree.contains(&("CodecID", 3)));
+        assert!(tree.contains(&("PixelWidth", 4)));
+        assert!(tree.contains(&("SimpleBlock", 2)));
+        assert!(tree.contains(&("CueTrack", 4)));
+        assert_eq!(tree.iter().filter(|&&(_, depth)| depth == 0).count(), 2);
+    }
+
+    #[test]
+    fn nesting_with_unknown_sizes() {
+        assert_eq!(tree(ENCODE_WEBM_TEST_FILE), vec![
+            ("EBML", 0),
+            ("DocType", 1),
+            ("Segment", 0),
+            ("Tracks", 1),
+            ("Cluster", 1),
+            ("Timecode", 2),
+            ("SimpleBlock", 2),
+            ("Cluster", 1),
+            ("Timecode", 2),
+        ]);
+    }
+}
@@ -2,7 +2,7 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures3::stream::{Stream, StreamExt, TryStream};
 use std::task::{Context, Poll};
 
-use crate::ebml::FromEbml;
+use crate::ebml::{decode_tag, decode_varint, FromEbml, Varint};
 use crate::error::WebmetroError;
 
 pub struct EbmlStreamingParser<S> {
@@ -10,6 +10,29 @@ pub struct EbmlStreamingParser<S> {
     buffer: BytesMut,
     buffer_size_limit: Option<usize>,
     borrowed: Bytes,
+    /// how many bytes of the stream have been parsed so far
+    consumed: u64,
+}
+
+/// Where an element was found in the stream, and how its header was laid out
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ElementPosition {
+    /// offset of the element's header from the start of the stream
+    pub offset: u64,
+    pub element_id: u64,
+    /// how many bytes the element ID takes up
+    pub id_size: usize,
+    /// how many bytes the element ID & payload size take up together
+    pub header_size: usize,
+    /// the declared payload size, or None if "unknown"
+    pub payload_size: Option<u64>,
+}
+
+impl ElementPosition {
+    /// the element ID as usually written in specs, including its length marker (e.g. 0x1A45DFA3)
+    pub fn encoded_id(&self) -> u64 {
+        self.element_id | 1 << (7 * self.id_size)
+    }
 }
 
 impl<S> EbmlStreamingParser<S> {
@@ -33,6 +56,7 @@ where
             buffer: BytesMut::new(),
             buffer_size_limit: None,
             borrowed: Bytes::new(),
+            consumed: 0,
         }
     }
 }
@@ -50,6 +74,7 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> EbmlStreamingPa
                     // need to refill buffer, below
                 }
                 Some(info) => {
+                    self.consumed += info.element_len as u64;
                     let mut bytes = self.buffer.split_to(info.element_len).freeze();
                     bytes.advance(info.body_offset);
                     self.borrowed = bytes;
@@ -81,12 +106,20 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> EbmlStreamingPa
 
 impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> EbmlStreamingParser<S> {
     pub async fn next<'a, T: FromEbml<'a>>(&'a mut self) -> Result<Option<T>, WebmetroError> {
+        Ok(self.next_with_position().await?.map(|(_, element)| element))
+    }
+
+    /// Like `next`, but also reports where the element was found in the stream
+    pub async fn next_with_position<'a, T: FromEbml<'a>>(&'a mut self) -> Result<Option<(ElementPosition, T)>, WebmetroError> {
         loop {
             if let Some(info) = T::check_space(&self.buffer)? {
+                let position = self.position_of_next()?;
+                self.consumed += info.element_len as u64;
+
                 let mut bytes = self.buffer.split_to(info.element_len).freeze();
                 bytes.advance(info.body_offset);
                 self.borrowed = bytes;
-                return Ok(Some(T::decode(info.element_id, &self.borrowed)?));
+                return Ok(Some((position, T::decode(info.element_id, &self.borrowed)?)));
             }
 
             if let Some(limit) = self.buffer_size_limit {
@@ -108,6 +141,35 @@ impl<I: Buf, S: Stream<Item = Result<I, WebmetroError>> + Unpin> EbmlStreamingPa
             }
         }
     }
+
+    /// how many bytes of the stream have been parsed into elements
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// how many bytes have been read but not yet parsed; once the stream
+    /// has ended, these are the remains of an incomplete element
+    pub fn unparsed_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// describes the element at the start of the buffer, which must have a complete header
+    fn position_of_next(&self) -> Result<ElementPosition, WebmetroError> {
+        let incomplete = || WebmetroError::from("Element header incomplete");
+        let (_, id_size) = decode_varint(&self.buffer)?.ok_or_else(incomplete)?;
+        let (element_id, payload_size, header_size) = decode_tag(&self.buffer)?.ok_or_else(incomplete)?;
+
+        Ok(ElementPosition {
+            offset: self.consumed,
+            element_id,
+            id_size,
+            header_size,
+            payload_size: match payload_size {
+                Varint::Value(size) => Some(size),
+                Varint::Unknown => None
+            }
+        })
+    }
 }
 
 #[cfg(test)]
@@ -136,7 +198,7 @@ mod tests {
 
             assert_matches!(
                 stream_parser.poll_event(cx),
-                Ready(Some(Ok(WebmElement::EbmlHead)))
+                Ready(Some(Ok(WebmElement::EbmlHead(_))))
             );
             assert_matches!(
                 stream_parser.poll_event(cx),
@@ -186,7 +248,7 @@ mod tests {
                 .map(|bytes| Ok(bytes.into_buf()))
                 .parse_ebml();
 
-            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
+            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead(_)));
             assert_matches!(parser.next().await?, Some(WebmElement::Segment));
             assert_matches!(parser.next().await?, Some(WebmElement::Tracks(_)));
             assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
@@ -201,4 +263,57 @@ mod tests {
         .expect("Test tried to block on I/O")
         .expect("Parse failed");
     }
+
+    #[test]
+    fn element_positions_test() {
+        let pieces = [
+            &ENCODE_WEBM_TEST_FILE[0..20],
+            &ENCODE_WEBM_TEST_FILE[20..40],
+            &ENCODE_WEBM_TEST_FILE[40..],
+        ];
+
+        async {
+            let mut parser = futures3::stream::iter(pieces.iter())
+                .map(|bytes| Ok(bytes.into_buf()))
+                .parse_ebml();
+
+            let mut positions = Vec::new();
+            for _ in 0..6 {
+                let (position, _) = parser.next_with_position::<WebmElement>().await?.unwrap();
+                positions.push((position.offset, position.encoded_id(), position.header_size, position.payload_size));
+            }
+
+            assert_eq!(positions, vec![
+                (0, 0x1A45DFA3, 5, Some(7)),
+                (12, 0x18538067, 5, None),
+                (17, 0x1654AE6B, 5, Some(0)),
+                (22, 0x1F43B675, 5, None),
+                (27, 0xE7, 2, Some(1)),
+                (30, 0xA3, 2, Some(16)),
+            ]);
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+        .now_or_never()
+        .expect("Test tried to block on I/O")
+        .expect("Parse failed");
+    }
+
+    #[test]
+    fn truncated_input_test() {
+        async {
+            let mut parser = futures3::stream::iter(Some(&ENCODE_WEBM_TEST_FILE[..40]))
+                .map(|bytes| Ok(bytes.into_buf()))
+                .parse_ebml();
+
+            while parser.next::<WebmElement>().await?.is_some() {}
+            assert_eq!(parser.consumed(), 30);
+            assert_eq!(parser.unparsed_len(), 10);
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+        .now_or_never()
+        .expect("Test tried to block on I/O")
+        .expect("Parse failed");
+    }
 }
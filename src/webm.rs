@@ -1,22 +1,153 @@
-use std::io::{Cursor, Error as IoError, ErrorKind, Result as IoResult, Write, Seek};
+use std::io::{Cursor, Error as IoError, ErrorKind, Result as IoResult, Write};
 use bytes::{BigEndian, BufMut, ByteOrder};
 use crate::ebml::*;
 use crate::iterator::ebml_iter;
 use crate::iterator::EbmlIterator;
 
-const SEGMENT_ID: u64 = 0x08538067;
-const SEEK_HEAD_ID: u64 = 0x014D9B74;
-const SEGMENT_INFO_ID: u64 = 0x0549A966;
-const CUES_ID: u64 = 0x0C53BB6B;
-const TRACKS_ID: u64 = 0x0654AE6B;
-const CLUSTER_ID: u64 = 0x0F43B675;
-const TIMECODE_ID: u64 = 0x67;
+const EBML_VERSION_ID: u64 = 0x0286;
+const EBML_READ_VERSION_ID: u64 = 0x02F7;
+const EBML_MAX_ID_LENGTH_ID: u64 = 0x02F2;
+const EBML_MAX_SIZE_LENGTH_ID: u64 = 0x02F3;
+const DOC_TYPE_VERSION_ID: u64 = 0x0287;
+const DOC_TYPE_READ_VERSION_ID: u64 = 0x0285;
+pub(crate) const SEGMENT_ID: u64 = 0x08538067;
+pub(crate) const SEEK_HEAD_ID: u64 = 0x014D9B74;
+pub(crate) const SEGMENT_INFO_ID: u64 = 0x0549A966;
+pub(crate) const CUES_ID: u64 = 0x0C53BB6B;
+pub(crate) const TRACKS_ID: u64 = 0x0654AE6B;
+const TRACK_ENTRY_ID: u64 = 0x2E;
+pub(crate) const CLUSTER_ID: u64 = 0x0F43B675;
+pub(crate) const TAGS_ID: u64 = 0x0254C367;
+pub(crate) const CHAPTERS_ID: u64 = 0x0043A770;
+pub(crate) const ATTACHMENTS_ID: u64 = 0x0941A469;
+pub(crate) const TIMECODE_ID: u64 = 0x67;
 const SIMPLE_BLOCK_ID: u64 = 0x23;
+const BLOCK_GROUP_ID: u64 = 0x20;
+const BLOCK_ID: u64 = 0x21;
+const REFERENCE_BLOCK_ID: u64 = 0x7B;
+
+const NO_LACING: u8 = 0b00;
+const XIPH_LACING: u8 = 0b01;
+const EBML_LACING: u8 = 0b11;
+
+const VIDEO_TRACK_TYPE: u64 = 1;
+const AUDIO_TRACK_TYPE: u64 = 2;
 
 pub fn parse_webm<'a, T: AsRef<[u8]> + ?Sized>(source: &'a T) -> EbmlIterator<'a, WebmElement> {
     ebml_iter(source.as_ref())
 }
 
+/// The contents of an EBML header; fields the source left out are None
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct EbmlHeader<'b> {
+    pub ebml_version: Option<u64>,
+    pub ebml_read_version: Option<u64>,
+    pub max_id_length: Option<u64>,
+    pub max_size_length: Option<u64>,
+    pub doc_type: Option<&'b str>,
+    pub doc_type_version: Option<u64>,
+    pub doc_type_read_version: Option<u64>
+}
+
+impl EbmlHeader<'static> {
+    /// a minimal header for a WebM stream
+    pub fn webm() -> Self {
+        EbmlHeader {
+            doc_type: Some("webm"),
+            ..EbmlHeader::default()
+        }
+    }
+}
+
+impl<'b> EbmlHeader<'b> {
+    /// the DocType, or the EBML default of "matroska" if it was left out
+    pub fn doc_type(&self) -> &'b str {
+        self.doc_type.unwrap_or("matroska")
+    }
+}
+
+/// Events from parsing the body of an EBML header
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum HeaderElement<'b> {
+    EbmlVersion(u64),
+    EbmlReadVersion(u64),
+    MaxIdLength(u64),
+    MaxSizeLength(u64),
+    DocType(&'b str),
+    DocTypeVersion(u64),
+    DocTypeReadVersion(u64),
+    Unknown(u64)
+}
+
+impl<'b> FromEbml<'b> for HeaderElement<'b> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<HeaderElement<'b>, EbmlError> {
+        match element_id {
+            EBML_VERSION_ID => decode_uint(bytes).map(HeaderElement::EbmlVersion),
+            EBML_READ_VERSION_ID => decode_uint(bytes).map(HeaderElement::EbmlReadVersion),
+            EBML_MAX_ID_LENGTH_ID => decode_uint(bytes).map(HeaderElement::MaxIdLength),
+            EBML_MAX_SIZE_LENGTH_ID => decode_uint(bytes).map(HeaderElement::MaxSizeLength),
+            DOC_TYPE_ID => decode_string(bytes).map(HeaderElement::DocType),
+            DOC_TYPE_VERSION_ID => decode_uint(bytes).map(HeaderElement::DocTypeVersion),
+            DOC_TYPE_READ_VERSION_ID => decode_uint(bytes).map(HeaderElement::DocTypeReadVersion),
+            _ => Ok(HeaderElement::Unknown(element_id))
+        }
+    }
+}
+
+/// Decodes the payload of an EBML header element
+pub fn parse_ebml_header(bytes: &[u8]) -> Result<EbmlHeader<'_>, EbmlError> {
+    let mut header = EbmlHeader::default();
+    let mut rest = bytes;
+
+    while let Some((element, element_size)) = HeaderElement::decode_element(rest)? {
+        rest = &rest[element_size..];
+        match element {
+            HeaderElement::EbmlVersion(version) => header.ebml_version = Some(version),
+            HeaderElement::EbmlReadVersion(version) => header.ebml_read_version = Some(version),
+            HeaderElement::MaxIdLength(length) => header.max_id_length = Some(length),
+            HeaderElement::MaxSizeLength(length) => header.max_size_length = Some(length),
+            HeaderElement::DocType(doc_type) => header.doc_type = Some(doc_type),
+            HeaderElement::DocTypeVersion(version) => header.doc_type_version = Some(version),
+            HeaderElement::DocTypeReadVersion(version) => header.doc_type_read_version = Some(version),
+            HeaderElement::Unknown(_) => {}
+        }
+    }
+
+    if !rest.is_empty() {
+        return Err(EbmlError::CorruptPayload);
+    }
+    Ok(header)
+}
+
+/// Writes an EBML header's payload, with just the fields it was given
+fn encode_ebml_header<T: Write + ?Sized>(header: &EbmlHeader, output: &mut T) -> IoResult<()> {
+    let integers = [
+        (EBML_VERSION_ID, header.ebml_version),
+        (EBML_READ_VERSION_ID, header.ebml_read_version),
+        (EBML_MAX_ID_LENGTH_ID, header.max_id_length),
+        (EBML_MAX_SIZE_LENGTH_ID, header.max_size_length)
+    ];
+    for &(tag, value) in integers.iter() {
+        if let Some(value) = value {
+            encode_integer(tag, value, output)?;
+        }
+    }
+    if let Some(doc_type) = header.doc_type {
+        encode_string(DOC_TYPE_ID, doc_type, output)?;
+    }
+    if let Some(version) = header.doc_type_version {
+        encode_integer(DOC_TYPE_VERSION_ID, version, output)?;
+    }
+    if let Some(version) = header.doc_type_read_version {
+        encode_integer(DOC_TYPE_READ_VERSION_ID, version, output)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct SimpleBlock<'b> {
     pub track: u64,
@@ -25,9 +156,126 @@ pub struct SimpleBlock<'b> {
     pub data: &'b[u8]
 }
 
+impl<'b> SimpleBlock<'b> {
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    /// Lists the sizes of the frames laced into this block's data,
+    /// failing if the lacing header is corrupt or doesn't fit the data.
+    pub fn frame_sizes(&self) -> Result<Vec<usize>, EbmlError> {
+        let lacing = (self.flags >> 1) & 0x03;
+        if lacing == NO_LACING {
+            return Ok(vec![self.data.len()]);
+        }
+
+        let (&last_frame, mut rest) = self.data.split_first().ok_or(EbmlError::CorruptPayload)?;
+        let frames = last_frame as usize + 1;
+        let mut sizes = Vec::with_capacity(frames);
+
+        match lacing {
+            XIPH_LACING => for _ in 1..frames {
+                // each size is a run of 255s ended by a smaller byte, all added together
+                let mut size = 0;
+                loop {
+                    let (&byte, next) = rest.split_first().ok_or(EbmlError::CorruptPayload)?;
+                    rest = next;
+                    size += byte as usize;
+                    if byte != 0xFF {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            },
+            EBML_LACING => for index in 1..frames {
+                // the first size is a varint, the rest are signed differences from the one before
+                let (value, length) = match decode_varint(rest)? {
+                    Some((Varint::Value(value), length)) => (value as i64, length),
+                    _ => return Err(EbmlError::CorruptPayload)
+                };
+                rest = &rest[length..];
+                let size = match sizes.last() {
+                    None if index == 1 => value,
+                    Some(&previous) => previous as i64 + value - ((1 << (7 * length - 1)) - 1),
+                    None => unreachable!()
+                };
+                if size < 0 {
+                    return Err(EbmlError::CorruptPayload);
+                }
+                sizes.push(size as usize);
+            },
+            _ => {
+                // fixed-size lacing: equal frames fill the data
+                if rest.len() % frames != 0 {
+                    return Err(EbmlError::CorruptPayload);
+                }
+                return Ok(vec![rest.len() / frames; frames]);
+            }
+        }
+
+        let laced: usize = sizes.iter().sum();
+        if laced > rest.len() {
+            return Err(EbmlError::CorruptPayload);
+        }
+        sizes.push(rest.len() - laced);
+        Ok(sizes)
+    }
+}
+
+/// A Block wrapped with extra details, such as its duration or the frames it
+/// references, as Matroska muxers write some blocks. The Block's flags don't mark
+/// keyframes; a group without any ReferenceBlock is a keyframe instead.
+///
+/// Only the timecode can be changed before the group is encoded again.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BlockGroup<'b> {
+    pub track: u64,
+    pub timecode: i16,
+    pub keyframe: bool,
+    /// the group's payload, to be written back out
+    payload: &'b[u8],
+    /// where the Block's timecode sits in the payload
+    timecode_offset: usize
+}
+
+impl<'b> BlockGroup<'b> {
+    fn decode(bytes: &'b[u8]) -> Result<BlockGroup<'b>, EbmlError> {
+        let mut block = None;
+        let mut keyframe = true;
+        for_each_child(bytes, |id, payload| {
+            match id {
+                BLOCK_ID => {
+                    let (decoded, timecode_offset) = decode_block(payload)?;
+                    let payload_offset = payload.as_ptr() as usize - bytes.as_ptr() as usize;
+                    block = Some((decoded.track, decoded.timecode, payload_offset + timecode_offset));
+                },
+                REFERENCE_BLOCK_ID => keyframe = false,
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        let (track, timecode, timecode_offset) = block.ok_or(EbmlError::CorruptPayload)?;
+        Ok(BlockGroup {track, timecode, keyframe, payload: bytes, timecode_offset})
+    }
+
+    /// The frame data carried by the group's Block
+    pub fn data(&self) -> &'b[u8] {
+        let mut data: &'b[u8] = &[];
+        // the Block already decoded once, when the group was
+        let _ = for_each_child(self.payload, |id, payload| {
+            if id == BLOCK_ID {
+                data = decode_block(payload)?.0.data;
+            }
+            Ok(())
+        });
+        data
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum WebmElement<'b> {
-    EbmlHead,
+    EbmlHead(EbmlHeader<'b>),
     Void,
     Segment,
     SeekHead,
@@ -37,6 +285,7 @@ pub enum WebmElement<'b> {
     Cluster,
     Timecode(u64),
     SimpleBlock(SimpleBlock<'b>),
+    BlockGroup(BlockGroup<'b>),
     Unknown(u64)
 }
 
@@ -52,7 +301,7 @@ impl<'b> FromEbml<'b> for WebmElement<'b> {
 
     fn decode(element_id: u64, bytes: &'b[u8]) -> Result<WebmElement<'b>, EbmlError> {
         match element_id {
-            EBML_HEAD_ID => Ok(WebmElement::EbmlHead),
+            EBML_HEAD_ID => parse_ebml_header(bytes).map(WebmElement::EbmlHead),
             VOID_ID => Ok(WebmElement::Void),
             SEGMENT_ID => Ok(WebmElement::Segment),
             SEEK_HEAD_ID => Ok(WebmElement::SeekHead),
@@ -62,12 +311,234 @@ impl<'b> FromEbml<'b> for WebmElement<'b> {
             CLUSTER_ID => Ok(WebmElement::Cluster),
             TIMECODE_ID => decode_uint(bytes).map(WebmElement::Timecode),
             SIMPLE_BLOCK_ID => decode_simple_block(bytes),
+            BLOCK_GROUP_ID => BlockGroup::decode(bytes).map(WebmElement::BlockGroup),
             _ => Ok(WebmElement::Unknown(element_id))
         }
     }
 }
 
+/// A video track's settings
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x60)]
+pub struct Video {
+    #[ebml(id = 0x30)]
+    pub pixel_width: Option<u64>,
+    #[ebml(id = 0x3A)]
+    pub pixel_height: Option<u64>
+}
+
+/// An audio track's settings
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x61)]
+pub struct Audio {
+    /// in Hz
+    #[ebml(id = 0x35)]
+    pub sampling_frequency: Option<f64>,
+    #[ebml(id = 0x1F)]
+    pub channels: Option<u64>
+}
+
+/// The basic properties of a track, as described in a Tracks element
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x2E)]
+pub struct TrackEntry {
+    #[ebml(id = 0x57, default)]
+    pub number: u64,
+    #[ebml(id = 0x03, default)]
+    pub track_type: u64,
+    /// e.g. "V_VP8" or "A_OPUS"
+    #[ebml(id = 0x06, default)]
+    pub codec_id: String,
+    /// codec-specific setup data, e.g. an Opus header
+    #[ebml(id = 0x23A2)]
+    pub codec_private: Option<Vec<u8>>,
+    /// nanoseconds per frame, if given
+    #[ebml(id = 0x03E383)]
+    pub default_duration: Option<u64>,
+    #[ebml(id = 0x60)]
+    pub video: Option<Video>,
+    #[ebml(id = 0x61)]
+    pub audio: Option<Audio>
+}
+
+impl TrackEntry {
+    pub fn is_video(&self) -> bool {
+        self.track_type == VIDEO_TRACK_TYPE
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.track_type == AUDIO_TRACK_TYPE
+    }
+
+    /// video tracks' frame dimensions
+    pub fn pixel_width(&self) -> Option<u64> {
+        self.video.as_ref().and_then(|video| video.pixel_width)
+    }
+
+    pub fn pixel_height(&self) -> Option<u64> {
+        self.video.as_ref().and_then(|video| video.pixel_height)
+    }
+
+    /// audio tracks' sample rate (in Hz) & channel count
+    pub fn sampling_frequency(&self) -> Option<f64> {
+        self.audio.as_ref().and_then(|audio| audio.sampling_frequency)
+    }
+
+    pub fn channels(&self) -> Option<u64> {
+        self.audio.as_ref().and_then(|audio| audio.channels)
+    }
+}
+
+/// A Tracks element, listing a segment's tracks
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x0654AE6B)]
+pub struct Tracks {
+    #[ebml(id = 0x2E)]
+    pub entries: Vec<TrackEntry>
+}
+
+/// An Info element, describing a segment as a whole
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x0549A966)]
+pub struct Info {
+    /// nanoseconds per timecode unit
+    #[ebml(id = 0x0AD7B1, default = 1000000)]
+    pub timecode_scale: u64,
+    /// in timecode units
+    #[ebml(id = 0x0489)]
+    pub duration: Option<f64>,
+    #[ebml(id = 0x3BA9)]
+    pub title: Option<String>,
+    #[ebml(id = 0x0D80)]
+    pub muxing_app: Option<String>,
+    #[ebml(id = 0x1741)]
+    pub writing_app: Option<String>
+}
+
+/// One entry of a SeekHead, giving where a top-level element is
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x0DBB)]
+pub struct Seek {
+    /// the element's ID, as encoded (including its length marker)
+    #[ebml(id = 0x13AB)]
+    pub id: Vec<u8>,
+    /// relative to the start of the Segment's payload
+    #[ebml(id = 0x13AC)]
+    pub position: u64
+}
+
+/// An index of where a segment's top-level elements are
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x014D9B74)]
+pub struct SeekHead {
+    #[ebml(id = 0x0DBB)]
+    pub seeks: Vec<Seek>
+}
+
+impl Seek {
+    /// The element ID this entry points to, as reported by the parser (without its length marker)
+    pub fn element_id(&self) -> Option<u64> {
+        match decode_varint(&self.id) {
+            Ok(Some((Varint::Value(id), length))) if length == self.id.len() => Some(id),
+            _ => None
+        }
+    }
+}
+
+/// Where a cue point's keyframe is, for one track
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x37)]
+pub struct CueTrackPositions {
+    #[ebml(id = 0x77)]
+    pub track: u64,
+    /// the Cluster's position, relative to the start of the Segment's payload
+    #[ebml(id = 0x71)]
+    pub cluster_position: u64,
+    /// the block's position, relative to the start of the Cluster's payload
+    #[ebml(id = 0x70)]
+    pub relative_position: Option<u64>
+}
+
+/// A seek point, usually at a video keyframe
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x3B)]
+pub struct CuePoint {
+    /// in timecode units
+    #[ebml(id = 0x33)]
+    pub time: u64,
+    #[ebml(id = 0x37)]
+    pub positions: Vec<CueTrackPositions>
+}
+
+/// A segment's index of seek points
+#[derive(Debug, PartialEq, Clone, Default, FromEbml, EbmlEncode)]
+#[ebml(id = 0x0C53BB6B)]
+pub struct Cues {
+    #[ebml(id = 0x3B)]
+    pub points: Vec<CuePoint>
+}
+
+/// Whether a stream with the `next` tracks can be spliced onto one with the
+/// `previous` tracks: both need the same track numbers, of the same types & codecs,
+/// with the same codec setup data.
+pub fn tracks_compatible(previous: &[TrackEntry], next: &[TrackEntry]) -> bool {
+    previous.len() == next.len() && previous.iter().all(|track| next.iter().any(|other|
+        other.number == track.number
+            && other.track_type == track.track_type
+            && other.codec_id == track.codec_id
+            && other.codec_private == track.codec_private
+    ))
+}
+
+/// Lists the tracks described by a Tracks element's payload;
+/// entries that can't be decoded are skipped with a warning, rather than losing the rest
+pub fn parse_tracks(tracks: &[u8]) -> Vec<TrackEntry> {
+    let mut entries = Vec::new();
+    let result = for_each_child(tracks, |id, payload| {
+        if id == TRACK_ENTRY_ID {
+            match TrackEntry::from_field(payload) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Skipping an unreadable TrackEntry: {}", err)
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        warn!("Tracks element is corrupt after {} entries: {}", entries.len(), err);
+    }
+    entries
+}
+
+/// Lists the tracks described by the Tracks element in a stream's headers, if any
+pub fn parse_header_tracks(headers: &[u8]) -> Vec<TrackEntry> {
+    parse_webm(headers).find_map(|element| match element {
+        WebmElement::Tracks(tracks) => Some(parse_tracks(tracks)),
+        _ => None
+    }).unwrap_or_default()
+}
+
+/// Finds the DocType declared by the EBML header in a stream's headers, if any
+pub fn parse_header_doc_type(headers: &[u8]) -> Option<&str> {
+    parse_webm(headers).find_map(|element| match element {
+        WebmElement::EbmlHead(header) => Some(header.doc_type()),
+        _ => None
+    })
+}
+
+/// Lists the track numbers of the video tracks described by a Tracks element's payload
+pub fn video_track_numbers(tracks: &[u8]) -> Vec<u64> {
+    parse_tracks(tracks).iter()
+        .filter(|entry| entry.is_video())
+        .map(|entry| entry.number)
+        .collect()
+}
+
 fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
+    decode_block(bytes).map(|(block, _)| WebmElement::SimpleBlock(block))
+}
+
+/// Decodes a SimpleBlock's or Block's payload, also giving where its timecode is
+fn decode_block(bytes: &[u8]) -> Result<(SimpleBlock<'_>, usize), EbmlError> {
     if let Ok(Some((Varint::Value(track), track_field_len))) = decode_varint(bytes) {
         let header_len = track_field_len + 2 + 1;
         if bytes.len() < header_len {
@@ -75,18 +546,18 @@ fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
         }
         let timecode = BigEndian::read_i16(&bytes[track_field_len..]);
         let flags = bytes[track_field_len + 2];
-        return Ok(WebmElement::SimpleBlock(SimpleBlock {
+        return Ok((SimpleBlock {
             track: track,
             timecode: timecode,
             flags: flags,
             data: &bytes[header_len..],
-        }))
+        }, track_field_len))
     } else {
         return Err(EbmlError::CorruptPayload);
     }
 }
 
-pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoResult<()> {
+pub fn encode_simple_block<T: Write + ?Sized>(block: SimpleBlock, output: &mut T) -> IoResult<()> {
     let SimpleBlock {
         track,
         timecode,
@@ -111,10 +582,21 @@ pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoRe
     output.write_all(data)
 }
 
-pub fn encode_webm_element<T: Write + Seek>(element: WebmElement, output: &mut T) -> IoResult<()> {
+pub fn encode_block_group<T: Write + ?Sized>(group: BlockGroup, output: &mut T) -> IoResult<()> {
+    let BlockGroup {timecode, payload, timecode_offset, ..} = group;
+    encode_tag_header(BLOCK_GROUP_ID, Varint::Value(payload.len() as u64), output)?;
+
+    output.write_all(&payload[..timecode_offset])?;
+    let mut buffer = Cursor::new([0; 2]);
+    buffer.put_i16_be(timecode);
+    output.write_all(&buffer.get_ref()[..])?;
+    output.write_all(&payload[timecode_offset + 2..])
+}
+
+pub fn encode_webm_element<T: Write + ?Sized>(element: WebmElement, output: &mut T) -> IoResult<()> {
     match element {
-        WebmElement::EbmlHead => encode_element(EBML_HEAD_ID, output, |output| {
-            encode_bytes(DOC_TYPE_ID, "webm".as_bytes(), output)
+        WebmElement::EbmlHead(header) => encode_element(EBML_HEAD_ID, output, |output| {
+            encode_ebml_header(&header, output)
         }),
         WebmElement::Segment => encode_tag_header(SEGMENT_ID, Varint::Unknown, output),
         WebmElement::SeekHead => Ok(()),
@@ -123,6 +605,7 @@ pub fn encode_webm_element<T: Write + Seek>(element: WebmElement, output: &mut T
         WebmElement::Cluster => encode_tag_header(CLUSTER_ID, Varint::Unknown, output),
         WebmElement::Timecode(time) => encode_integer(TIMECODE_ID, time, output),
         WebmElement::SimpleBlock(block) => encode_simple_block(block, output),
+        WebmElement::BlockGroup(group) => encode_block_group(group, output),
         WebmElement::Void => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
         WebmElement::Info => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
         WebmElement::Unknown(_) => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
@@ -142,7 +625,15 @@ mod tests {
         let mut iter = parse_webm(TEST_FILE);
 
         // test that we match the structure of the test file
-        assert_eq!(iter.next(), Some(WebmElement::EbmlHead));
+        assert_eq!(iter.next(), Some(WebmElement::EbmlHead(EbmlHeader {
+            ebml_version: Some(1),
+            ebml_read_version: Some(1),
+            max_id_length: Some(4),
+            max_size_length: Some(8),
+            doc_type: Some("webm"),
+            doc_type_version: Some(2),
+            doc_type_read_version: Some(2)
+        })));
         assert_eq!(iter.next(), Some(WebmElement::Segment));
         assert_eq!(iter.next(), Some(WebmElement::SeekHead));
         assert_eq!(iter.next(), Some(WebmElement::Void));
@@ -192,11 +683,191 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn find_video_tracks_test1() {
+        assert_eq!(video_track_numbers(&TEST_FILE[358..421]), vec![1]);
+        assert_eq!(video_track_numbers(&[]), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn parse_tracks_test1() {
+        assert_eq!(parse_tracks(&TEST_FILE[358..421]), vec![TrackEntry {
+            number: 1,
+            track_type: 1,
+            codec_id: String::from("V_VP9"),
+            default_duration: Some(33333333),
+            video: Some(Video {
+                pixel_width: Some(320),
+                pixel_height: Some(240)
+            }),
+            ..TrackEntry::default()
+        }]);
+        assert_eq!(parse_header_tracks(&TEST_FILE[..421]), parse_tracks(&TEST_FILE[358..421]));
+        assert_eq!(parse_header_doc_type(&TEST_FILE[..421]), Some("webm"));
+        assert_eq!(parse_header_doc_type(&TEST_FILE[358..421]), None);
+    }
+
+    #[test]
+    fn parse_tracks_skips_bad_entries() {
+        // an entry with an invalid CodecID, before the file's own entry
+        let mut tracks = vec![0xAE, 0x83, 0x86, 0x81, 0xFF];
+        tracks.extend_from_slice(&TEST_FILE[358..421]);
+        assert_eq!(parse_tracks(&tracks), parse_tracks(&TEST_FILE[358..421]));
+
+        // entries before a corrupt element are kept
+        tracks.drain(..5);
+        tracks.extend_from_slice(&[0xAE, 0x85, 0x00]);
+        assert_eq!(parse_tracks(&tracks), parse_tracks(&TEST_FILE[358..421]));
+    }
+
+    #[test]
+    fn block_groups() {
+        let group = [
+            0xA0, 0x8A, // BlockGroup
+                0xA1, 0x85, 0x81, 0x00, 0x10, 0x00, 0xAA, // Block on track 1 at 16ms
+                0x9B, 0x81, 0x28, // BlockDuration
+        ];
+        let mut referencing = group.to_vec();
+        referencing[1] += 3;
+        referencing.extend_from_slice(&[0xFB, 0x81, 0xD8]); // ReferenceBlock
+
+        let mut iter = parse_webm(&group[..]);
+        let mut decoded = match iter.next() {
+            Some(WebmElement::BlockGroup(group)) => group,
+            other => panic!("Expected a BlockGroup, got {:?}", other)
+        };
+        assert_eq!((decoded.track, decoded.timecode, decoded.keyframe), (1, 16, true));
+        assert_eq!(decoded.data(), &[0xAA]);
+        assert_eq!(iter.next(), None);
+
+        decoded.timecode = -2;
+        let mut cursor = Cursor::new(Vec::new());
+        encode_block_group(decoded, &mut cursor).unwrap();
+        let mut moved = group.to_vec();
+        moved[5..7].copy_from_slice(&[0xFF, 0xFE]);
+        assert_eq!(cursor.into_inner(), moved);
+
+        assert!(matches!(parse_webm(&referencing).next(), Some(WebmElement::BlockGroup(BlockGroup {keyframe: false, ..}))));
+        // a group needs its Block
+        assert!(WebmElement::decode(BLOCK_GROUP_ID, &[0x9B, 0x81, 0x28]).is_err());
+    }
+
+    #[test]
+    fn block_frame_sizes() {
+        let block = |flags, data| SimpleBlock {track: 1, timecode: 0, flags, data};
+
+        assert_eq!(block(0x80, &[0; 5]).frame_sizes().unwrap(), vec![5]);
+
+        // Xiph lacing: 3 frames, of 256 & 3 bytes then the remainder
+        let mut xiph = vec![0x02, 0xFF, 0x01, 0x03];
+        xiph.extend_from_slice(&[0; 261]);
+        assert_eq!(block(0x02, &xiph).frame_sizes().unwrap(), vec![256, 3, 2]);
+        assert!(block(0x02, &xiph[..100]).frame_sizes().is_err());
+
+        // EBML lacing: 3 frames, of 2 bytes, then 2 + 1 bytes, then the remainder
+        let ebml = [0x02, 0x82, 0xC0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(block(0x06, &ebml).frame_sizes().unwrap(), vec![2, 3, 4]);
+        assert!(block(0x06, &ebml[..6]).frame_sizes().is_err());
+
+        // fixed-size lacing: 2 equal frames
+        assert_eq!(block(0x04, &[0x01, 0, 0, 0, 0]).frame_sizes().unwrap(), vec![2, 2]);
+        assert!(block(0x04, &[0x01, 0, 0, 0]).frame_sizes().is_err());
+
+        assert!(block(0x02, &[]).frame_sizes().is_err());
+    }
+
+    #[test]
+    fn parse_audio_track() {
+        let tracks = [
+            0xAE, 0xA3, // TrackEntry
+                0xD7, 0x81, 0x02, // TrackNumber
+                0x83, 0x81, 0x02, // TrackType
+                0x86, 0x86, b'A', b'_', b'O', b'P', b'U', b'S', // CodecID
+                0x63, 0xA2, 0x83, 0x01, 0x02, 0x03, // CodecPrivate
+                0xE1, 0x8D, // Audio
+                    0xB5, 0x88, 0x40, 0xE7, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00, // SamplingFrequency
+                    0x9F, 0x81, 0x02 // Channels
+        ];
+
+        let entries = parse_tracks(&tracks);
+        assert_eq!(entries, vec![TrackEntry {
+            number: 2,
+            track_type: 2,
+            codec_id: String::from("A_OPUS"),
+            codec_private: Some(vec![1, 2, 3]),
+            audio: Some(Audio {
+                sampling_frequency: Some(48000.0),
+                channels: Some(2)
+            }),
+            ..TrackEntry::default()
+        }]);
+        assert!(entries[0].is_audio());
+    }
+
+    #[test]
+    fn tracks_round_trip() {
+        let tracks = Tracks::decode(0x0654AE6B, &TEST_FILE[358..421]).unwrap();
+        assert_eq!(tracks.entries, parse_tracks(&TEST_FILE[358..421]));
+        assert_eq!(tracks.entries[0].pixel_width(), Some(320));
+        assert_eq!(tracks.entries[0].channels(), None);
+
+        let mut cursor = Cursor::new(Vec::new());
+        tracks.encode(&mut cursor).unwrap();
+        assert_eq!(parse_header_tracks(cursor.get_ref()), tracks.entries);
+    }
+
+    #[test]
+    fn parse_info() {
+        let info = Info::decode(0x0549A966, &TEST_FILE[296..346]).unwrap();
+        assert_eq!(info, Info {
+            timecode_scale: 1000000,
+            duration: Some(3000.0),
+            title: None,
+            muxing_app: Some("Lavf57.56.100".into()),
+            writing_app: Some("Lavf57.56.100".into())
+        });
+
+        let mut cursor = Cursor::new(Vec::new());
+        Info {
+            title: Some("Café".into()),
+            ..info.clone()
+        }.encode(&mut cursor).unwrap();
+        let (reencoded, _) = Info::decode_element(cursor.get_ref()).unwrap().unwrap();
+        assert_eq!(reencoded.title.as_deref(), Some("Café"));
+        assert_eq!(reencoded.duration, info.duration);
+
+        // an empty Info takes the default scale
+        assert_eq!(Info::decode(0x0549A966, &[]).unwrap().timecode_scale, 1000000);
+    }
+
+    #[test]
+    fn tracks_compatible_test() {
+        let tracks = parse_tracks(&TEST_FILE[358..421]);
+        assert!(tracks_compatible(&tracks, &tracks));
+
+        let mut other_duration = tracks.clone();
+        other_duration[0].default_duration = None;
+        assert!(tracks_compatible(&tracks, &other_duration));
+
+        let mut other_codec = tracks.clone();
+        other_codec[0].codec_id = String::from("V_VP8");
+        assert!(!tracks_compatible(&tracks, &other_codec));
+
+        let mut other_setup = tracks.clone();
+        other_setup[0].codec_private = Some(vec![1, 2, 3]);
+        assert!(!tracks_compatible(&tracks, &other_setup));
+
+        let mut extra_track = tracks.clone();
+        extra_track.push(TrackEntry {number: 2, track_type: 2, codec_id: String::from("A_OPUS"), ..TrackEntry::default()});
+        assert!(!tracks_compatible(&tracks, &extra_track));
+        assert!(!tracks_compatible(&extra_track, &tracks));
+    }
+
     #[test]
     fn encode_webm_test() {
         let mut cursor = Cursor::new(Vec::new());
 
-        encode_webm_element(WebmElement::EbmlHead, &mut cursor).unwrap();
+        encode_webm_element(WebmElement::EbmlHead(EbmlHeader::webm()), &mut cursor).unwrap();
         encode_webm_element(WebmElement::Segment, &mut cursor).unwrap();
 
         encode_webm_element(WebmElement::Tracks(&[]), &mut cursor).unwrap();
@@ -217,4 +888,24 @@ mod tests {
         assert_eq!(cursor.get_ref(), &ENCODE_WEBM_TEST_FILE);
     }
 
+    #[test]
+    fn reencode_matches_test1() {
+        // test1.webm's header & cluster timecodes use minimal-size integers,
+        // so re-encoding them should reproduce its bytes exactly
+        let header = match parse_webm(TEST_FILE).next() {
+            Some(WebmElement::EbmlHead(header)) => header,
+            other => panic!("expected an EBML header, got {:?}", other)
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead(header), &mut cursor).unwrap();
+        // the header's size is written in 1 byte here, but in 8 in the file
+        assert_eq!(&cursor.get_ref()[..4], &TEST_FILE[..4]);
+        assert_eq!(cursor.get_ref()[4], 0x80 | 31);
+        assert_eq!(&cursor.get_ref()[5..], &TEST_FILE[12..43]);
+
+        let mut cursor = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::Timecode(0), &mut cursor).unwrap();
+        assert_eq!(&cursor.get_ref()[..], &TEST_FILE[433..436]);
+    }
+
 }
@@ -0,0 +1,585 @@
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use futures3::prelude::*;
+
+use crate::chunk::{Chunk, WebmStream};
+use crate::ebml::{decode_tag, decode_uint, EbmlError, FromEbmlField, Varint, EBML_HEAD_ID};
+use crate::error::WebmetroError;
+use crate::fixers::ChunkStream;
+use crate::stream_parser::StreamEbml;
+use crate::webm::*;
+
+/// IDs of the elements that can appear directly within a Segment
+const TOP_LEVEL_IDS: [u64; 8] = [
+    SEEK_HEAD_ID, SEGMENT_INFO_ID, TRACKS_ID, CLUSTER_ID,
+    CUES_ID, TAGS_ID, CHAPTERS_ID, ATTACHMENTS_ID
+];
+
+/// the most bytes an element's ID & size can take up
+const MAX_TAG_LEN: u64 = 4 + 8;
+
+/// An element's position & header, as read from the file
+#[derive(Debug, PartialEq, Copy, Clone)]
+struct Tag {
+    id: u64,
+    offset: u64,
+    header_size: u64,
+    size: Varint
+}
+
+impl Tag {
+    fn payload_offset(&self) -> u64 {
+        self.offset + self.header_size
+    }
+}
+
+/// A cluster's position & timecode, from scanning the file
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ClusterPosition {
+    pub offset: u64,
+    pub timecode: u64
+}
+
+/// Reads a WebM file out of order, fetching only the parts that are asked for.
+///
+/// Opening the file reads the EBML header & the top-level elements before the
+/// first Cluster; anything else (e.g. Cues at the end of the file) is found via
+/// the SeekHead, or else by scanning through the file's clusters.
+pub struct WebmFile<R: Read + Seek> {
+    reader: R,
+    /// the whole EBML header element, and where its payload starts within it
+    header: Vec<u8>,
+    header_payload: usize,
+    /// where the Segment's payload starts & ends
+    segment_start: u64,
+    segment_end: u64,
+    /// top-level elements found so far, as (ID, offset)
+    elements: Vec<(u64, u64)>,
+    /// where the SeekHead says top-level elements are, as (ID, offset)
+    seek_head: Vec<(u64, u64)>,
+    /// every cluster in the file, once scanned for
+    clusters: Option<Vec<ClusterPosition>>,
+    cues: Option<Option<Cues>>
+}
+
+impl<R: Read + Seek> WebmFile<R> {
+    pub fn open(mut reader: R) -> Result<WebmFile<R>, WebmetroError> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+
+        let header_tag = match read_tag(&mut reader, 0)? {
+            Some(tag) if tag.id == EBML_HEAD_ID => tag,
+            _ => return Err("File doesn't start with an EBML header".into())
+        };
+        let header_end = known_end(&header_tag)?;
+        let header = read_bytes(&mut reader, 0, header_end, file_len)?;
+        parse_ebml_header(&header[header_tag.header_size as usize..])?;
+
+        let segment_tag = match read_tag(&mut reader, header_end)? {
+            Some(tag) if tag.id == SEGMENT_ID => tag,
+            _ => return Err("EBML header isn't followed by a Segment".into())
+        };
+        let segment_start = segment_tag.payload_offset();
+        let segment_end = match segment_tag.size {
+            Varint::Value(size) => (segment_start + size).min(file_len),
+            Varint::Unknown => file_len
+        };
+
+        let mut file = WebmFile {
+            reader,
+            header,
+            header_payload: header_tag.header_size as usize,
+            segment_start,
+            segment_end,
+            elements: Vec::new(),
+            seek_head: Vec::new(),
+            clusters: None,
+            cues: None
+        };
+
+        // index the top-level elements up to the first cluster
+        let mut offset = segment_start;
+        while let Some(tag) = file.read_top_level_tag(offset)? {
+            file.elements.push((tag.id, tag.offset));
+            if tag.id == CLUSTER_ID {
+                break;
+            }
+            offset = file.element_end(&tag)?;
+        }
+
+        if let Some(seek_head) = file.read_element(SEEK_HEAD_ID)? {
+            let seek_head = SeekHead::from_field(&seek_head)?;
+            file.seek_head = seek_head.seeks.iter()
+                .filter_map(|seek| Some((seek.element_id()?, segment_start.checked_add(seek.position)?)))
+                .collect();
+        }
+
+        Ok(file)
+    }
+
+    /// The file's EBML header, as a complete element
+    pub fn header_bytes(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn ebml_header(&self) -> EbmlHeader<'_> {
+        // already checked when opening
+        parse_ebml_header(&self.header[self.header_payload..]).unwrap_or_default()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the payload of a top-level element, if the file has one with the given ID
+    pub fn read_element(&mut self, id: u64) -> Result<Option<Vec<u8>>, WebmetroError> {
+        let offset = match self.find_element(id)? {
+            Some(offset) => offset,
+            None => return Ok(None)
+        };
+        let tag = match self.read_top_level_tag(offset)? {
+            Some(tag) if tag.id == id => tag,
+            _ => return Err(format!("Expected element {:X} at offset {}", id, offset).as_str().into())
+        };
+        let end = known_end(&tag)?;
+        Ok(Some(read_bytes(&mut self.reader, tag.payload_offset(), end, self.segment_end)?))
+    }
+
+    pub fn info(&mut self) -> Result<Option<Info>, WebmetroError> {
+        match self.read_element(SEGMENT_INFO_ID)? {
+            Some(payload) => Ok(Some(Info::from_field(&payload)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn tracks(&mut self) -> Result<Option<Tracks>, WebmetroError> {
+        match self.read_element(TRACKS_ID)? {
+            Some(payload) => Ok(Some(Tracks::from_field(&payload)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// The file's Cues, if it has any
+    pub fn cues(&mut self) -> Result<Option<&Cues>, WebmetroError> {
+        if self.cues.is_none() {
+            let cues = match self.read_element(CUES_ID)? {
+                Some(payload) => Some(Cues::from_field(&payload)?),
+                None => None
+            };
+            self.cues = Some(cues);
+        }
+        Ok(self.cues.as_ref().and_then(Option::as_ref))
+    }
+
+    /// Lists every cluster in the file, reading just enough of each to find its timecode
+    pub fn clusters(&mut self) -> Result<&[ClusterPosition], WebmetroError> {
+        if self.clusters.is_none() {
+            self.scan()?;
+        }
+        Ok(self.clusters.as_ref().map_or(&[], Vec::as_slice))
+    }
+
+    /// Finds the cluster to start at to play from the given timecode (in the file's timecode units):
+    /// the last one starting at or before it, or the first cluster if it comes before them all.
+    /// Uses the Cues if there are any, or else scans the file's clusters.
+    pub fn seek_to_time(&mut self, timecode: u64) -> Result<Option<u64>, WebmetroError> {
+        let segment_start = self.segment_start;
+        if let Some(cues) = self.cues()? {
+            let points = cues.points.iter().filter_map(|point| {
+                Some((point.time, segment_start.checked_add(point.positions.first()?.cluster_position)?))
+            });
+            let point = points.clone().filter(|&(time, _)| time <= timecode).max_by_key(|&(time, _)| time)
+                .or_else(|| points.min_by_key(|&(time, _)| time));
+            if let Some((_, position)) = point {
+                return Ok(Some(position));
+            }
+        }
+
+        let clusters = self.clusters()?;
+        Ok(clusters.iter().rev().find(|cluster| cluster.timecode <= timecode)
+            .or_else(|| clusters.first())
+            .map(|cluster| cluster.offset))
+    }
+
+    /// Streams the file like a live source joined partway through: its headers,
+    /// then clusters from the last keyframe at or before the given time (in
+    /// milliseconds), with timecodes rebased to start at zero
+    pub fn stream_from(self, milliseconds: u64) -> Result<impl Stream<Item = Result<Chunk, WebmetroError>> + Unpin, WebmetroError> {
+        let (start, pieces) = self.pieces_from(milliseconds)?;
+        Ok(play_pieces(start, stream::iter(pieces)))
+    }
+
+    /// Finds what `stream_from` plays, without parsing it: the timecode to clip to,
+    /// and an iterator reading the file's headers then each cluster after the starting
+    /// point. Clusters are only read as the iterator is advanced, which blocks on the
+    /// reader; pass the pieces to `play_pieces` to turn them into chunks.
+    pub fn pieces_from(mut self, milliseconds: u64) -> Result<(u64, impl Iterator<Item = Result<Vec<u8>, WebmetroError>>), WebmetroError> {
+        let timecode_scale = self.info()?.map_or(1_000_000, |info| info.timecode_scale).max(1);
+        let start = (milliseconds as u128 * 1_000_000 / timecode_scale as u128) as u64;
+
+        let first_cluster = self.next_cluster(self.segment_start)?;
+        let headers = read_bytes(&mut self.reader, 0, first_cluster.unwrap_or(self.segment_end), self.segment_end)?;
+        let mut next = match first_cluster {
+            Some(_) => self.seek_to_time(start)?,
+            None => None
+        };
+
+        let clusters = std::iter::from_fn(move || {
+            let offset = next.take()?;
+            let cluster = self.read_cluster(offset)
+                .and_then(|(cluster, end)| {
+                    next = self.next_cluster(end)?;
+                    Ok(cluster)
+                });
+            Some(cluster)
+        });
+
+        Ok((start, std::iter::once(Ok(headers)).chain(clusters)))
+    }
+
+    /// Finds the first cluster at or after the given offset, skipping other top-level elements
+    pub fn next_cluster(&mut self, mut offset: u64) -> Result<Option<u64>, WebmetroError> {
+        while let Some(tag) = self.read_top_level_tag(offset)? {
+            if tag.id == CLUSTER_ID {
+                return Ok(Some(offset));
+            }
+            offset = self.element_end(&tag)?;
+        }
+        Ok(None)
+    }
+
+    /// Reads the whole Cluster element at the given offset,
+    /// returning it with the offset of whatever follows it
+    pub fn read_cluster(&mut self, offset: u64) -> Result<(Vec<u8>, u64), WebmetroError> {
+        let tag = match self.read_top_level_tag(offset)? {
+            Some(tag) if tag.id == CLUSTER_ID => tag,
+            _ => return Err(format!("No Cluster at offset {}", offset).as_str().into())
+        };
+        let end = self.element_end(&tag)?;
+        Ok((read_bytes(&mut self.reader, offset, end, self.segment_end)?, end))
+    }
+
+    /// Reads the tag of a top-level element in the segment;
+    /// None at the end of the segment, or if another file's EBML header starts there
+    fn read_top_level_tag(&mut self, offset: u64) -> Result<Option<Tag>, WebmetroError> {
+        if offset >= self.segment_end {
+            return Ok(None);
+        }
+        match read_tag(&mut self.reader, offset)? {
+            Some(tag) if tag.id == EBML_HEAD_ID => Ok(None),
+            tag => Ok(tag)
+        }
+    }
+
+    /// Where the given top-level element ends. Unknown-size elements end where
+    /// the next top-level element starts, which means stepping through their children.
+    fn element_end(&mut self, tag: &Tag) -> Result<u64, WebmetroError> {
+        if let Varint::Value(_) = tag.size {
+            return Ok(known_end(tag)?.min(self.segment_end));
+        }
+
+        let mut offset = tag.payload_offset();
+        while offset < self.segment_end {
+            let child = match read_tag(&mut self.reader, offset)? {
+                Some(child) => child,
+                None => break
+            };
+            if child.id == EBML_HEAD_ID || TOP_LEVEL_IDS.contains(&child.id) {
+                return Ok(offset);
+            }
+            offset = known_end(&child)?;
+        }
+        Ok(self.segment_end)
+    }
+
+    /// Where a top-level element is, from what's been read so far, the SeekHead, or failing those, a scan
+    fn find_element(&mut self, id: u64) -> Result<Option<u64>, WebmetroError> {
+        let known = |elements: &[(u64, u64)]| elements.iter()
+            .find(|&&(element_id, _)| element_id == id)
+            .map(|&(_, offset)| offset);
+
+        if let Some(offset) = known(&self.elements).or_else(|| known(&self.seek_head)) {
+            return Ok(Some(offset));
+        }
+        if self.clusters.is_none() {
+            self.scan()?;
+            return Ok(known(&self.elements));
+        }
+        Ok(None)
+    }
+
+    /// Steps through every top-level element, noting where each is and each cluster's timecode
+    fn scan(&mut self) -> Result<(), WebmetroError> {
+        let mut clusters = Vec::new();
+        let mut offset = self.segment_start;
+
+        while let Some(tag) = self.read_top_level_tag(offset)? {
+            if !self.elements.contains(&(tag.id, offset)) {
+                self.elements.push((tag.id, offset));
+            }
+            let end = self.element_end(&tag)?;
+            if tag.id == CLUSTER_ID {
+                let timecode = self.cluster_timecode(&tag, end)?;
+                clusters.push(ClusterPosition { offset, timecode });
+            }
+            offset = end;
+        }
+
+        self.clusters = Some(clusters);
+        Ok(())
+    }
+
+    /// Finds a cluster's timecode among its first children
+    fn cluster_timecode(&mut self, tag: &Tag, end: u64) -> Result<u64, WebmetroError> {
+        let mut offset = tag.payload_offset();
+        while offset < end {
+            let child = read_tag(&mut self.reader, offset)?.ok_or(EbmlError::CorruptPayload)?;
+            let child_end = known_end(&child)?;
+            if child.id == TIMECODE_ID {
+                return Ok(decode_uint(&read_bytes(&mut self.reader, child.payload_offset(), child_end, end)?)?);
+            }
+            offset = child_end;
+        }
+        Err(format!("Cluster at offset {} has no Timecode", tag.offset).as_str().into())
+    }
+}
+
+/// Parses the pieces of a file read by `WebmFile::pieces_from` into chunks, starting at the given timecode
+pub fn play_pieces<S>(start: u64, pieces: S) -> impl Stream<Item = Result<Chunk, WebmetroError>> + Unpin
+where S: Stream<Item = Result<Vec<u8>, WebmetroError>> + Unpin {
+    pieces.map_ok(Cursor::new)
+        .parse_ebml()
+        .chunk_webm()
+        .clip_range(start, None)
+}
+
+/// Reads the element tag at the given offset; None at the end of the file
+fn read_tag<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Option<Tag>, WebmetroError> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::with_capacity(MAX_TAG_LEN as usize);
+    reader.take(MAX_TAG_LEN).read_to_end(&mut bytes)?;
+
+    Ok(decode_tag(&bytes)?.map(|(id, size, header_size)| Tag {
+        id,
+        offset,
+        header_size: header_size as u64,
+        size
+    }))
+}
+
+/// Where an element ends, if it has a known size
+fn known_end(tag: &Tag) -> Result<u64, EbmlError> {
+    match tag.size {
+        Varint::Value(size) => Ok(tag.payload_offset() + size),
+        Varint::Unknown => Err(EbmlError::UnknownElementLength)
+    }
+}
+
+/// Reads the bytes from `start` to `end`, which must not run past `limit` (the end of
+/// whatever holds them), so a corrupt size can't make it allocate more than the file has
+fn read_bytes<R: Read + Seek>(reader: &mut R, start: u64, end: u64, limit: u64) -> Result<Vec<u8>, WebmetroError> {
+    if start > end || end > limit {
+        return Err(EbmlError::CorruptPayload.into());
+    }
+    reader.seek(SeekFrom::Start(start))?;
+    let mut bytes = vec![0; (end - start) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures3::prelude::*;
+    use matches::assert_matches;
+
+    use crate::chunk::{Chunk, WebmStream};
+    use crate::ebml::{EbmlNode, EbmlValue};
+    use crate::error::WebmetroError;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{
+        TEST_FILE,
+        ENCODE_WEBM_TEST_FILE
+    };
+    use crate::webm_file::*;
+
+    #[test]
+    fn read_test1() {
+        let mut file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+
+        assert_eq!(file.header_bytes(), &TEST_FILE[..43]);
+        assert_eq!(file.ebml_header().doc_type, Some("webm"));
+        assert_eq!(file.info().unwrap().unwrap().duration, Some(3000.0));
+        assert_eq!(file.tracks().unwrap().unwrap().entries[0].number, 1);
+
+        let cues = file.cues().unwrap().unwrap();
+        assert_eq!(cues.points.len(), 3);
+        assert_eq!(cues.points[1].time, 1000);
+    }
+
+    #[test]
+    fn seek_by_cues() {
+        let mut file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+
+        assert_eq!(file.seek_to_time(0).unwrap(), Some(421));
+        assert_eq!(file.seek_to_time(999).unwrap(), Some(421));
+        assert_eq!(file.seek_to_time(1500).unwrap(), Some(13739));
+        assert_eq!(file.seek_to_time(5000).unwrap(), Some(34814));
+        // the cues were enough, without scanning the file
+        assert_eq!(file.clusters, None);
+
+        let (cluster, next) = file.read_cluster(13739).unwrap();
+        assert_eq!(&cluster[..], &TEST_FILE[13739..34814]);
+        assert_eq!(next, 34814);
+        assert_eq!(file.next_cluster(next).unwrap(), Some(34814));
+        let (_, next) = file.read_cluster(34814).unwrap();
+        assert_eq!(file.next_cluster(next).unwrap(), None);
+    }
+
+    #[test]
+    fn scan_test1_clusters() {
+        let mut file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+
+        assert_eq!(file.clusters().unwrap(), &[
+            ClusterPosition { offset: 421, timecode: 0 },
+            ClusterPosition { offset: 13739, timecode: 1000 },
+            ClusterPosition { offset: 34814, timecode: 2000 }
+        ]);
+    }
+
+    #[test]
+    fn seek_without_cues() {
+        // unknown-size segment & clusters, with no SeekHead or Cues
+        let mut file = WebmFile::open(Cursor::new(ENCODE_WEBM_TEST_FILE)).unwrap();
+
+        assert!(file.cues().unwrap().is_none());
+        assert!(file.info().unwrap().is_none());
+        assert_eq!(file.tracks().unwrap().unwrap().entries, vec![]);
+
+        assert_eq!(file.seek_to_time(0).unwrap(), Some(22));
+        assert_eq!(file.seek_to_time(999).unwrap(), Some(22));
+        assert_eq!(file.seek_to_time(1000).unwrap(), Some(48));
+
+        let (cluster, next) = file.read_cluster(22).unwrap();
+        assert_eq!(&cluster[..], &ENCODE_WEBM_TEST_FILE[22..48]);
+        assert_eq!(next, 48);
+    }
+
+    #[test]
+    fn stop_at_concatenated_file() {
+        let mut bytes = ENCODE_WEBM_TEST_FILE.to_vec();
+        bytes.extend_from_slice(ENCODE_WEBM_TEST_FILE);
+        let mut file = WebmFile::open(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(file.clusters().unwrap(), &[
+            ClusterPosition { offset: 22, timecode: 0 },
+            ClusterPosition { offset: 48, timecode: 1000 }
+        ]);
+        let (_, next) = file.read_cluster(48).unwrap();
+        assert_eq!(next, ENCODE_WEBM_TEST_FILE.len() as u64);
+        assert_eq!(file.next_cluster(next).unwrap(), None);
+    }
+
+    fn cluster_starts(file: WebmFile<Cursor<&[u8]>>, milliseconds: u64) -> Vec<u64> {
+        let chunks: Vec<Chunk> = file.stream_from(milliseconds).unwrap()
+            .try_collect().now_or_never().expect("Test tried to block on I/O").unwrap();
+
+        assert_matches!(chunks[0], Chunk::Headers {..});
+        chunks.iter().filter_map(|chunk| match chunk {
+            Chunk::ClusterHead(cluster_head) => Some(cluster_head.start),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn stream_from_start() {
+        let file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+        assert_eq!(cluster_starts(file, 0), vec![0, 1000, 2000]);
+    }
+
+    #[test]
+    fn stream_from_keyframe() {
+        let file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+        assert_eq!(cluster_starts(file, 1500), vec![0, 1000]);
+
+        let file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+        assert_eq!(cluster_starts(file, 2000), vec![0]);
+    }
+
+    #[test]
+    fn stream_headers_match_live() {
+        let file = WebmFile::open(Cursor::new(TEST_FILE)).unwrap();
+        let first: Option<Result<Chunk, WebmetroError>> = file.stream_from(2500).unwrap()
+            .next().now_or_never().unwrap();
+        let live: Option<Result<Chunk, WebmetroError>> = stream::iter(Some(Ok(Cursor::new(TEST_FILE))))
+            .parse_ebml()
+            .chunk_webm()
+            .next().now_or_never().unwrap();
+
+        assert_eq!(first.unwrap().unwrap().as_ref(), live.unwrap().unwrap().as_ref());
+    }
+
+    #[test]
+    fn reject_non_webm() {
+        assert!(WebmFile::open(Cursor::new(&b"not a WebM file"[..])).is_err());
+        assert!(WebmFile::open(Cursor::new(&TEST_FILE[..43])).is_err());
+    }
+
+    fn is_corrupt<T>(result: Result<T, WebmetroError>) -> bool {
+        matches!(result, Err(WebmetroError::EbmlError { source: EbmlError::CorruptPayload }))
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        assert!(is_corrupt(WebmFile::open(Cursor::new(&TEST_FILE[..30]))));
+
+        // a header claiming to be far bigger than the file
+        let mut bytes = vec![0x1A, 0x45, 0xDF, 0xA3, 0x01, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        bytes.extend_from_slice(&ENCODE_WEBM_TEST_FILE[5..]);
+        assert!(is_corrupt(WebmFile::open(Cursor::new(bytes))));
+    }
+
+    #[test]
+    fn reject_oversized_timecode() {
+        // the first cluster's Timecode claims to run past the cluster
+        let mut bytes = ENCODE_WEBM_TEST_FILE.to_vec();
+        assert_eq!(&bytes[27..30], &[0xE7, 0x81, 0x00]);
+        bytes[28] = 0xFE;
+        // without a SeekHead, opening scans the clusters
+        assert!(is_corrupt(WebmFile::open(Cursor::new(bytes))));
+    }
+
+    #[test]
+    fn ignore_overflowing_positions() {
+        // a SeekHead claiming the Info is past the end of any file,
+        // and Cues claiming the same of the first cluster
+        let mut index = Vec::new();
+        EbmlNode::master(0x014D9B74, vec![
+            EbmlNode::master(0x0DBB, vec![
+                EbmlNode::value(0x13AB, EbmlValue::Binary(vec![0x15, 0x49, 0xA9, 0x66].into())),
+                EbmlNode::value(0x13AC, EbmlValue::Uint(u64::MAX))
+            ])
+        ]).encode(&mut index).unwrap();
+        EbmlNode::master(0x0C53BB6B, vec![
+            EbmlNode::master(0x3B, vec![
+                EbmlNode::value(0x33, EbmlValue::Uint(0)),
+                EbmlNode::master(0x37, vec![
+                    EbmlNode::value(0x77, EbmlValue::Uint(1)),
+                    EbmlNode::value(0x71, EbmlValue::Uint(u64::MAX))
+                ])
+            ])
+        ]).encode(&mut index).unwrap();
+
+        // just inside the unknown-size Segment
+        let mut bytes = ENCODE_WEBM_TEST_FILE[..17].to_vec();
+        bytes.extend(&index);
+        bytes.extend_from_slice(&ENCODE_WEBM_TEST_FILE[17..]);
+        let mut file = WebmFile::open(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(file.cues().unwrap().unwrap().points.len(), 1);
+        assert!(file.info().unwrap().is_none());
+        // the Cues are no help, so the clusters get scanned
+        let first_cluster = 22 + index.len() as u64;
+        assert_eq!(file.seek_to_time(0).unwrap(), Some(first_cluster));
+    }
+}
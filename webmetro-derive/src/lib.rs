@@ -0,0 +1,255 @@
+//! Derives `FromEbml` & `EbmlEncode` for structs representing EBML master elements.
+//!
+//! The struct is given its element ID with `#[ebml(id = 0x...)]`, and each field
+//! the ID of the child element it holds:
+//!
+//! * a plain field is a required child; `#[ebml(id = 0x..., default)]` makes it
+//!   fall back to `Default::default()` if absent, or `default = <literal>` to a given value
+//! * an `Option<T>` field is an optional child
+//! * a `Vec<T>` field collects every child with that ID (except `Vec<u8>`, which is binary data)
+//!
+//! Field types implement `FromEbmlField`/`EncodeEbmlField`, including other derived structs
+//! for nested masters. Unrecognized children are skipped when decoding.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, GenericArgument, Ident, Lit,
+    Meta, NestedMeta, PathArguments, Result, Type
+};
+
+/// How many of a child element a field holds
+enum Shape {
+    /// exactly one, with an optional default
+    Required(Option<TokenStream2>),
+    Optional,
+    Repeated
+}
+
+struct Field {
+    name: Ident,
+    id: u64,
+    /// the type of each child's value
+    value_type: Type,
+    shape: Shape
+}
+
+#[proc_macro_derive(FromEbml, attributes(ebml))]
+pub fn derive_from_ebml(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_ebml(&input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+#[proc_macro_derive(EbmlEncode, attributes(ebml))]
+pub fn derive_ebml_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_ebml_encode(&input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+fn expand_from_ebml(input: &DeriveInput) -> Result<TokenStream2> {
+    let (name, id, fields) = parse_struct(input)?;
+
+    // locals are prefixed so fields can't shadow the generated code's variables
+    let local = |field: &Field| Ident::new(&format!("field_{}", field.name), field.name.span());
+
+    let declarations = fields.iter().map(|field| {
+        let (local, value_type) = (local(field), &field.value_type);
+        match field.shape {
+            Shape::Repeated => quote! { let mut #local: ::std::vec::Vec<#value_type> = ::std::vec::Vec::new(); },
+            _ => quote! { let mut #local: ::std::option::Option<#value_type> = ::std::option::Option::None; }
+        }
+    });
+    let arms = fields.iter().map(|field| {
+        let (local, id, value_type) = (local(field), field.id, &field.value_type);
+        let value = quote! { <#value_type as ::webmetro::ebml::FromEbmlField>::from_field(payload)? };
+        match field.shape {
+            Shape::Repeated => quote! { #id => #local.push(#value), },
+            _ => quote! { #id => #local = ::std::option::Option::Some(#value), }
+        }
+    });
+    let initializers = fields.iter().map(|field| {
+        let (name, local) = (&field.name, local(field));
+        match &field.shape {
+            Shape::Required(None) => quote! {
+                #name: #local.ok_or(::webmetro::ebml::EbmlError::MissingElement)?
+            },
+            Shape::Required(Some(default)) => quote! { #name: #local.unwrap_or_else(|| #default) },
+            Shape::Optional | Shape::Repeated => quote! { #name: #local }
+        }
+    });
+
+    Ok(quote! {
+        impl ::webmetro::ebml::FromEbmlField for #name {
+            fn from_field(bytes: &[u8]) -> ::std::result::Result<#name, ::webmetro::ebml::EbmlError> {
+                #(#declarations)*
+                ::webmetro::ebml::for_each_child(bytes, |child_id, payload| {
+                    match child_id {
+                        #(#arms)*
+                        _ => {}
+                    }
+                    ::std::result::Result::Ok(())
+                })?;
+                ::std::result::Result::Ok(#name {
+                    #(#initializers),*
+                })
+            }
+        }
+
+        impl<'a> ::webmetro::ebml::FromEbml<'a> for #name {
+            fn should_unwrap(_element_id: u64) -> bool {
+                false
+            }
+
+            fn decode(element_id: u64, bytes: &'a[u8]) -> ::std::result::Result<#name, ::webmetro::ebml::EbmlError> {
+                if element_id != #id {
+                    return ::std::result::Result::Err(::webmetro::ebml::EbmlError::CorruptPayload);
+                }
+                <#name as ::webmetro::ebml::FromEbmlField>::from_field(bytes)
+            }
+        }
+    })
+}
+
+fn expand_ebml_encode(input: &DeriveInput) -> Result<TokenStream2> {
+    let (name, id, fields) = parse_struct(input)?;
+
+    // visits each child's value in field order, repeated ones in sequence
+    let for_each_value = |action: TokenStream2| fields.iter().map(move |field| {
+        let Field { name, id, value_type, shape } = field;
+        let action = quote! { { let value: &#value_type = value; let id: u64 = #id; #action } };
+        match shape {
+            Shape::Required(_) => quote! { { let value = &self.#name; #action } },
+            Shape::Optional => quote! { if let ::std::option::Option::Some(value) = &self.#name { #action } },
+            Shape::Repeated => quote! { for value in self.#name.iter() { #action } }
+        }
+    }).collect::<Vec<_>>();
+    // children are measured by size rather than encoded twice, so nesting stays linear
+    let measures = for_each_value(quote! {
+        payload_size += ::webmetro::ebml::EncodeEbmlField::encoded_field_size(value, id)?;
+    });
+    let payload_size = quote! { { let mut payload_size = 0u64; #(#measures)* payload_size } };
+    let writes = for_each_value(quote! {
+        ::webmetro::ebml::EncodeEbmlField::encode_field(value, id, output)?;
+    });
+
+    Ok(quote! {
+        impl ::webmetro::ebml::EncodeEbmlField for #name {
+            fn encode_field<T: ::std::io::Write + ?Sized>(&self, id: u64, output: &mut T) -> ::std::io::Result<()> {
+                ::webmetro::ebml::encode_sized_element(id, #payload_size, output, |output| {
+                    #(#writes)*
+                    ::std::io::Result::Ok(())
+                })
+            }
+
+            fn encoded_field_size(&self, id: u64) -> ::std::io::Result<u64> {
+                ::webmetro::ebml::element_size(id, #payload_size)
+            }
+        }
+
+        impl ::webmetro::ebml::EbmlEncode for #name {
+            fn encode<T: ::std::io::Write + ?Sized>(&self, output: &mut T) -> ::std::io::Result<()> {
+                <#name as ::webmetro::ebml::EncodeEbmlField>::encode_field(self, #id, output)
+            }
+        }
+    })
+}
+
+/// Gets a struct's name, element ID & child fields
+fn parse_struct(input: &DeriveInput) -> Result<(&Ident, u64, Vec<Field>)> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "EBML elements can't have generic parameters"));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(&input.ident, "EBML elements need named fields"))
+        },
+        _ => return Err(Error::new_spanned(&input.ident, "EBML elements can only be derived for structs"))
+    };
+
+    let (id, default) = parse_attributes(&input.attrs, &input.ident)?;
+    if default.is_some() {
+        return Err(Error::new_spanned(&input.ident, "defaults belong on fields"));
+    }
+
+    let fields = fields.iter().map(|field| {
+        let name = field.ident.clone().expect("named field");
+        let (id, default) = parse_attributes(&field.attrs, &name)?;
+        let (shape, value_type) = match (container_type(&field.ty), default) {
+            (Some(("Option", _)), Some(_)) => return Err(Error::new_spanned(&name, "optional fields can't have defaults")),
+            (Some(("Vec", _)), Some(_)) => return Err(Error::new_spanned(&name, "repeated fields can't have defaults")),
+            (Some(("Option", inner)), None) => (Shape::Optional, inner.clone()),
+            (Some(("Vec", inner)), None) => (Shape::Repeated, inner.clone()),
+            (_, default) => (Shape::Required(default), field.ty.clone())
+        };
+        Ok(Field { name, id, value_type, shape })
+    }).collect::<Result<Vec<_>>>()?;
+
+    Ok((&input.ident, id, fields))
+}
+
+/// Reads `#[ebml(id = ..., default[ = ...])]`, giving the ID and any default expression
+fn parse_attributes(attrs: &[Attribute], owner: &Ident) -> Result<(u64, Option<TokenStream2>)> {
+    let mut id = None;
+    let mut default = None;
+
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("ebml")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new_spanned(meta, "expected #[ebml(id = ...)]"))
+        };
+        for item in list.nested.iter() {
+            match item {
+                NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("id") => match &pair.lit {
+                    Lit::Int(value) => id = Some(value.base10_parse::<u64>()?),
+                    lit => return Err(Error::new_spanned(lit, "element IDs must be integers"))
+                },
+                NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("default") => {
+                    default = Some(match &pair.lit {
+                        Lit::Str(value) => quote! { ::std::string::String::from(#value) },
+                        lit => quote! { #lit }
+                    });
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("default") => {
+                    default = Some(quote! { ::std::default::Default::default() });
+                },
+                item => return Err(Error::new_spanned(item, "unknown ebml attribute"))
+            }
+        }
+    }
+
+    match id {
+        Some(id) => Ok((id, default)),
+        None => Err(Error::new_spanned(owner, "missing #[ebml(id = ...)] attribute"))
+    }
+}
+
+/// If a type is `Option<T>` or `Vec<T>` (but not `Vec<u8>`, which is binary data),
+/// gives which, and `T`
+fn container_type(ty: &Type) -> Option<(&'static str, &Type)> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None
+    };
+    let container = if segment.ident == "Option" {
+        "Option"
+    } else if segment.ident == "Vec" {
+        "Vec"
+    } else {
+        return None
+    };
+    let inner = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) if arguments.args.len() == 1 => match arguments.args.first()? {
+            GenericArgument::Type(inner) => inner,
+            _ => return None
+        },
+        _ => return None
+    };
+    match inner {
+        Type::Path(path) if container == "Vec" && path.path.is_ident("u8") => None,
+        inner => Some((container, inner))
+    }
+}